@@ -2,8 +2,34 @@ use std::fmt::Display;
 
 use chrono::{DateTime, Utc};
 use ed25519_dalek::VerifyingKey;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
+/// Globally unique identifier of a [`Message`], chosen at random by its author
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct MessageId(#[serde(with = "serde_bytes")] [u8; 16]);
+
+/// How far a message has come on its way to the recipient
+///
+/// The states are ordered by progress, a message never goes back to an earlier state, except
+/// for [`DeliveryState::Failed`].
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Default,
+)]
+pub enum DeliveryState {
+    /// Could not be sent
+    Failed,
+    /// Not sent yet
+    #[default]
+    Pending,
+    /// Was written to the connection with the recipient
+    Sent,
+    /// The recipient confirmed that it stored the message
+    Delivered,
+    /// The recipient has seen the message
+    Read,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Message {
     Text(MessageText),
@@ -11,9 +37,15 @@ pub enum Message {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MessageMeta {
+    pub id: MessageId,
     pub author_key: VerifyingKey,
+    /// When the author sent the message, according to the clock of the author
+    pub time_sent: chrono::DateTime<chrono::Utc>,
+    /// When the message was stored locally, used for ordering
     pub time_received: chrono::DateTime<chrono::Utc>,
     pub seen: bool,
+    #[serde(default)]
+    pub delivery: DeliveryState,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -22,13 +54,67 @@ pub struct MessageText {
     pub meta: MessageMeta,
 }
 
+impl MessageId {
+    /// Creates a new random [`MessageId`].
+    pub fn generate() -> Self {
+        let mut id = [0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut id);
+        Self(id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl DeliveryState {
+    /// Moves on to a later state, returns `false` if `to` is not later than the current state.
+    pub fn advance(&mut self, to: Self) -> bool {
+        if to > *self {
+            *self = to;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Display for DeliveryState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Failed => "Failed",
+                Self::Pending => "Pending",
+                Self::Sent => "Sent",
+                Self::Delivered => "Delivered",
+                Self::Read => "Read",
+            }
+        )
+    }
+}
+
 impl Message {
     pub fn new_text(
         text: impl Display,
-        time_received: DateTime<Utc>,
+        time_sent: DateTime<Utc>,
         author_key: VerifyingKey,
     ) -> Self {
-        Self::Text(MessageText::new(text, time_received, author_key))
+        Self::Text(MessageText::new(text, time_sent, author_key))
+    }
+
+    pub fn id(&self) -> MessageId {
+        self.meta().id
     }
 
     pub fn meta(&self) -> &MessageMeta {
@@ -36,22 +122,34 @@ impl Message {
             Message::Text(m) => &m.meta,
         }
     }
+
+    pub fn meta_mut(&mut self) -> &mut MessageMeta {
+        match self {
+            Message::Text(m) => &mut m.meta,
+        }
+    }
 }
 
 impl MessageText {
-    pub fn new(text: impl Display, time_received: DateTime<Utc>, author_key: VerifyingKey) -> Self {
+    pub fn new(text: impl Display, time_sent: DateTime<Utc>, author_key: VerifyingKey) -> Self {
         Self {
             text: text.to_string(),
-            meta: MessageMeta::new(time_received, author_key),
+            meta: MessageMeta::new(time_sent, author_key),
         }
     }
 }
 
 impl MessageMeta {
-    pub fn new(time_received: DateTime<Utc>, author_key: VerifyingKey) -> Self {
+    /// Creates the metadata of a new message with a fresh [`MessageId`].
+    ///
+    /// `time_received` is set to `time_sent` until the message is received by someone.
+    pub fn new(time_sent: DateTime<Utc>, author_key: VerifyingKey) -> Self {
         Self {
-            time_received,
+            id: MessageId::generate(),
+            time_sent,
+            time_received: time_sent,
             seen: false,
+            delivery: DeliveryState::default(),
             author_key,
         }
     }
@@ -1,45 +1,77 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr};
 
-use crate::{chat::messages::Message, identity::ContactIdentity, state::State};
+use crate::{
+    chat::messages::{Message, MessageId},
+    identity::ContactIdentity,
+    state::State,
+};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 pub mod messages;
+pub mod receipts;
+pub mod requests;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Chat {
-    messages: Vec<Message>,
+    messages: HashMap<MessageId, Message>,
+    /// Ids of the messages, ordered by the time they were received
+    order: Vec<MessageId>,
     contact: ContactIdentity,
 }
 
 impl Chat {
     pub fn new(contact: ContactIdentity) -> Self {
         Self {
-            messages: Vec::new(),
+            messages: HashMap::new(),
+            order: Vec::new(),
             contact,
         }
     }
 
     pub fn latest_timestamp(&self) -> Option<DateTime<Utc>> {
-        Some(self.messages.last()?.meta().time_received)
+        Some(self.messages[self.order.last()?].meta().time_received)
     }
 
-    pub fn messages(&self) -> &[Message] {
-        &self.messages
+    /// Iterates over the messages, ordered by the time they were received.
+    pub fn messages(&self) -> impl DoubleEndedIterator<Item = &Message> + ExactSizeIterator {
+        self.order.iter().map(|id| &self.messages[id])
+    }
+
+    pub fn message(&self, id: &MessageId) -> Option<&Message> {
+        self.messages.get(id)
+    }
+
+    pub fn message_mut(&mut self, id: &MessageId) -> Option<&mut Message> {
+        self.messages.get_mut(id)
     }
 
     pub fn contact(&self) -> &ContactIdentity {
         &self.contact
     }
 
-    pub fn add_message(&mut self, msg: Message) {
-        self.messages.push(msg);
-        self.sort();
+    pub(crate) fn contact_mut(&mut self) -> &mut ContactIdentity {
+        &mut self.contact
     }
 
-    fn sort(&mut self) {
-        self.messages.sort_by_key(|m| m.meta().time_received);
+    /// Adds a message to the chat, keeping the messages ordered.
+    ///
+    /// Returns `false` if a message with the same [`MessageId`] is already in the chat, the new
+    /// message is dropped then.
+    pub fn add_message(&mut self, msg: Message) -> bool {
+        let id = msg.id();
+        if self.messages.contains_key(&id) {
+            return false;
+        }
+
+        let time_received = msg.meta().time_received;
+        let position = self
+            .order
+            .partition_point(|other| self.messages[other].meta().time_received <= time_received);
+        self.order.insert(position, id);
+        self.messages.insert(id, msg);
+        true
     }
 }
 
@@ -0,0 +1,112 @@
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chat::messages::{DeliveryState, MessageId},
+    error::{CoreError, CoreResult},
+    identity::UserIdentity,
+};
+
+/// Prefix of the signed data, so that receipt signatures can not be used for anything else
+const RECEIPT_SIGNATURE_CONTEXT: &[u8] = b"SREMP receipt v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ReceiptKind {
+    /// The recipient has stored the message
+    Delivered,
+    /// The recipient has seen the message
+    Read,
+}
+
+/// Confirmation from the recipient of a message, signed with its identity key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub message_id: MessageId,
+    pub kind: ReceiptKind,
+    signature: Signature,
+}
+
+impl Receipt {
+    pub fn sign(user: &UserIdentity, message_id: MessageId, kind: ReceiptKind) -> Self {
+        Self {
+            message_id,
+            kind,
+            signature: user.private_key.sign(&Self::signed_data(message_id, kind)),
+        }
+    }
+
+    /// Checks that the receipt was signed by `signer`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidReceipt`] if the signature does not match.
+    pub fn verify(&self, signer: &VerifyingKey) -> CoreResult<()> {
+        signer
+            .verify_strict(
+                &Self::signed_data(self.message_id, self.kind),
+                &self.signature,
+            )
+            .map_err(|_| CoreError::InvalidReceipt(self.message_id))
+    }
+
+    /// The state of the message the receipt confirms
+    pub fn delivery_state(&self) -> DeliveryState {
+        match self.kind {
+            ReceiptKind::Delivered => DeliveryState::Delivered,
+            ReceiptKind::Read => DeliveryState::Read,
+        }
+    }
+
+    fn signed_data(message_id: MessageId, kind: ReceiptKind) -> Vec<u8> {
+        let mut data = RECEIPT_SIGNATURE_CONTEXT.to_vec();
+        data.extend_from_slice(message_id.as_bytes());
+        data.push(match kind {
+            ReceiptKind::Delivered => 0,
+            ReceiptKind::Read => 1,
+        });
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receipt_is_verified_with_the_key_of_its_signer() {
+        let user = UserIdentity::build("alice").unwrap();
+        let id = MessageId::generate();
+        for kind in [ReceiptKind::Delivered, ReceiptKind::Read] {
+            let receipt = Receipt::sign(&user, id, kind);
+            assert!(receipt.verify(&user.identity.public_key).is_ok());
+        }
+        assert_eq!(
+            Receipt::sign(&user, id, ReceiptKind::Read).delivery_state(),
+            DeliveryState::Read
+        );
+    }
+
+    #[test]
+    fn receipt_from_another_signer_is_refused() {
+        let user = UserIdentity::build("alice").unwrap();
+        let mallory = UserIdentity::build("mallory").unwrap();
+        let id = MessageId::generate();
+        let receipt = Receipt::sign(&mallory, id, ReceiptKind::Delivered);
+        assert!(matches!(
+            receipt.verify(&user.identity.public_key),
+            Err(CoreError::InvalidReceipt(failed)) if failed == id
+        ));
+    }
+
+    #[test]
+    fn altered_receipt_is_refused() {
+        let user = UserIdentity::build("alice").unwrap();
+        let mut receipt = Receipt::sign(&user, MessageId::generate(), ReceiptKind::Delivered);
+        receipt.kind = ReceiptKind::Read;
+        assert!(receipt.verify(&user.identity.public_key).is_err());
+
+        let mut receipt = Receipt::sign(&user, MessageId::generate(), ReceiptKind::Read);
+        receipt.message_id = MessageId::generate();
+        assert!(receipt.verify(&user.identity.public_key).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+//! Contact requests from peers the user has not chatted with yet
+//!
+//! Messages from a peer with [`Trust::Unknown`] who has no chat yet are kept apart in
+//! [`State::requests`], so that anyone who can connect cannot fill the chats of the user. The
+//! user reviews the requests and accepts, rejects or blocks them.
+
+use ed25519_dalek::VerifyingKey;
+
+use crate::{
+    error::{CoreError, CoreResult},
+    identity::Trust,
+    state::State,
+};
+
+impl State {
+    /// Moves a contact request to the chats, the messages in it are kept.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::NoContactRequest`] if there is no request from that key.
+    pub fn accept_request(&mut self, key: &VerifyingKey) -> CoreResult<()> {
+        let request = self
+            .requests
+            .remove(key)
+            .ok_or(CoreError::NoContactRequest(*key))?;
+        self.chats.insert(*key, request);
+        Ok(())
+    }
+
+    /// Deletes a contact request with its messages. If the peer writes again, a new request is
+    /// made.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::NoContactRequest`] if there is no request from that key.
+    pub fn reject_request(&mut self, key: &VerifyingKey) -> CoreResult<()> {
+        self.requests
+            .remove(key)
+            .ok_or(CoreError::NoContactRequest(*key))?;
+        Ok(())
+    }
+
+    /// Marks a contact as [`Trust::Rejected`] and deletes its request, if it made one.
+    ///
+    /// The peer cannot connect anymore after this, but open connections with it are not closed.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::UnknownContact`] if there is no contact with that key.
+    pub fn block_contact(&mut self, key: &VerifyingKey) -> CoreResult<()> {
+        self.set_trust(key, Trust::Rejected)?;
+        self.requests.remove(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,5 @@
+//! Cryptography on top of the transport encryption of connections
+
+pub mod prekeys;
+pub mod ratchet;
+pub mod safety_number;
@@ -0,0 +1,422 @@
+//! Prekeys for starting sessions with contacts that are not online
+//!
+//! Every user publishes a [`PrekeyBundle`] as part of their [`Identity`]: a signed prekey which
+//! is replaced every [`SIGNED_PREKEY_LIFETIME`], and a batch of one-time prekeys which are used
+//! up by the sessions started with them. The bundle is signed with the identity key, so it can
+//! be handed out by anyone without being forged.
+//!
+//! A session is started with an X3DH key agreement: the side that sends first combines its
+//! identity key and an ephemeral key with the keys from the bundle of the peer, and sends the
+//! ephemeral key along with its first messages. See [`super::ratchet`] for the sessions
+//! themselves.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use hkdf::Hkdf;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    error::{CoreError, CoreResult},
+    identity::{Identity, UserIdentity},
+};
+
+/// How long a signed prekey is published before it is replaced
+pub const SIGNED_PREKEY_LIFETIME: TimeDelta = TimeDelta::days(7);
+/// How long a replaced signed prekey is kept, for sessions started with an older bundle
+const PREVIOUS_SIGNED_PREKEY_LIFETIME: TimeDelta = TimeDelta::days(30);
+/// How many one-time prekeys are published
+pub const ONE_TIME_PREKEY_COUNT: usize = 50;
+/// New one-time prekeys are generated once fewer than this are left
+const ONE_TIME_PREKEY_MIN: usize = 10;
+
+/// Prefix of the signed data, so that bundle signatures can not be used for anything else
+const BUNDLE_SIGNATURE_CONTEXT: &[u8] = b"SREMP prekey bundle v1";
+const KDF_INFO_X3DH: &[u8] = b"SREMP X3DH";
+
+/// The private prekeys of the user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserPrekeys {
+    signed: SignedPrekey,
+    /// The signed prekey that was replaced last, until it expires as well
+    previous_signed: Option<SignedPrekey>,
+    one_time: Vec<OneTimePrekey>,
+    next_id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SignedPrekey {
+    id: u32,
+    secret: PrekeySecret,
+    created: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct OneTimePrekey {
+    id: u32,
+    secret: PrekeySecret,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct PrekeySecret(StaticSecret);
+
+/// The public prekeys of a user, signed with the identity key of that user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrekeyBundle {
+    pub identity_key: VerifyingKey,
+    pub signed_prekey: PublishedPrekey,
+    pub one_time_prekeys: Vec<PublishedPrekey>,
+    pub created: DateTime<Utc>,
+    signature: Signature,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishedPrekey {
+    pub id: u32,
+    pub key: PublicKey,
+}
+
+/// Sent with the first messages of a session, so that the peer can do its half of X3DH
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionStart {
+    pub ephemeral_key: PublicKey,
+    pub signed_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+}
+
+impl UserPrekeys {
+    /// Generates a signed prekey and a full batch of one-time prekeys.
+    pub fn generate(now: DateTime<Utc>) -> Self {
+        let mut prekeys = Self {
+            signed: SignedPrekey {
+                id: 0,
+                secret: PrekeySecret::generate(),
+                created: now,
+            },
+            previous_signed: None,
+            one_time: Vec::new(),
+            next_id: 1,
+        };
+        prekeys.replenish();
+        prekeys
+    }
+
+    /// Replaces an expired signed prekey and generates one-time prekeys if they run low.
+    ///
+    /// Returns `true` if the published keys have changed.
+    pub fn refresh(&mut self, now: DateTime<Utc>) -> bool {
+        let mut changed = false;
+
+        if self
+            .previous_signed
+            .as_ref()
+            .is_some_and(|prev| now - prev.created > PREVIOUS_SIGNED_PREKEY_LIFETIME)
+        {
+            self.previous_signed = None;
+        }
+        if now - self.signed.created > SIGNED_PREKEY_LIFETIME {
+            let signed = SignedPrekey {
+                id: self.next_id(),
+                secret: PrekeySecret::generate(),
+                created: now,
+            };
+            self.previous_signed = Some(std::mem::replace(&mut self.signed, signed));
+            log::info!("Rotated the signed prekey");
+            changed = true;
+        }
+        if self.one_time.len() < ONE_TIME_PREKEY_MIN {
+            self.replenish();
+            log::info!("Generated new one-time prekeys");
+            changed = true;
+        }
+
+        changed
+    }
+
+    /// Signs the public parts of the prekeys with the identity key.
+    pub fn bundle(&self, identity_key: &ed25519_dalek::SigningKey) -> PrekeyBundle {
+        let mut bundle = PrekeyBundle {
+            identity_key: identity_key.verifying_key(),
+            signed_prekey: PublishedPrekey {
+                id: self.signed.id,
+                key: self.signed.secret.public_key(),
+            },
+            one_time_prekeys: self
+                .one_time
+                .iter()
+                .map(|prekey| PublishedPrekey {
+                    id: prekey.id,
+                    key: prekey.secret.public_key(),
+                })
+                .collect(),
+            created: self.signed.created,
+            signature: Signature::from_bytes(&[0; Signature::BYTE_SIZE]),
+        };
+        bundle.signature = identity_key.sign(&bundle.signed_data());
+        bundle
+    }
+
+    fn signed_secret(&self, id: u32) -> Option<&StaticSecret> {
+        std::iter::once(&self.signed)
+            .chain(&self.previous_signed)
+            .find(|prekey| prekey.id == id)
+            .map(|prekey| &prekey.secret.0)
+    }
+
+    fn one_time_secret(&self, id: u32) -> Option<&StaticSecret> {
+        self.one_time
+            .iter()
+            .find(|prekey| prekey.id == id)
+            .map(|prekey| &prekey.secret.0)
+    }
+
+    /// Deletes a one-time prekey once a session was started with it, so it is never used again.
+    pub(crate) fn remove_one_time(&mut self, id: u32) {
+        self.one_time.retain(|prekey| prekey.id != id);
+    }
+
+    fn replenish(&mut self) {
+        while self.one_time.len() < ONE_TIME_PREKEY_COUNT {
+            let id = self.next_id();
+            self.one_time.push(OneTimePrekey {
+                id,
+                secret: PrekeySecret::generate(),
+            });
+        }
+    }
+
+    fn next_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+}
+
+impl PrekeyBundle {
+    /// Checks that the bundle was signed by `identity_key`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidPrekeyBundle`] if the bundle belongs to another identity
+    /// or the signature does not match.
+    pub fn verify(&self, identity_key: &VerifyingKey) -> CoreResult<()> {
+        if self.identity_key != *identity_key {
+            return Err(CoreError::InvalidPrekeyBundle(*identity_key));
+        }
+        identity_key
+            .verify_strict(&self.signed_data(), &self.signature)
+            .map_err(|_| CoreError::InvalidPrekeyBundle(*identity_key))
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = BUNDLE_SIGNATURE_CONTEXT.to_vec();
+        data.extend_from_slice(self.identity_key.as_bytes());
+        data.extend_from_slice(&self.created.timestamp().to_be_bytes());
+        for prekey in std::iter::once(&self.signed_prekey).chain(&self.one_time_prekeys) {
+            data.extend_from_slice(&prekey.id.to_be_bytes());
+            data.extend_from_slice(prekey.key.as_bytes());
+        }
+        data
+    }
+}
+
+impl PrekeySecret {
+    fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(rand::rngs::OsRng))
+    }
+
+    fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.0)
+    }
+}
+
+impl PartialEq for PrekeySecret {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_bytes() == other.0.as_bytes()
+    }
+}
+
+impl Eq for PrekeySecret {}
+
+impl std::fmt::Debug for PrekeySecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PrekeySecret")
+            .field(&self.public_key())
+            .finish()
+    }
+}
+
+/// The X3DH agreement of the side that starts a session.
+///
+/// The one-time prekeys in `used` were taken by earlier sessions, and are never picked again,
+/// as the peer has deleted them. If the bundle has no other ones left, the session starts
+/// without a one-time prekey.
+///
+/// Returns the shared secret, the signed prekey of the peer that the session starts from, and
+/// what the peer needs to know to do its half.
+///
+/// # Errors
+///
+/// Fails if the peer has no valid [`PrekeyBundle`].
+pub(crate) fn x3dh_initiate(
+    user: &UserIdentity,
+    peer: &Identity,
+    used: &[u32],
+) -> CoreResult<(Zeroizing<[u8; 32]>, PublicKey, SessionStart)> {
+    let bundle = peer.prekey_bundle()?;
+    let unused: Vec<&PublishedPrekey> = bundle
+        .one_time_prekeys
+        .iter()
+        .filter(|prekey| !used.contains(&prekey.id))
+        .collect();
+    let one_time_prekey = unused.choose(&mut rand::rngs::OsRng).copied();
+    let ephemeral = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let identity = StaticSecret::from(user.dh_private_key());
+    let signed_prekey = bundle.signed_prekey.key;
+
+    let mut dhs = vec![
+        diffie_hellman(&identity, &signed_prekey)?,
+        diffie_hellman(&ephemeral, &PublicKey::from(peer.dh_public_key()))?,
+        diffie_hellman(&ephemeral, &signed_prekey)?,
+    ];
+    if let Some(one_time_prekey) = one_time_prekey {
+        dhs.push(diffie_hellman(&ephemeral, &one_time_prekey.key)?);
+    }
+
+    let start = SessionStart {
+        ephemeral_key: PublicKey::from(&ephemeral),
+        signed_prekey_id: bundle.signed_prekey.id,
+        one_time_prekey_id: one_time_prekey.map(|prekey| prekey.id),
+    };
+    Ok((kdf_x3dh(&dhs), signed_prekey, start))
+}
+
+/// The X3DH agreement of the side that receives the first message of a session.
+///
+/// Returns the shared secret and the signed prekey, which is the first ratchet key of this side.
+/// The one-time prekey is not deleted here, that must only happen once the first message was
+/// decrypted.
+///
+/// # Errors
+///
+/// Fails with [`CoreError::Ratchet`] if the prekeys the peer used do not exist (anymore).
+pub(crate) fn x3dh_respond(
+    user: &UserIdentity,
+    peer: &VerifyingKey,
+    start: &SessionStart,
+) -> CoreResult<(Zeroizing<[u8; 32]>, StaticSecret)> {
+    let signed_prekey =
+        user.prekeys
+            .signed_secret(start.signed_prekey_id)
+            .ok_or(CoreError::Ratchet(
+                "the signed prekey is unknown or expired",
+            ))?;
+    let identity = StaticSecret::from(user.dh_private_key());
+    let peer_identity = PublicKey::from(peer.to_montgomery().to_bytes());
+
+    let mut dhs = vec![
+        diffie_hellman(signed_prekey, &peer_identity)?,
+        diffie_hellman(&identity, &start.ephemeral_key)?,
+        diffie_hellman(signed_prekey, &start.ephemeral_key)?,
+    ];
+    if let Some(id) = start.one_time_prekey_id {
+        let one_time_prekey = user
+            .prekeys
+            .one_time_secret(id)
+            .ok_or(CoreError::Ratchet("the one-time prekey was already used"))?;
+        dhs.push(diffie_hellman(one_time_prekey, &start.ephemeral_key)?);
+    }
+
+    Ok((kdf_x3dh(&dhs), signed_prekey.clone()))
+}
+
+pub(super) fn diffie_hellman(
+    secret: &StaticSecret,
+    public: &PublicKey,
+) -> CoreResult<Zeroizing<[u8; 32]>> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(CoreError::Ratchet("the peer sent a weak key"));
+    }
+    Ok(Zeroizing::new(shared.to_bytes()))
+}
+
+fn kdf_x3dh(dhs: &[Zeroizing<[u8; 32]>]) -> Zeroizing<[u8; 32]> {
+    // the 0xFF prefix keeps the input apart from that of other uses of X25519 keys
+    let mut input = Zeroizing::new(vec![0xFF; 32]);
+    for dh in dhs {
+        input.extend_from_slice(dh.as_ref());
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&[0; 32]), &input)
+        .expand(KDF_INFO_X3DH, key.as_mut())
+        .expect("output length is valid for HKDF-SHA256");
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs both halves of X3DH, returning what the session started with and whether both
+    /// sides came to the same secret.
+    fn agree(with_one_time_prekey: bool) -> (SessionStart, bool) {
+        let alice = UserIdentity::build("alice").unwrap();
+        let bob = UserIdentity::build("bob").unwrap();
+        let used: Vec<u32> = if with_one_time_prekey {
+            Vec::new()
+        } else {
+            let bundle = bob.identity.prekey_bundle().unwrap();
+            bundle
+                .one_time_prekeys
+                .iter()
+                .map(|prekey| prekey.id)
+                .collect()
+        };
+
+        let (alice_secret, bob_prekey, start) =
+            x3dh_initiate(&alice, &bob.identity, &used).unwrap();
+        let (bob_secret, bob_prekey_secret) =
+            x3dh_respond(&bob, &alice.identity.public_key, &start).unwrap();
+        assert_eq!(PublicKey::from(&bob_prekey_secret), bob_prekey);
+        (start, alice_secret == bob_secret)
+    }
+
+    #[test]
+    fn x3dh_agrees_with_a_one_time_prekey() {
+        let (start, agreed) = agree(true);
+        assert!(start.one_time_prekey_id.is_some());
+        assert!(agreed);
+    }
+
+    #[test]
+    fn x3dh_agrees_without_a_one_time_prekey() {
+        let (start, agreed) = agree(false);
+        assert_eq!(start.one_time_prekey_id, None);
+        assert!(agreed);
+    }
+
+    #[test]
+    fn x3dh_refuses_a_used_one_time_prekey() {
+        let alice = UserIdentity::build("alice").unwrap();
+        let mut bob = UserIdentity::build("bob").unwrap();
+        let (_, _, start) = x3dh_initiate(&alice, &bob.identity, &[]).unwrap();
+
+        bob.prekeys
+            .remove_one_time(start.one_time_prekey_id.unwrap());
+        assert!(x3dh_respond(&bob, &alice.identity.public_key, &start).is_err());
+    }
+
+    #[test]
+    fn x3dh_refuses_a_tampered_bundle() {
+        let alice = UserIdentity::build("alice").unwrap();
+        let mut bob = UserIdentity::build("bob").unwrap().identity;
+        let extensions = bob.extensions.as_mut().unwrap();
+        extensions.prekey_bundle.as_mut().unwrap().signed_prekey.id += 1;
+
+        assert!(x3dh_initiate(&alice, &bob, &[]).is_err());
+    }
+}
@@ -0,0 +1,620 @@
+//! Double Ratchet sessions for the end-to-end encryption of chat messages
+//!
+//! Messages to a contact are encrypted with a [`RatchetSession`] before they are handed to a
+//! connection, so that only the contact can read them, no matter how they travel. The sessions
+//! follow the Double Ratchet algorithm with X25519, HKDF-SHA256, HMAC-SHA256 and
+//! ChaCha20-Poly1305, which gives every message its own key. Old keys are deleted right away,
+//! so a stolen session state cannot decrypt earlier messages.
+//!
+//! The side that sends first starts a session with the [`PrekeyBundle`](super::prekeys) of the
+//! peer, the peer picks it up from the first message it receives. Both sides might start a
+//! session at the same time, so every contact has a few [`RatchetSessions`] which are all tried
+//! when decrypting.
+
+use std::collections::VecDeque;
+
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use ed25519_dalek::VerifyingKey;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use super::prekeys::{SessionStart, diffie_hellman, x3dh_initiate, x3dh_respond};
+use crate::{
+    error::{CoreError, CoreResult},
+    identity::{Identity, UserIdentity},
+};
+
+/// How many messages of a single chain may be skipped, for example because they were lost
+pub const MAX_SKIP: u32 = 1000;
+/// How many keys of skipped messages are kept per session, the oldest are dropped first
+const MAX_SKIPPED_KEYS: usize = 2000;
+/// How many sessions are kept per contact
+const MAX_SESSIONS: usize = 4;
+
+const KDF_INFO_ROOT: &[u8] = b"SREMP ratchet root";
+const KDF_INFO_MESSAGE: &[u8] = b"SREMP ratchet message";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+type Key = Zeroizing<[u8; KEY_LEN]>;
+
+/// All sessions with a single contact, the one used for sending comes first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RatchetSessions {
+    sessions: Vec<RatchetSession>,
+    /// Ids of the one-time prekeys of the peer that sessions were started with, the bundle of
+    /// the peer we know still lists them until the peer sends a new one
+    #[serde(default)]
+    used_one_time_prekeys: Vec<u32>,
+}
+
+/// State of one Double Ratchet session with a contact
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RatchetSession {
+    /// First ratchet key of the side that started the session, identifies the session
+    base_key: PublicKey,
+    root_key: Key,
+    dh_sending: StaticSecret,
+    dh_receiving: Option<PublicKey>,
+    sending_chain: Option<Chain>,
+    receiving_chain: Option<Chain>,
+    /// Length of the previous sending chain, sent along so the peer can skip missing messages
+    previous_sending_len: u32,
+    skipped: VecDeque<SkippedKey>,
+    /// Sent along with every message until the peer has answered in this session
+    pending_start: Option<SessionStart>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Chain {
+    key: Key,
+    index: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    dh: PublicKey,
+    index: u32,
+    key: Key,
+}
+
+/// Unencrypted header of a [`RatchetMessage`], authenticated along with the ciphertext
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    /// Current ratchet key of the sender
+    pub dh: PublicKey,
+    /// Number of messages in the previous sending chain of the sender
+    pub previous_chain_len: u32,
+    /// Number of the message in the current sending chain
+    pub index: u32,
+    /// Set while the sender has not heard back in a session it started
+    pub session_start: Option<SessionStart>,
+}
+
+/// An end-to-end encrypted payload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatchetMessage {
+    pub header: RatchetHeader,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl RatchetSessions {
+    /// Encrypts a payload for `peer`, starting a new session if there is none to send with.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a new session is needed but `peer` has no valid
+    /// [`PrekeyBundle`](super::prekeys::PrekeyBundle).
+    pub fn encrypt(
+        &mut self,
+        user: &UserIdentity,
+        peer: &Identity,
+        plaintext: &[u8],
+    ) -> CoreResult<RatchetMessage> {
+        if self
+            .sessions
+            .first()
+            .is_none_or(|session| session.sending_chain.is_none())
+        {
+            log::debug!("Starting a new ratchet session");
+            let session = RatchetSession::initiate(user, peer, &self.used_one_time_prekeys)?;
+            // ids are never reused, so those gone from the bundle can be forgotten
+            let bundle = peer.prekey_bundle()?;
+            self.used_one_time_prekeys.retain(|id| {
+                bundle
+                    .one_time_prekeys
+                    .iter()
+                    .any(|prekey| prekey.id == *id)
+            });
+            if let Some(id) = session
+                .pending_start
+                .and_then(|start| start.one_time_prekey_id)
+            {
+                self.used_one_time_prekeys.push(id);
+            }
+            self.add(session);
+        }
+        self.sessions[0].encrypt(
+            plaintext,
+            &associated_data(&user.identity.public_key, &peer.public_key),
+        )
+    }
+
+    /// Decrypts a payload from `peer`, picking up a new session if the peer started one.
+    ///
+    /// The session that decrypted the message is used for sending from now on. A one-time
+    /// prekey the peer started a session with is deleted from `user`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::Ratchet`] if no session can decrypt the message. The sessions are
+    /// not changed then.
+    pub fn decrypt(
+        &mut self,
+        user: &mut UserIdentity,
+        peer: &VerifyingKey,
+        msg: &RatchetMessage,
+    ) -> CoreResult<Vec<u8>> {
+        let ad = associated_data(peer, &user.identity.public_key);
+
+        for i in 0..self.sessions.len() {
+            if let Ok(plaintext) = self.sessions[i].decrypt(msg, &ad) {
+                let session = self.sessions.remove(i);
+                self.sessions.insert(0, session);
+                return Ok(plaintext);
+            }
+        }
+
+        // a replayed first message must not start the same session again
+        let Some(start) = msg.header.session_start.filter(|start| {
+            !self
+                .sessions
+                .iter()
+                .any(|session| session.base_key == start.ephemeral_key)
+        }) else {
+            return Err(CoreError::Ratchet("no session can decrypt the message"));
+        };
+
+        let mut session = RatchetSession::respond(user, peer, &start)?;
+        let plaintext = session.decrypt(msg, &ad)?;
+        if let Some(id) = start.one_time_prekey_id {
+            user.prekeys.remove_one_time(id);
+        }
+        log::debug!("Picked up a new ratchet session");
+        self.add(session);
+        Ok(plaintext)
+    }
+
+    fn add(&mut self, session: RatchetSession) {
+        self.sessions.insert(0, session);
+        self.sessions.truncate(MAX_SESSIONS);
+    }
+}
+
+impl RatchetSession {
+    /// Starts a session as the side that sends first. The signed prekey of the peer is its
+    /// first ratchet key.
+    fn initiate(user: &UserIdentity, peer: &Identity, used: &[u32]) -> CoreResult<Self> {
+        let (shared_secret, peer_dh, start) = x3dh_initiate(user, peer, used)?;
+        let dh_sending = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let (root_key, chain_key) =
+            kdf_root(&shared_secret, &diffie_hellman(&dh_sending, &peer_dh)?);
+
+        Ok(Self {
+            base_key: start.ephemeral_key,
+            root_key,
+            dh_sending,
+            dh_receiving: Some(peer_dh),
+            sending_chain: Some(Chain::new(chain_key)),
+            receiving_chain: None,
+            previous_sending_len: 0,
+            skipped: VecDeque::new(),
+            pending_start: Some(start),
+        })
+    }
+
+    /// Prepares a session started by the peer, the first message then completes it.
+    fn respond(user: &UserIdentity, peer: &VerifyingKey, start: &SessionStart) -> CoreResult<Self> {
+        let (shared_secret, signed_prekey) = x3dh_respond(user, peer, start)?;
+        Ok(Self {
+            base_key: start.ephemeral_key,
+            root_key: shared_secret,
+            dh_sending: signed_prekey,
+            dh_receiving: None,
+            sending_chain: None,
+            receiving_chain: None,
+            previous_sending_len: 0,
+            skipped: VecDeque::new(),
+            pending_start: None,
+        })
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> CoreResult<RatchetMessage> {
+        let chain = self
+            .sending_chain
+            .as_mut()
+            .ok_or(CoreError::Ratchet("the session cannot send yet"))?;
+        let header = RatchetHeader {
+            dh: PublicKey::from(&self.dh_sending),
+            previous_chain_len: self.previous_sending_len,
+            index: chain.index,
+            session_start: self.pending_start,
+        };
+        let message_key = chain.advance();
+
+        Ok(RatchetMessage {
+            header,
+            ciphertext: seal(&message_key, ad, &header, plaintext)?,
+        })
+    }
+
+    /// Decrypts a message, the session only changes if that succeeds.
+    fn decrypt(&mut self, msg: &RatchetMessage, ad: &[u8]) -> CoreResult<Vec<u8>> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_in_place(msg, ad)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_in_place(&mut self, msg: &RatchetMessage, ad: &[u8]) -> CoreResult<Vec<u8>> {
+        let header = &msg.header;
+        if let Some(pos) = self
+            .skipped
+            .iter()
+            .position(|skipped| skipped.dh == header.dh && skipped.index == header.index)
+        {
+            let skipped = self.skipped.remove(pos).expect("position is in bounds");
+            let plaintext = open(&skipped.key, ad, header, &msg.ciphertext)?;
+            self.pending_start = None;
+            return Ok(plaintext);
+        }
+
+        if self.dh_receiving != Some(header.dh) {
+            self.skip_message_keys(header.previous_chain_len)?;
+            self.dh_ratchet(header.dh)?;
+        }
+        self.skip_message_keys(header.index)?;
+
+        let chain = self
+            .receiving_chain
+            .as_mut()
+            .expect("the dh ratchet sets the receiving chain");
+        if chain.index != header.index {
+            return Err(CoreError::Ratchet("the message key was already used"));
+        }
+        let message_key = chain.advance();
+        let plaintext = open(&message_key, ad, header, &msg.ciphertext)?;
+        // the peer has picked up the session
+        self.pending_start = None;
+        Ok(plaintext)
+    }
+
+    /// Stores the keys of messages in the current receiving chain up to `until`.
+    fn skip_message_keys(&mut self, until: u32) -> CoreResult<()> {
+        let (Some(chain), Some(dh)) = (self.receiving_chain.as_mut(), self.dh_receiving) else {
+            return Ok(());
+        };
+        if until.saturating_sub(chain.index) > MAX_SKIP {
+            return Err(CoreError::Ratchet("too many messages were skipped"));
+        }
+
+        while chain.index < until {
+            let index = chain.index;
+            let key = chain.advance();
+            self.skipped.push_back(SkippedKey { dh, index, key });
+        }
+        while self.skipped.len() > MAX_SKIPPED_KEYS {
+            self.skipped.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Moves on to a new ratchet key of the peer, which replaces both chains.
+    fn dh_ratchet(&mut self, peer_dh: PublicKey) -> CoreResult<()> {
+        self.previous_sending_len = self.sending_chain.as_ref().map_or(0, |chain| chain.index);
+        self.dh_receiving = Some(peer_dh);
+
+        let (root_key, chain_key) =
+            kdf_root(&self.root_key, &diffie_hellman(&self.dh_sending, &peer_dh)?);
+        self.receiving_chain = Some(Chain::new(chain_key));
+
+        self.dh_sending = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let (root_key, chain_key) =
+            kdf_root(&root_key, &diffie_hellman(&self.dh_sending, &peer_dh)?);
+        self.sending_chain = Some(Chain::new(chain_key));
+        self.root_key = root_key;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for RatchetSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RatchetSession")
+            .field("base_key", &self.base_key)
+            .field(
+                "sent",
+                &self.sending_chain.as_ref().map(|chain| chain.index),
+            )
+            .field(
+                "received",
+                &self.receiving_chain.as_ref().map(|chain| chain.index),
+            )
+            .field("skipped", &self.skipped.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Chain {
+    fn new(key: Key) -> Self {
+        Self { key, index: 0 }
+    }
+
+    /// Returns the key for the next message and moves the chain forward.
+    fn advance(&mut self) -> Key {
+        let message_key = hmac(&self.key, &[0x01]);
+        self.key = hmac(&self.key, &[0x02]);
+        self.index += 1;
+        message_key
+    }
+}
+
+/// Binds the ciphertext to the identities of sender and receiver
+fn associated_data(sender: &VerifyingKey, receiver: &VerifyingKey) -> Vec<u8> {
+    [sender.as_bytes().as_slice(), receiver.as_bytes()].concat()
+}
+
+/// `KDF_RK` from the Double Ratchet specification, returns the new root key and a chain key
+fn kdf_root(root_key: &Key, dh: &Key) -> (Key, Key) {
+    let mut output = Zeroizing::new([0u8; 2 * KEY_LEN]);
+    Hkdf::<Sha256>::new(Some(root_key.as_ref()), dh.as_ref())
+        .expand(KDF_INFO_ROOT, output.as_mut())
+        .expect("output length is valid for HKDF-SHA256");
+
+    let (root, chain) = output.split_at(KEY_LEN);
+    (
+        Zeroizing::new(root.try_into().expect("length is KEY_LEN")),
+        Zeroizing::new(chain.try_into().expect("length is KEY_LEN")),
+    )
+}
+
+fn hmac(key: &Key, data: &[u8]) -> Key {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key.as_ref()).expect("HMAC accepts any key length");
+    mac.update(data);
+    Zeroizing::new(mac.finalize().into_bytes().into())
+}
+
+/// Derives the cipher and nonce for a message key, every message key is only used once
+fn message_cipher(message_key: &Key) -> (ChaCha20Poly1305, [u8; NONCE_LEN]) {
+    let mut output = Zeroizing::new([0u8; KEY_LEN + NONCE_LEN]);
+    Hkdf::<Sha256>::new(None, message_key.as_ref())
+        .expand(KDF_INFO_MESSAGE, output.as_mut())
+        .expect("output length is valid for HKDF-SHA256");
+
+    let (key, nonce) = output.split_at(KEY_LEN);
+    (
+        ChaCha20Poly1305::new(key.into()),
+        nonce.try_into().expect("length is NONCE_LEN"),
+    )
+}
+
+fn header_data(ad: &[u8], header: &RatchetHeader) -> CoreResult<Vec<u8>> {
+    let mut data = ad.to_vec();
+    data.extend_from_slice(&rmp_serde::to_vec(header)?);
+    Ok(data)
+}
+
+fn seal(
+    message_key: &Key,
+    ad: &[u8],
+    header: &RatchetHeader,
+    plaintext: &[u8],
+) -> CoreResult<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &header_data(ad, header)?,
+            },
+        )
+        .map_err(|_| CoreError::Ratchet("the message could not be encrypted"))
+}
+
+fn open(
+    message_key: &Key,
+    ad: &[u8],
+    header: &RatchetHeader,
+    ciphertext: &[u8],
+) -> CoreResult<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &header_data(ad, header)?,
+            },
+        )
+        .map_err(|_| CoreError::Ratchet("the message could not be decrypted"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::prekeys::ONE_TIME_PREKEY_COUNT;
+
+    /// Two users with their sessions with each other
+    struct Pair {
+        alice: UserIdentity,
+        bob: UserIdentity,
+        alice_sessions: RatchetSessions,
+        bob_sessions: RatchetSessions,
+    }
+
+    impl Pair {
+        fn new() -> Self {
+            Self {
+                alice: UserIdentity::build("alice").unwrap(),
+                bob: UserIdentity::build("bob").unwrap(),
+                alice_sessions: RatchetSessions::default(),
+                bob_sessions: RatchetSessions::default(),
+            }
+        }
+
+        fn alice_sends(&mut self, plaintext: &[u8]) -> RatchetMessage {
+            self.alice_sessions
+                .encrypt(&self.alice, &self.bob.identity, plaintext)
+                .unwrap()
+        }
+
+        fn bob_sends(&mut self, plaintext: &[u8]) -> RatchetMessage {
+            self.bob_sessions
+                .encrypt(&self.bob, &self.alice.identity, plaintext)
+                .unwrap()
+        }
+
+        fn bob_reads(&mut self, msg: &RatchetMessage) -> CoreResult<Vec<u8>> {
+            let alice_key = self.alice.identity.public_key;
+            self.bob_sessions.decrypt(&mut self.bob, &alice_key, msg)
+        }
+
+        fn alice_reads(&mut self, msg: &RatchetMessage) -> CoreResult<Vec<u8>> {
+            let bob_key = self.bob.identity.public_key;
+            self.alice_sessions.decrypt(&mut self.alice, &bob_key, msg)
+        }
+    }
+
+    #[test]
+    fn messages_go_both_ways() {
+        let mut pair = Pair::new();
+        let first = pair.alice_sends(b"hello");
+        assert!(first.header.session_start.is_some());
+        assert_eq!(pair.bob_reads(&first).unwrap(), b"hello");
+
+        let answer = pair.bob_sends(b"hi");
+        assert!(answer.header.session_start.is_none());
+        assert_eq!(pair.alice_reads(&answer).unwrap(), b"hi");
+
+        // the answer completes the session, and every turn moves the ratchet on
+        let second = pair.alice_sends(b"how are you");
+        assert!(second.header.session_start.is_none());
+        assert_ne!(second.header.dh, first.header.dh);
+        assert_eq!(pair.bob_reads(&second).unwrap(), b"how are you");
+        assert_eq!(pair.alice_sessions.sessions.len(), 1);
+        assert_eq!(pair.bob_sessions.sessions.len(), 1);
+
+        // the keys are gone once used
+        assert!(pair.bob_reads(&second).is_err());
+        assert!(pair.bob_reads(&first).is_err());
+    }
+
+    #[test]
+    fn messages_can_arrive_out_of_order() {
+        let mut pair = Pair::new();
+        let messages: Vec<RatchetMessage> = (0..3u8).map(|i| pair.alice_sends(&[i])).collect();
+        assert_eq!(pair.bob_reads(&messages[2]).unwrap(), [2]);
+        assert_eq!(pair.bob_reads(&messages[0]).unwrap(), [0]);
+
+        // a message from before the last turn still has its key
+        let answer = pair.bob_sends(b"answer");
+        assert_eq!(pair.alice_reads(&answer).unwrap(), b"answer");
+        let after_turn = pair.alice_sends(b"after");
+        assert_eq!(pair.bob_reads(&after_turn).unwrap(), b"after");
+        assert_eq!(pair.bob_reads(&messages[1]).unwrap(), [1]);
+        assert!(pair.bob_reads(&messages[1]).is_err());
+    }
+
+    #[test]
+    fn too_many_skipped_messages_are_refused() {
+        let mut pair = Pair::new();
+        let first = pair.alice_sends(b"first");
+        assert_eq!(pair.bob_reads(&first).unwrap(), b"first");
+
+        let messages: Vec<RatchetMessage> =
+            (0..=MAX_SKIP + 1).map(|_| pair.alice_sends(b"")).collect();
+        // the last one would skip one message too many
+        let last = messages.last().unwrap();
+        assert!(pair.bob_reads(last).is_err());
+        // the failed attempt has not changed the session
+        assert_eq!(pair.bob_reads(&messages[messages.len() - 2]).unwrap(), b"");
+        assert_eq!(pair.bob_reads(last).unwrap(), b"");
+        assert_eq!(pair.bob_reads(&messages[0]).unwrap(), b"");
+    }
+
+    #[test]
+    fn oldest_skipped_keys_are_dropped() {
+        let mut pair = Pair::new();
+        let first = pair.alice_sends(b"first");
+        assert_eq!(pair.bob_reads(&first).unwrap(), b"first");
+
+        // every turn skips MAX_SKIP messages, which is too much to keep for three turns
+        let mut skipped = Vec::new();
+        for turn in 0..3 {
+            let messages: Vec<RatchetMessage> =
+                (0..=MAX_SKIP).map(|_| pair.alice_sends(&[turn])).collect();
+            assert_eq!(pair.bob_reads(messages.last().unwrap()).unwrap(), [turn]);
+            skipped.push(messages[0].clone());
+
+            let answer = pair.bob_sends(b"answer");
+            assert_eq!(pair.alice_reads(&answer).unwrap(), b"answer");
+        }
+        assert_eq!(
+            pair.bob_sessions.sessions[0].skipped.len(),
+            MAX_SKIPPED_KEYS
+        );
+
+        assert!(pair.bob_reads(&skipped[0]).is_err());
+        assert_eq!(pair.bob_reads(&skipped[1]).unwrap(), [1]);
+        assert_eq!(pair.bob_reads(&skipped[2]).unwrap(), [2]);
+    }
+
+    #[test]
+    fn sessions_started_one_after_another_use_different_one_time_prekeys() {
+        let alice = UserIdentity::build("alice").unwrap();
+        let mut bob = UserIdentity::build("bob").unwrap();
+        let bob_identity = bob.identity.clone();
+        let mut alice_sessions = RatchetSessions::default();
+        let mut bob_sessions = RatchetSessions::default();
+
+        // more sessions than bob has one-time prekeys, all with the same cached bundle
+        let mut messages = Vec::new();
+        for _ in 0..=ONE_TIME_PREKEY_COUNT {
+            alice_sessions.sessions.clear();
+            messages.push(
+                alice_sessions
+                    .encrypt(&alice, &bob_identity, b"hello")
+                    .unwrap(),
+            );
+        }
+
+        let mut prekeys: Vec<Option<u32>> = messages
+            .iter()
+            .map(|msg| msg.header.session_start.unwrap().one_time_prekey_id)
+            .collect();
+        // once all are used, sessions start without one
+        assert_eq!(prekeys.pop(), Some(None));
+        prekeys.sort_unstable();
+        prekeys.dedup();
+        assert_eq!(prekeys.len(), ONE_TIME_PREKEY_COUNT);
+        assert!(prekeys.iter().all(Option::is_some));
+
+        let alice_key = alice.identity.public_key;
+        for msg in &messages {
+            assert_eq!(
+                bob_sessions.decrypt(&mut bob, &alice_key, msg).unwrap(),
+                b"hello"
+            );
+        }
+    }
+}
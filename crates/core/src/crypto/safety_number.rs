@@ -0,0 +1,205 @@
+//! Safety numbers for verifying contacts out of band
+//!
+//! The safety number of a chat is derived from the identity keys of both sides, so both see the
+//! same number. If the numbers match when compared in person or over another trusted channel,
+//! nobody is in the middle and the contact can be marked as
+//! [`Trusted`](crate::identity::Trust::Trusted).
+
+use std::fmt::{Display, Write};
+
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha512};
+
+use crate::error::{CoreError, CoreResult};
+
+/// Version of the safety number derivation, part of the hashed data and the QR payload
+pub const SAFETY_NUMBER_VERSION: u8 = 1;
+/// Prefix of the QR payload
+const QR_PAYLOAD_PREFIX: &str = "SREMP-SAFETY";
+/// Makes computing many fingerprints expensive, see the Signal safety number
+const FINGERPRINT_ITERATIONS: usize = 5200;
+const FINGERPRINT_LEN: usize = 30;
+/// Every 5 bytes of a fingerprint make a group of 5 digits
+const GROUP_BYTES: usize = 5;
+
+/// Fingerprint of the identity keys of a chat, see the [module documentation](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SafetyNumber {
+    local: [u8; FINGERPRINT_LEN],
+    remote: [u8; FINGERPRINT_LEN],
+}
+
+impl SafetyNumber {
+    /// Derives the safety number of a chat between the `local` and `remote` identity keys.
+    pub fn new(local: &VerifyingKey, remote: &VerifyingKey) -> Self {
+        Self {
+            local: fingerprint(local),
+            remote: fingerprint(remote),
+        }
+    }
+
+    /// The 12 groups of 5 digits, in the same order on both sides.
+    pub fn groups(&self) -> Vec<String> {
+        let (first, second) = if self.local <= self.remote {
+            (&self.local, &self.remote)
+        } else {
+            (&self.remote, &self.local)
+        };
+        first
+            .chunks(GROUP_BYTES)
+            .chain(second.chunks(GROUP_BYTES))
+            .map(|chunk| {
+                let value = chunk
+                    .iter()
+                    .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+                format!("{:05}", value % 100_000)
+            })
+            .collect()
+    }
+
+    /// Text to put into a QR code, for the contact to scan and check with
+    /// [`SafetyNumber::matches_qr_payload`].
+    pub fn qr_payload(&self) -> String {
+        format!(
+            "{QR_PAYLOAD_PREFIX}:{SAFETY_NUMBER_VERSION}:{}:{}",
+            to_hex(&self.local),
+            to_hex(&self.remote)
+        )
+    }
+
+    /// Checks a QR payload scanned from the device of the contact.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidSafetyNumberPayload`] if the payload is not a SREMP
+    /// safety number of this version.
+    pub fn matches_qr_payload(&self, payload: &str) -> CoreResult<bool> {
+        let mut parts = payload.trim().split(':');
+        let (Some(QR_PAYLOAD_PREFIX), Some(version), Some(theirs), Some(ours), None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return Err(CoreError::InvalidSafetyNumberPayload);
+        };
+        if version != SAFETY_NUMBER_VERSION.to_string() {
+            return Err(CoreError::InvalidSafetyNumberPayload);
+        }
+
+        // the local key of the contact is our remote key
+        Ok(from_hex(theirs)? == self.remote && from_hex(ours)? == self.local)
+    }
+}
+
+impl Display for SafetyNumber {
+    /// Shows the groups in three lines of four, like `12345 67890 12345 67890`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, line) in self.groups().chunks(4).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", line.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+fn fingerprint(key: &VerifyingKey) -> [u8; FINGERPRINT_LEN] {
+    let mut hash = Sha512::new()
+        .chain_update([SAFETY_NUMBER_VERSION])
+        .chain_update(key.as_bytes())
+        .finalize();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(key.as_bytes())
+            .finalize();
+    }
+    hash[..FINGERPRINT_LEN]
+        .try_into()
+        .expect("length is FINGERPRINT_LEN")
+}
+
+fn to_hex(data: &[u8]) -> String {
+    let mut buf = String::with_capacity(data.len() * 2);
+    for b in data {
+        write!(buf, "{b:02X}").expect("writing to a string cannot fail");
+    }
+    buf
+}
+
+fn from_hex(hex: &str) -> CoreResult<[u8; FINGERPRINT_LEN]> {
+    if hex.len() != FINGERPRINT_LEN * 2 || !hex.is_ascii() {
+        return Err(CoreError::InvalidSafetyNumberPayload);
+    }
+    let mut data = [0u8; FINGERPRINT_LEN];
+    for (byte, pair) in data.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).expect("input is ascii");
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| CoreError::InvalidSafetyNumberPayload)?;
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::UserIdentity;
+
+    fn keys() -> (VerifyingKey, VerifyingKey) {
+        (
+            UserIdentity::build("alice").unwrap().identity.public_key,
+            UserIdentity::build("bob").unwrap().identity.public_key,
+        )
+    }
+
+    #[test]
+    fn both_sides_see_the_same_number() {
+        let (alice, bob) = keys();
+        let alice_side = SafetyNumber::new(&alice, &bob);
+        let bob_side = SafetyNumber::new(&bob, &alice);
+
+        assert_eq!(alice_side.groups(), bob_side.groups());
+        assert_eq!(alice_side.to_string(), bob_side.to_string());
+        assert_eq!(alice_side.groups().len(), 12);
+        assert!(
+            alice_side
+                .groups()
+                .iter()
+                .all(|group| group.len() == 5 && group.chars().all(|c| c.is_ascii_digit()))
+        );
+    }
+
+    #[test]
+    fn qr_payload_matches_only_the_other_side() {
+        let (alice, bob) = keys();
+        let (carol, _) = keys();
+        let alice_side = SafetyNumber::new(&alice, &bob);
+        let bob_side = SafetyNumber::new(&bob, &alice);
+
+        assert!(
+            bob_side
+                .matches_qr_payload(&alice_side.qr_payload())
+                .unwrap()
+        );
+        assert!(
+            alice_side
+                .matches_qr_payload(&bob_side.qr_payload())
+                .unwrap()
+        );
+        // scanning your own code proves nothing
+        assert!(
+            !alice_side
+                .matches_qr_payload(&alice_side.qr_payload())
+                .unwrap()
+        );
+        let someone_else = SafetyNumber::new(&carol, &alice);
+        assert!(
+            !alice_side
+                .matches_qr_payload(&someone_else.qr_payload())
+                .unwrap()
+        );
+        assert!(alice_side.matches_qr_payload("not a payload").is_err());
+    }
+}
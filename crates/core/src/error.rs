@@ -1,8 +1,12 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
 
 use thiserror::Error;
 
-use crate::net::{NetworkCommand, NetworkEvent};
+use crate::{
+    chat::messages::MessageId,
+    identity::format_key,
+    net::{NetworkCommand, NetworkEvent, RejectionReason},
+};
 
 pub type CoreResult<T> = std::result::Result<T, CoreError>;
 
@@ -10,7 +14,7 @@ pub type CoreResult<T> = std::result::Result<T, CoreError>;
 pub enum CoreError {
     #[error("standard io error: {0}")]
     IO(#[from] std::io::Error),
-    #[error("Could not load the application store")]
+    #[error("Could not load the application store: {0}")]
     Load(#[from] LoadError),
     #[error("Could not load the application store")]
     ChannelRecv(#[from] async_channel::RecvError),
@@ -24,6 +28,10 @@ pub enum CoreError {
     MessagePackEncode(#[from] rmp_serde::encode::Error),
     #[error("MessagePack decode error: {0}")]
     MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("Key derivation error: {0}")]
+    Kdf(#[from] argon2::Error),
+    #[error("Background task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
     // custom Errors
     #[error("No user identity currently exists")]
     NoUserIdentity,
@@ -33,21 +41,93 @@ pub enum CoreError {
     FrameTooLarge(usize),
     #[error("Frame length is over 2 byte long: {0}")]
     FrameLengthOverU16(usize),
+    #[error("Tried to send a message that is too large ({0} > MAX_MESSAGE_SIZE)")]
+    MessageTooLarge(usize),
+    #[error("Received an invalid chunk: {0}")]
+    InvalidChunk(&'static str),
+    #[error("Received a packet with the unsupported version {0}")]
+    UnsupportedPacketVersion(u8),
+    #[error("Peer ({remote}) sent an unexpected {kind} packet")]
+    UnexpectedPacket {
+        remote: SocketAddr,
+        kind: &'static str,
+    },
     #[error("Could not get the public key of peer ({0}) during the connection initialization")]
     NoisePeerHasNoPublicKey(SocketAddr),
     #[error("Public key of peer ({0}) is malformed")]
     PeerKeyIsMalformed(SocketAddr),
+    #[error("Peer ({0}) may not connect: {1}")]
+    ConnectionRejected(SocketAddr, RejectionReason),
     #[error("Public key of peer ({remote}) is invalid: {source}")]
     PeerKeyIsInvalid {
         remote: SocketAddr,
         source: ed25519_dalek::SignatureError,
     },
+    #[error("Peer ({remote}) does not speak a compatible protocol: {reason}")]
+    IncompatiblePeer { remote: SocketAddr, reason: String },
     #[error("The given username does not conform to the constraints of the specification")]
     InvalidUsername,
+    #[error("No active connection exists for {0}")]
+    NoConnection(SocketAddr),
+    #[error("The connection with {0} does not belong to the addressed contact")]
+    ConnectionContactMismatch(SocketAddr),
+    #[error("Peer ({remote}) did not answer in time during the {stage}")]
+    Timeout {
+        remote: SocketAddr,
+        stage: &'static str,
+    },
+    #[error("The connection with {0} was closed")]
+    ConnectionClosed(SocketAddr),
+    #[error("Peer ({0}) is not a relay server")]
+    NotARelay(SocketAddr),
+    #[error("The {0} has an invalid signature")]
+    InvalidSignature(&'static str),
+    #[error("The receipt for message {0} has an invalid signature")]
+    InvalidReceipt(MessageId),
+    #[error("{} has not published any prekeys", format_key(.0))]
+    NoPrekeyBundle(ed25519_dalek::VerifyingKey),
+    #[error("The prekeys of {} have an invalid signature", format_key(.0))]
+    InvalidPrekeyBundle(ed25519_dalek::VerifyingKey),
+    #[error("The scanned code does not contain a SREMP safety number")]
+    InvalidSafetyNumberPayload,
+    #[error("{} is not a known contact", format_key(.0))]
+    UnknownContact(ed25519_dalek::VerifyingKey),
+    #[error("End-to-end encryption failed: {0}")]
+    Ratchet(&'static str),
+    #[error("No chat exists with {}", format_key(.0))]
+    UnknownChat(ed25519_dalek::VerifyingKey),
+    #[error("There is no contact request from {}", format_key(.0))]
+    NoContactRequest(ed25519_dalek::VerifyingKey),
+    #[error("{0:?} is not a valid key")]
+    InvalidKeyText(String),
+    #[error("{0:?} is neither a network nor an address")]
+    InvalidNetwork(String),
+    #[error("{0:?} is not a valid endpoint")]
+    InvalidEndpoint(String),
+    #[error("No rendezvous server is set")]
+    NoRendezvousServer,
+    #[error("The rendezvous server ({0}) refused the request: {1}")]
+    RendezvousRefused(SocketAddr, String),
+    #[error("Could not determine the data directory of the user")]
+    NoDataDirectory,
+    #[error("The storage is locked, it needs a passphrase first")]
+    StorageLocked,
+    #[error("Could not encrypt the state for storing it")]
+    StorageEncryption,
+    #[error("The given passphrase is wrong")]
+    WrongPassphrase,
 }
 
 #[derive(Debug, Error)]
 pub enum LoadError {
-    #[error("could not load")]
-    Placeholder,
+    #[error("no stored state exists at {0}")]
+    Missing(PathBuf),
+    #[error("the file is not a stored state")]
+    NotAStateFile,
+    #[error("the passphrase is wrong or the stored state was tampered with")]
+    Decryption,
+    #[error("the stored state is corrupt: {0}")]
+    Corrupt(#[from] rmp_serde::decode::Error),
+    #[error("the stored state has version {found}, but version {expected} is required")]
+    VersionMismatch { found: u16, expected: u16 },
 }
@@ -1,10 +1,13 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, net::SocketAddr};
 
 use chrono::{DateTime, Utc};
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
-use crate::error::CoreResult;
+use crate::{
+    crypto::prekeys::{PrekeyBundle, UserPrekeys},
+    error::{CoreError, CoreResult},
+};
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Trust {
@@ -25,6 +28,9 @@ pub struct Identity {
 pub struct Extensions {
     pub profile_picture: Option<Vec<u8>>,
     pub additional_metadata: HashMap<String, Vec<u8>>,
+    /// Keys for starting end-to-end encrypted sessions, see [`crate::crypto::prekeys`]
+    #[serde(default)]
+    pub prekey_bundle: Option<Box<PrekeyBundle>>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -40,6 +46,10 @@ pub struct UserIdentity {
     pub identity: Identity,
     pub private_key: SigningKey,
     pub created: DateTime<Utc>,
+    /// State files from before there were prekeys get new ones, which are published with the
+    /// next [`UserIdentity::refresh_prekeys`]
+    #[serde(default = "new_prekeys")]
+    pub prekeys: UserPrekeys,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -48,6 +58,12 @@ pub struct ContactIdentity {
     pub trust: Trust,
     pub first_seen: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
+    /// Where the last connection we opened to the contact went, for reconnecting
+    #[serde(default)]
+    pub last_address: Option<SocketAddr>,
+    /// Keep a connection to the contact open, even when there is nothing to send
+    #[serde(default)]
+    pub pinned: bool,
 }
 
 impl Identity {
@@ -68,10 +84,31 @@ impl Identity {
         &self.username
     }
 
+    /// Returns the X25519 form of the public key, which is used as the noise static key.
+    pub fn dh_public_key(&self) -> [u8; 32] {
+        self.public_key.to_montgomery().to_bytes()
+    }
+
+    /// Returns the published [`PrekeyBundle`], after checking its signature.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::NoPrekeyBundle`] if there is no bundle, or with
+    /// [`CoreError::InvalidPrekeyBundle`] if it was not signed by this identity.
+    pub fn prekey_bundle(&self) -> CoreResult<&PrekeyBundle> {
+        let bundle = self
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.prekey_bundle.as_ref())
+            .ok_or(CoreError::NoPrekeyBundle(self.public_key))?;
+        bundle.verify(&self.public_key)?;
+        Ok(bundle)
+    }
+
     pub fn validate_username(username: &str) -> CoreResult<()> {
         let chars_len = username.chars().count();
         if !(1..=40).contains(&chars_len) {
-            Err(crate::error::CoreError::InvalidUsername)
+            Err(CoreError::InvalidUsername)
         } else {
             Ok(())
         }
@@ -85,20 +122,58 @@ impl UserIdentity {
         Self::load(username, key, Utc::now())
     }
 
-    /// Create a [`UserIdentity`] from the necessary values.
+    /// Create a [`UserIdentity`] from the necessary values, with new prekeys.
     pub fn load(username: &str, key: SigningKey, created: DateTime<Utc>) -> CoreResult<Self> {
         let identity = Identity::build(username, key.verifying_key())?;
-        Ok(Self {
+        let mut user = Self {
             identity,
             private_key: key,
             created,
-        })
+            prekeys: UserPrekeys::generate(Utc::now()),
+        };
+        user.publish_prekeys();
+        Ok(user)
+    }
+
+    /// Rotates and replenishes the prekeys if needed, see [`UserPrekeys::refresh`]. The bundle
+    /// is also published if the identity has none yet.
+    ///
+    /// Returns `true` if the published [`PrekeyBundle`] has changed.
+    pub fn refresh_prekeys(&mut self, now: DateTime<Utc>) -> bool {
+        let unpublished = self
+            .identity
+            .extensions
+            .as_ref()
+            .is_none_or(|extensions| extensions.prekey_bundle.is_none());
+        let changed = self.prekeys.refresh(now) || unpublished;
+        if changed {
+            self.publish_prekeys();
+        }
+        changed
+    }
+
+    /// Puts the current [`PrekeyBundle`] into the identity that is sent to peers.
+    pub fn publish_prekeys(&mut self) {
+        let bundle = self.prekeys.bundle(&self.private_key);
+        self.identity
+            .extensions
+            .get_or_insert_default()
+            .prekey_bundle = Some(Box::new(bundle));
     }
 
     /// Returns a reference to the private key of this [`UserIdentity`].
     pub fn private_key(&self) -> &SigningKey {
         &self.private_key
     }
+
+    /// Returns the X25519 form of the private key, which is used as the noise static key.
+    pub fn dh_private_key(&self) -> [u8; 32] {
+        self.private_key.to_scalar_bytes()
+    }
+}
+
+fn new_prekeys() -> UserPrekeys {
+    UserPrekeys::generate(Utc::now())
 }
 
 impl ContactIdentity {
@@ -116,6 +191,8 @@ impl ContactIdentity {
             trust,
             first_seen,
             last_seen,
+            last_address: None,
+            pinned: false,
         })
     }
 
@@ -165,3 +242,22 @@ pub fn format_key(key: &VerifyingKey) -> String {
     }
     buf
 }
+
+/// Reads a key in the hex format of [`format_key`].
+///
+/// # Errors
+///
+/// Fails with [`CoreError::InvalidKeyText`] if the text is not a valid key.
+pub fn parse_key(text: &str) -> CoreResult<VerifyingKey> {
+    let text = text.trim();
+    let invalid = || CoreError::InvalidKeyText(text.to_string());
+    if text.len() != 64 || !text.is_ascii() {
+        return Err(invalid());
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(text.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).expect("text is ascii");
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    VerifyingKey::from_bytes(&bytes).map_err(|_| invalid())
+}
@@ -3,10 +3,12 @@
 #![allow(clippy::result_large_err)]
 
 pub mod chat;
+pub mod crypto;
 pub mod error;
 pub mod identity;
 pub mod net;
 pub mod state;
+pub mod storage;
 
 pub fn version() -> String {
     format!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
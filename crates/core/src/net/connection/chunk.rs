@@ -0,0 +1,351 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::frame::MAX_FRAME_SIZE;
+use crate::error::{CoreError, CoreResult};
+
+/// Room left in a frame for the noise tag and the encoded [`ChunkHeader`]
+const CHUNK_OVERHEAD: usize = 64;
+/// Maximum payload carried by a single [`Chunk`]
+pub(super) const CHUNK_PAYLOAD_SIZE: usize = MAX_FRAME_SIZE - CHUNK_OVERHEAD;
+/// Maximum size of a message after reassembly
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// How many incomplete messages a peer may have at the same time
+const MAX_PENDING_MESSAGES: usize = 8;
+/// How many bytes of incomplete messages are buffered for a peer, the oldest message is dropped
+/// once there would be more
+const MAX_PENDING_BYTES: usize = 2 * MAX_MESSAGE_SIZE;
+/// How long an incomplete message is kept before it is dropped
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// `CHUNK_HEADER` from the specification
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct ChunkHeader {
+    /// Counter of the sending side of the connection, not to be confused with the id of a chat
+    /// message
+    message_id: u64,
+    chunk_index: u16,
+    total_chunks: u16,
+    chunk_size: u16,
+}
+
+/// `CHUNKED_MESSAGE` from the specification, every transport message carries one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Chunk {
+    header: ChunkHeader,
+    #[serde(with = "serde_bytes")]
+    payload: Vec<u8>,
+}
+
+/// Collects the chunks of incoming messages until they are complete
+#[derive(Debug, Default)]
+pub(super) struct Reassembler {
+    pending: HashMap<u64, PendingMessage>,
+    /// Sum of the payloads in `pending`
+    buffered: usize,
+}
+
+#[derive(Debug)]
+struct PendingMessage {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    /// Sum of the payloads in `chunks`
+    size: usize,
+    started: Instant,
+}
+
+/// Splits serialized data into the chunks that are sent for it.
+///
+/// Empty data still results in a single, empty chunk.
+pub(super) fn split(message_id: u64, data: &[u8]) -> CoreResult<Vec<Chunk>> {
+    if data.len() > MAX_MESSAGE_SIZE {
+        return Err(CoreError::MessageTooLarge(data.len()));
+    }
+
+    let total_chunks = data.len().div_ceil(CHUNK_PAYLOAD_SIZE).max(1);
+    let total_chunks = u16::try_from(total_chunks).expect("MAX_MESSAGE_SIZE fits in u16 chunks");
+    let mut chunks: Vec<Chunk> = data
+        .chunks(CHUNK_PAYLOAD_SIZE)
+        .zip(0..)
+        .map(|(payload, chunk_index)| Chunk {
+            header: ChunkHeader {
+                message_id,
+                chunk_index,
+                total_chunks,
+                chunk_size: u16::try_from(payload.len()).expect("chunk payload fits in u16"),
+            },
+            payload: payload.to_vec(),
+        })
+        .collect();
+
+    if chunks.is_empty() {
+        chunks.push(Chunk {
+            header: ChunkHeader {
+                message_id,
+                chunk_index: 0,
+                total_chunks,
+                chunk_size: 0,
+            },
+            payload: Vec::new(),
+        });
+    }
+
+    Ok(chunks)
+}
+
+impl Reassembler {
+    /// Adds a received chunk, returning the whole message with its id once all of its chunks
+    /// are there.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidChunk`] if the chunk does not fit to the others or would
+    /// exceed the buffering limits. The peer is not following the protocol then.
+    pub(super) fn insert(&mut self, chunk: Chunk) -> CoreResult<Option<(u64, Vec<u8>)>> {
+        self.expire();
+
+        let Chunk { header, payload } = chunk;
+        let total_chunks = usize::from(header.total_chunks);
+        let chunk_index = usize::from(header.chunk_index);
+
+        if chunk_index >= total_chunks {
+            return Err(CoreError::InvalidChunk("chunk index out of bounds"));
+        }
+        if payload.len() != usize::from(header.chunk_size) || payload.len() > CHUNK_PAYLOAD_SIZE {
+            return Err(CoreError::InvalidChunk(
+                "chunk size does not match the payload",
+            ));
+        }
+        if total_chunks > MAX_MESSAGE_SIZE.div_ceil(CHUNK_PAYLOAD_SIZE) {
+            return Err(CoreError::InvalidChunk("message is too large"));
+        }
+
+        // most messages fit into a single chunk, they don't need to be buffered
+        if total_chunks == 1 {
+            return Ok(Some((header.message_id, payload)));
+        }
+
+        if !self.pending.contains_key(&header.message_id)
+            && self.pending.len() >= MAX_PENDING_MESSAGES
+        {
+            return Err(CoreError::InvalidChunk("too many incomplete messages"));
+        }
+        let pending = self
+            .pending
+            .entry(header.message_id)
+            .or_insert_with(|| PendingMessage {
+                chunks: vec![None; total_chunks],
+                received: 0,
+                size: 0,
+                started: Instant::now(),
+            });
+
+        if pending.chunks.len() != total_chunks {
+            return Err(CoreError::InvalidChunk(
+                "chunk count changed within a message",
+            ));
+        }
+        let slot = &mut pending.chunks[chunk_index];
+        if slot.is_some() {
+            return Err(CoreError::InvalidChunk("chunk was received twice"));
+        }
+        let size = payload.len();
+        *slot = Some(payload);
+        pending.received += 1;
+        pending.size += size;
+        self.buffered += size;
+
+        if pending.received < total_chunks {
+            self.limit_buffered();
+            return Ok(None);
+        }
+
+        let pending = self
+            .remove(header.message_id)
+            .expect("pending message exists");
+        let data = pending.chunks.into_iter().flatten().flatten().collect();
+        Ok(Some((header.message_id, data)))
+    }
+
+    /// Drops the oldest incomplete messages until the buffered chunks fit the limit.
+    fn limit_buffered(&mut self) {
+        while self.buffered > MAX_PENDING_BYTES {
+            let Some(oldest) = self
+                .pending
+                .iter()
+                .min_by_key(|(_, pending)| pending.started)
+                .map(|(message_id, _)| *message_id)
+            else {
+                break;
+            };
+            if let Some(pending) = self.remove(oldest) {
+                log::warn!(
+                    "Dropping message {oldest} with {} of {} chunks, the buffer is full",
+                    pending.received,
+                    pending.chunks.len()
+                );
+            }
+        }
+    }
+
+    fn remove(&mut self, message_id: u64) -> Option<PendingMessage> {
+        let pending = self.pending.remove(&message_id)?;
+        self.buffered -= pending.size;
+        Some(pending)
+    }
+
+    /// Drops incomplete messages that have been waiting for too long.
+    fn expire(&mut self) {
+        let buffered = &mut self.buffered;
+        self.pending.retain(|message_id, pending| {
+            let keep = pending.started.elapsed() < REASSEMBLY_TIMEOUT;
+            if !keep {
+                log::warn!(
+                    "Dropping incomplete message {message_id} after receiving {} of {} chunks",
+                    pending.received,
+                    pending.chunks.len()
+                );
+                *buffered -= pending.size;
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Data that needs three chunks
+    fn three_chunks() -> Vec<u8> {
+        (0..=u8::MAX)
+            .cycle()
+            .take(CHUNK_PAYLOAD_SIZE * 2 + 10)
+            .collect()
+    }
+
+    #[test]
+    fn chunks_are_reassembled_in_any_order() {
+        let data = three_chunks();
+        let mut chunks = split(7, &data).unwrap();
+        assert_eq!(chunks.len(), 3);
+        chunks.reverse();
+
+        let mut reassembler = Reassembler::default();
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert_eq!(reassembler.insert(chunk).unwrap(), None);
+        }
+        assert_eq!(reassembler.insert(last).unwrap(), Some((7, data)));
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn empty_message_is_a_single_chunk() {
+        let mut chunks = split(1, &[]).unwrap();
+        assert_eq!(chunks.len(), 1);
+        let mut reassembler = Reassembler::default();
+        assert_eq!(
+            reassembler.insert(chunks.pop().unwrap()).unwrap(),
+            Some((1, Vec::new()))
+        );
+    }
+
+    #[test]
+    fn message_with_missing_chunks_stays_incomplete() {
+        let mut reassembler = Reassembler::default();
+        for message_id in 0..MAX_PENDING_MESSAGES as u64 {
+            let chunks = split(message_id, &three_chunks()).unwrap();
+            // the middle chunk never arrives
+            for chunk in [&chunks[0], &chunks[2]] {
+                assert_eq!(reassembler.insert(chunk.clone()).unwrap(), None);
+            }
+        }
+        assert_eq!(reassembler.pending.len(), MAX_PENDING_MESSAGES);
+
+        let chunks = split(MAX_PENDING_MESSAGES as u64, &three_chunks()).unwrap();
+        assert!(matches!(
+            reassembler.insert(chunks[0].clone()),
+            Err(CoreError::InvalidChunk(_))
+        ));
+    }
+
+    #[test]
+    fn duplicate_chunk_is_refused() {
+        let chunks = split(3, &three_chunks()).unwrap();
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.insert(chunks[0].clone()).unwrap(), None);
+        assert!(matches!(
+            reassembler.insert(chunks[0].clone()),
+            Err(CoreError::InvalidChunk(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_messages_and_chunks_are_refused() {
+        assert!(matches!(
+            split(0, &vec![0; MAX_MESSAGE_SIZE + 1]),
+            Err(CoreError::MessageTooLarge(_))
+        ));
+
+        let mut reassembler = Reassembler::default();
+        let too_many_chunks = MAX_MESSAGE_SIZE.div_ceil(CHUNK_PAYLOAD_SIZE) + 1;
+        let mut chunk = split(0, &[1, 2, 3]).unwrap().pop().unwrap();
+        chunk.header.total_chunks = u16::try_from(too_many_chunks).unwrap();
+        assert!(matches!(
+            reassembler.insert(chunk),
+            Err(CoreError::InvalidChunk(_))
+        ));
+
+        let mut chunk = split(0, &[1, 2, 3]).unwrap().pop().unwrap();
+        chunk.payload.push(4);
+        assert!(matches!(
+            reassembler.insert(chunk),
+            Err(CoreError::InvalidChunk(_))
+        ));
+
+        let mut chunk = split(0, &[0; CHUNK_PAYLOAD_SIZE]).unwrap().pop().unwrap();
+        chunk.payload.push(0);
+        chunk.header.chunk_size += 1;
+        assert!(matches!(
+            reassembler.insert(chunk),
+            Err(CoreError::InvalidChunk(_))
+        ));
+    }
+
+    #[test]
+    fn oldest_message_is_dropped_when_too_much_is_buffered() {
+        let mut reassembler = Reassembler::default();
+        let data = vec![7u8; MAX_MESSAGE_SIZE];
+        let mut last_chunks = Vec::new();
+        for message_id in 0..3 {
+            let mut chunks = split(message_id, &data).unwrap();
+            last_chunks.push(chunks.pop().unwrap());
+            for chunk in chunks {
+                assert_eq!(reassembler.insert(chunk).unwrap(), None);
+            }
+        }
+        assert!(reassembler.buffered <= MAX_PENDING_BYTES);
+        assert!(!reassembler.pending.contains_key(&0));
+
+        let mut last_chunks = last_chunks.into_iter();
+        // the first message lost its other chunks
+        assert_eq!(
+            reassembler.insert(last_chunks.next().unwrap()).unwrap(),
+            None
+        );
+        assert_eq!(
+            reassembler.insert(last_chunks.next().unwrap()).unwrap(),
+            Some((1, data.clone()))
+        );
+        assert_eq!(
+            reassembler.insert(last_chunks.next().unwrap()).unwrap(),
+            Some((2, data))
+        );
+    }
+}
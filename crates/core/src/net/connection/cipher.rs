@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use snow::StatelessTransportState;
+
+use super::frame::{Frame, MAX_FRAME_SIZE};
+use crate::error::CoreResult;
+
+/// Encrypts outgoing transport messages of a noise session.
+///
+/// The nonce is tracked here instead of in the [`StatelessTransportState`], so that the sending
+/// and the receiving half of a connection can be used independently of each other.
+#[derive(Debug)]
+pub(super) struct SendCipher {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+/// Decrypts incoming transport messages of a noise session.
+///
+/// See [`SendCipher`].
+#[derive(Debug)]
+pub(super) struct RecvCipher {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+/// Splits a finished noise session into its sending and receiving direction.
+pub(super) fn split(transport: StatelessTransportState) -> (SendCipher, RecvCipher) {
+    let transport = Arc::new(transport);
+    (
+        SendCipher {
+            transport: transport.clone(),
+            nonce: 0,
+        },
+        RecvCipher {
+            transport,
+            nonce: 0,
+        },
+    )
+}
+
+impl SendCipher {
+    pub(super) fn encrypt(&mut self, payload: &[u8]) -> CoreResult<Frame> {
+        let mut buf = vec![0u8; MAX_FRAME_SIZE];
+        let len = self
+            .transport
+            .write_message(self.nonce, payload, &mut buf)?;
+        self.nonce += 1;
+        Frame::raw(&buf[..len])
+    }
+}
+
+impl RecvCipher {
+    pub(super) fn decrypt(&mut self, frame: &Frame) -> CoreResult<Vec<u8>> {
+        let mut buf = vec![0u8; MAX_FRAME_SIZE];
+        let len = self
+            .transport
+            .read_message(self.nonce, frame.data(), &mut buf)?;
+        self.nonce += 1;
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
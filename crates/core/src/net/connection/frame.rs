@@ -1,12 +1,15 @@
 use std::ops::Deref;
 
-use serde::{Deserialize, Serialize};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net,
-};
+use serde::{Deserialize, Serialize, de::IgnoredAny};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::error::{CoreError, CoreResult};
+use crate::{
+    chat::receipts::Receipt,
+    crypto::ratchet::RatchetMessage,
+    error::{CoreError, CoreResult},
+    identity::Identity,
+    net::{relay::RelayPacket, rendezvous::RendezvousPacket},
+};
 
 pub(super) const MAX_FRAME_SIZE: usize = 65535;
 
@@ -16,8 +19,37 @@ pub(super) struct Frame {
     data: Vec<u8>,
 }
 
+/// Version of the [`FrameBody`] encoding, sent along with every packet
+const FRAME_BODY_VERSION: u8 = 1;
+
+/// Application packet carried by the encrypted transport of an established connection
+///
+/// Every packet is encoded as MessagePack together with [`FRAME_BODY_VERSION`], see
+/// [`FrameBody::encode`]. Large packets are split into chunks by the connection.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(super) struct FrameBody {}
+#[allow(clippy::large_enum_variant)]
+pub enum FrameBody {
+    /// The identity of the sender, exchanged once right after the noise handshake
+    Identity(Identity),
+    /// A chat message, end-to-end encrypted for the peer
+    Message(RatchetMessage),
+    /// Confirms that a chat message was delivered or read
+    Receipt(Receipt),
+    /// Confirms that the packet with this id was received
+    Ack(u64),
+    /// Asks the peer to answer with a [`FrameBody::Pong`] with the same value
+    Ping(u64),
+    /// The answer to a [`FrameBody::Ping`]
+    Pong(u64),
+    /// The peer is about to close the connection on purpose
+    Goodbye,
+    /// The peer closes the connection because something went wrong
+    Error(String),
+    /// A packet of the relay protocol, see [`crate::net::relay`]
+    Relay(RelayPacket),
+    /// A packet of the rendezvous protocol, see [`crate::net::rendezvous`]
+    Rendezvous(RendezvousPacket),
+}
 
 impl Frame {
     pub fn raw(data: &[u8]) -> CoreResult<Self> {
@@ -27,7 +59,7 @@ impl Frame {
         })
     }
 
-    pub async fn send(self, stream: &mut net::TcpStream) -> CoreResult<()> {
+    pub async fn send(self, stream: &mut (impl AsyncWrite + Unpin)) -> CoreResult<()> {
         log::debug!("Sending Frame");
         log::trace!("Sending Length");
         stream.write_u16(self.len()).await?;
@@ -38,7 +70,7 @@ impl Frame {
         Ok(())
     }
 
-    pub async fn recv(stream: &mut net::TcpStream) -> CoreResult<Self> {
+    pub async fn recv(stream: &mut (impl AsyncRead + Unpin)) -> CoreResult<Self> {
         log::debug!("Receiving Frame");
         log::trace!("Reading Length");
         let len = stream.read_u16().await? as usize;
@@ -54,7 +86,8 @@ impl Frame {
     }
 
     pub fn len(&self) -> u16 {
-        self.data.len() as u16 // cannot construct a frame that is too big
+        // cannot construct a frame that is too big
+        check_length(self.data.len()).expect("frame is larger than MAX_FRAME_SIZE")
     }
 
     pub(super) fn data(&self) -> &[u8] {
@@ -70,6 +103,45 @@ impl Deref for Frame {
     }
 }
 
+impl FrameBody {
+    pub(crate) fn encode(&self) -> CoreResult<Vec<u8>> {
+        Ok(rmp_serde::to_vec(&(FRAME_BODY_VERSION, self))?)
+    }
+
+    pub(crate) fn decode(data: &[u8]) -> CoreResult<Self> {
+        // look at the version first, a newer packet might not decode at all
+        let (version, _): (u8, IgnoredAny) = rmp_serde::from_slice(data)?;
+        if version != FRAME_BODY_VERSION {
+            return Err(CoreError::UnsupportedPacketVersion(version));
+        }
+        let (_, body): (u8, Self) = rmp_serde::from_slice(data)?;
+        Ok(body)
+    }
+
+    /// Short name of the packet type, for logging
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Identity(_) => "identity",
+            Self::Message(_) => "message",
+            Self::Receipt(_) => "receipt",
+            Self::Ack(_) => "ack",
+            Self::Ping(_) => "ping",
+            Self::Pong(_) => "pong",
+            Self::Goodbye => "goodbye",
+            Self::Error(_) => "error",
+            Self::Relay(packet) => packet.kind(),
+            Self::Rendezvous(packet) => packet.kind(),
+        }
+    }
+}
+
+/// A [`FrameBody`] as it was received, with the id the peer sent it under
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub id: u64,
+    pub body: FrameBody,
+}
+
 fn check_length(length: usize) -> CoreResult<u16> {
     if length > MAX_FRAME_SIZE {
         return Err(CoreError::FrameTooLarge(length));
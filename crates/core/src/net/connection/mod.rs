@@ -1,32 +1,88 @@
-use std::sync::LazyLock;
-
-use snow::{TransportState, params::NoiseParams};
-use tokio::{io::AsyncWriteExt, net};
+use std::{sync::LazyLock, time::Duration};
+
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use snow::params::NoiseParams;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{self, tcp::OwnedReadHalf},
+    task::JoinHandle,
+    time::Instant,
+};
 
 use crate::{
     error::{CoreError, CoreResult},
     identity::{Identity, UserIdentity},
+    net::policy::HandshakeAccess,
 };
 
+mod chunk;
+mod cipher;
 mod frame;
+mod relayed;
+mod version;
+mod writer;
+pub use chunk::MAX_MESSAGE_SIZE;
+use chunk::{Chunk, Reassembler};
+use cipher::*;
 use frame::*;
+pub use frame::{FrameBody, Packet};
+pub use relayed::{RelayedConnection, RelayedConnectionReader};
+use version::exchange_version;
+pub use version::{PROTOCOL_NAME, PROTOCOL_VERSION, ProtocolVersion};
+pub use writer::ConnectionWriter;
 
 pub static NOISE_PARAMS: LazyLock<NoiseParams> = LazyLock::new(|| {
-    "Noise_XX_25519_ChaChaPoly_Blake2s"
+    "Noise_XX_25519_ChaChaPoly_BLAKE2s"
         .parse()
         .expect("noise parameter string is malformed")
 });
 
+/// How long peers get to answer, so that a silent peer cannot hold on to a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timeouts {
+    /// For opening the TCP connection, agreeing on the protocol version and the noise handshake
+    pub handshake: Duration,
+    /// For the exchange of the identities after the handshake
+    pub identity: Duration,
+    /// How long an established connection may be silent before the peer is pinged
+    pub keepalive_interval: Duration,
+    /// How long to wait for the peer after a ping, before the connection counts as lost
+    pub keepalive_timeout: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: Duration::from_secs(10),
+            identity: Duration::from_secs(10),
+            keepalive_interval: Duration::from_secs(30),
+            keepalive_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
 #[derive(Debug)]
 #[must_use]
 pub enum Connection {
     P2P(P2PConnection),
+    /// A connection to a relay server, not to a contact
+    Relayed(RelayedConnection),
+}
+
+/// The receiving half of a [`Connection`], which is driven by its own task.
+#[derive(Debug)]
+#[must_use]
+pub enum ConnectionReader {
+    P2P(P2PConnectionReader),
+    Relayed(RelayedConnectionReader),
 }
 
 macro_rules! delegate {
     ($self:tt, $($do:tt)+) => {
         match $self {
             Self::P2P(c) => c.$($do)+,
+            Self::Relayed(c) => c.$($do)+,
         }
     };
 }
@@ -34,161 +90,379 @@ macro_rules! delegate {
 #[derive(Debug)]
 #[must_use]
 pub struct P2PConnection {
-    stream: net::TcpStream,
     peer_identity: Identity,
-    transport: snow::TransportState,
+    protocol_version: ProtocolVersion,
+    writer: ConnectionWriter,
+    writer_task: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+#[must_use]
+pub struct P2PConnectionReader {
+    stream: OwnedReadHalf,
+    cipher: RecvCipher,
+    reassembler: Reassembler,
 }
 
 impl Connection {
-    pub(crate) async fn connect_to(
+    /// Opens a connection to `remote`, doing the handshake and the identity exchange.
+    pub async fn connect_to(
         remote: std::net::SocketAddr,
         user: &UserIdentity,
-    ) -> CoreResult<Self> {
-        Ok(Self::P2P(P2PConnection::connect_to(remote, user).await?))
+        timeouts: &Timeouts,
+    ) -> CoreResult<(Self, ConnectionReader)> {
+        let (conn, reader) = P2PConnection::connect_to(remote, user, timeouts, None).await?;
+        Ok((Self::P2P(conn), ConnectionReader::P2P(reader)))
     }
 
-    pub(crate) async fn connect_from(
+    /// Opens a connection to the contact with the identity key `contact` at `remote`, like
+    /// [`connect_to`](Self::connect_to), but only if the peer there is that contact.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::ConnectionRejected`] if another peer answers. The user does not
+    /// send its identity to it.
+    pub async fn connect_to_contact(
+        remote: std::net::SocketAddr,
+        contact: &VerifyingKey,
+        user: &UserIdentity,
+        timeouts: &Timeouts,
+    ) -> CoreResult<(Self, ConnectionReader)> {
+        let access = HandshakeAccess::expecting(contact);
+        let (conn, reader) =
+            P2PConnection::connect_to(remote, user, timeouts, Some(&access)).await?;
+        Ok((Self::P2P(conn), ConnectionReader::P2P(reader)))
+    }
+
+    /// Accepts a connection that `remote` opened, doing the handshake and the identity exchange.
+    pub async fn connect_from(
         stream: net::TcpStream,
         remote: std::net::SocketAddr,
         user: &UserIdentity,
-    ) -> CoreResult<Self> {
-        Ok(Self::P2P(
-            P2PConnection::connect_from(stream, remote, user).await?,
-        ))
+        timeouts: &Timeouts,
+    ) -> CoreResult<(Self, ConnectionReader)> {
+        let (conn, reader) =
+            P2PConnection::connect_from(stream, remote, user, timeouts, None).await?;
+        Ok((Self::P2P(conn), ConnectionReader::P2P(reader)))
     }
 
-    pub(crate) async fn disconnect(self) -> CoreResult<()> {
-        delegate!(self, disconnect().await)
+    /// Accepts a connection that `remote` opened to the listener, like
+    /// [`connect_from`](Self::connect_from), but checks the peer against `access` before the
+    /// user sends its identity.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::ConnectionRejected`] if the peer may not connect.
+    pub(crate) async fn connect_from_listener(
+        stream: net::TcpStream,
+        remote: std::net::SocketAddr,
+        user: &UserIdentity,
+        timeouts: &Timeouts,
+        access: &HandshakeAccess,
+    ) -> CoreResult<(Self, ConnectionReader)> {
+        let (conn, reader) =
+            P2PConnection::connect_from(stream, remote, user, timeouts, Some(access)).await?;
+        Ok((Self::P2P(conn), ConnectionReader::P2P(reader)))
     }
 
-    pub(crate) async fn peer_identity(&self) -> &Identity {
-        delegate!(self, peer_identity().await)
+    /// Opens a connection to the relay server at `remote`, and registers the user with it.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::NotARelay`] if the peer is no relay server.
+    pub async fn connect_relay(
+        remote: std::net::SocketAddr,
+        user: &UserIdentity,
+        timeouts: &Timeouts,
+    ) -> CoreResult<(Self, ConnectionReader)> {
+        let (conn, reader) = RelayedConnection::connect_to(remote, user, timeouts).await?;
+        Ok((Self::Relayed(conn), ConnectionReader::Relayed(reader)))
     }
-}
 
-impl P2PConnection {
-    async fn connect_to(remote: std::net::SocketAddr, user: &UserIdentity) -> CoreResult<Self> {
-        let mut tcp_stream = net::TcpStream::connect(remote).await?;
-        let (peer_identity, transport) = Self::dead_switch(&mut tcp_stream, async |tcp_stream| {
-            let mut noise = Self::noise_initiator(user)?;
-            let mut buf = [0u8; MAX_FRAME_SIZE];
-            let mut len;
+    /// Returns the relay connection, if this is a connection to a relay server.
+    pub fn relay(&self) -> Option<&RelayedConnection> {
+        match self {
+            Self::P2P(_) => None,
+            Self::Relayed(c) => Some(c),
+        }
+    }
 
-            log::debug!("Beginning noise handshake as initiator");
+    pub async fn disconnect(self) -> CoreResult<()> {
+        delegate!(self, disconnect().await)
+    }
 
-            log::debug!("Sending Noise: `XX: --> e`");
-            len = noise.write_message(&[], &mut buf)?;
-            Frame::raw(&buf[..len])?.send(tcp_stream).await?;
+    pub async fn peer_identity(&self) -> &Identity {
+        delegate!(self, peer_identity().await)
+    }
 
-            log::debug!("Receiving: `XX: <-- e, ee, s, es`");
-            let frame = Frame::recv(tcp_stream).await?;
-            _ = noise.read_message(frame.data(), &mut buf)?;
+    /// The protocol version both peers agreed on before the handshake.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        delegate!(self, protocol_version())
+    }
+
+    /// Returns a handle to queue data for sending over this connection.
+    ///
+    /// The actual sending is done by a separate task, so the handle can be used without
+    /// holding on to the [`Connection`] (or the state it is stored in).
+    pub fn writer(&self) -> ConnectionWriter {
+        delegate!(self, writer())
+    }
+}
 
-            log::debug!("Sending Noise: `XX: --> s, se`");
-            len = noise.write_message(&[], &mut buf)?;
-            Frame::raw(&buf[..len])?.send(tcp_stream).await?;
+impl ConnectionReader {
+    /// Waits for the next [`Packet`] from the peer.
+    pub async fn recv(&mut self) -> CoreResult<Packet> {
+        delegate!(self, recv().await)
+    }
 
-            log::debug!("Finished noise handshake");
+    /// Waits for the next [`Packet`] from the peer, and pings it if it stays silent for too
+    /// long.
+    ///
+    /// Any packet counts as an answer to the ping, the [`FrameBody::Pong`] is returned like the
+    /// others.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::Timeout`] if the peer does not answer the ping in time.
+    pub async fn recv_keepalive(
+        &mut self,
+        writer: &ConnectionWriter,
+        timeouts: &Timeouts,
+    ) -> CoreResult<Packet> {
+        // the same future is polled across the timeouts, so that no half read frame is lost
+        let recv = self.recv();
+        tokio::pin!(recv);
+        if let Ok(result) = tokio::time::timeout(timeouts.keepalive_interval, &mut recv).await {
+            return result;
+        }
 
-            Self::post_handshake(&mut buf, tcp_stream, user, noise, remote).await
-        })
-        .await?;
+        log::trace!("Peer {} is silent, pinging it", writer.remote());
+        writer.send_detached(FrameBody::Ping(rand::random()));
+        tokio::time::timeout(timeouts.keepalive_timeout, &mut recv)
+            .await
+            .map_err(|_| CoreError::Timeout {
+                remote: writer.remote(),
+                stage: "keepalive",
+            })?
+    }
+}
 
-        Ok(Self {
-            stream: tcp_stream,
-            peer_identity,
-            transport,
-        })
+impl P2PConnection {
+    async fn connect_to(
+        remote: std::net::SocketAddr,
+        user: &UserIdentity,
+        timeouts: &Timeouts,
+        access: Option<&HandshakeAccess>,
+    ) -> CoreResult<(Self, P2PConnectionReader)> {
+        let deadline = Instant::now() + timeouts.handshake;
+        let mut tcp_stream = tokio::time::timeout_at(deadline, net::TcpStream::connect(remote))
+            .await
+            .map_err(|_| CoreError::Timeout {
+                remote,
+                stage: "handshake",
+            })??;
+        let (protocol_version, noise) =
+            Self::dead_switch(&mut tcp_stream, remote, deadline, async |tcp_stream| {
+                let version = exchange_version(tcp_stream, remote).await?;
+                let mut noise = Self::noise_initiator(user)?;
+                let mut buf = [0u8; MAX_FRAME_SIZE];
+                let mut len;
+
+                log::debug!("Beginning noise handshake as initiator");
+
+                log::debug!("Sending Noise: `XX: --> e`");
+                len = noise.write_message(&[], &mut buf)?;
+                Frame::raw(&buf[..len])?.send(tcp_stream).await?;
+
+                log::debug!("Receiving: `XX: <-- e, ee, s, es`");
+                let frame = Frame::recv(tcp_stream).await?;
+                _ = noise.read_message(frame.data(), &mut buf)?;
+
+                log::debug!("Sending Noise: `XX: --> s, se`");
+                len = noise.write_message(&[], &mut buf)?;
+                Frame::raw(&buf[..len])?.send(tcp_stream).await?;
+
+                log::debug!("Finished noise handshake");
+                Ok((version, noise))
+            })
+            .await?;
+
+        Self::establish(
+            tcp_stream,
+            remote,
+            user,
+            timeouts,
+            protocol_version,
+            noise,
+            access,
+        )
+        .await
     }
 
     async fn connect_from(
         mut tcp_stream: net::TcpStream,
         remote: std::net::SocketAddr,
         user: &UserIdentity,
-    ) -> CoreResult<Self> {
-        let (peer_identity, transport) = Self::dead_switch(&mut tcp_stream, async |tcp_stream| {
-            let mut noise = Self::noise_responder(user)?;
-            let mut buf = [0u8; MAX_FRAME_SIZE];
-            let mut frame;
-
-            log::debug!("Beginning noise handshake as responder");
-
-            log::debug!("Receiving: `XX: --> e`");
-            frame = Frame::recv(tcp_stream).await?;
-            _ = noise.read_message(frame.data(), &mut buf)?;
-
-            log::debug!("Sending Noise: `XX: <-- e, ee, s, es`");
-            let len = noise.write_message(&[], &mut buf)?;
-            Frame::raw(&buf[..len])?.send(tcp_stream).await?;
-
-            log::debug!("Receiving: `XX: --> s, se`");
-            frame = Frame::recv(tcp_stream).await?;
-            _ = noise.read_message(frame.data(), &mut buf)?;
-
-            log::debug!("Finished noise handshake");
-
-            Self::post_handshake(&mut buf, tcp_stream, user, noise, remote).await
-        })
-        .await?;
-
-        Ok(Self {
-            stream: tcp_stream,
-            peer_identity,
-            transport,
-        })
+        timeouts: &Timeouts,
+        access: Option<&HandshakeAccess>,
+    ) -> CoreResult<(Self, P2PConnectionReader)> {
+        let deadline = Instant::now() + timeouts.handshake;
+        let (protocol_version, noise) =
+            Self::dead_switch(&mut tcp_stream, remote, deadline, async |tcp_stream| {
+                let version = exchange_version(tcp_stream, remote).await?;
+                let mut noise = Self::noise_responder(user)?;
+                let mut buf = [0u8; MAX_FRAME_SIZE];
+                let mut frame;
+
+                log::debug!("Beginning noise handshake as responder");
+
+                log::debug!("Receiving: `XX: --> e`");
+                frame = Frame::recv(tcp_stream).await?;
+                _ = noise.read_message(frame.data(), &mut buf)?;
+
+                log::debug!("Sending Noise: `XX: <-- e, ee, s, es`");
+                let len = noise.write_message(&[], &mut buf)?;
+                Frame::raw(&buf[..len])?.send(tcp_stream).await?;
+
+                log::debug!("Receiving: `XX: --> s, se`");
+                frame = Frame::recv(tcp_stream).await?;
+                _ = noise.read_message(frame.data(), &mut buf)?;
+
+                log::debug!("Finished noise handshake");
+                Ok((version, noise))
+            })
+            .await?;
+
+        Self::establish(
+            tcp_stream,
+            remote,
+            user,
+            timeouts,
+            protocol_version,
+            noise,
+            access,
+        )
+        .await
     }
 
-    async fn post_handshake(
-        buf: &mut [u8; MAX_FRAME_SIZE],
-        stream: &mut net::TcpStream,
+    /// Switches to the transport mode after the noise handshake and exchanges the identities.
+    ///
+    /// A peer that fails the `access` check is disconnected before anything is sent to it.
+    /// From here on, everything goes through the writer task and the reader as [`FrameBody`].
+    async fn establish(
+        stream: net::TcpStream,
+        remote: std::net::SocketAddr,
         user: &UserIdentity,
+        timeouts: &Timeouts,
+        protocol_version: ProtocolVersion,
         noise: snow::HandshakeState,
-        remote: std::net::SocketAddr,
-    ) -> CoreResult<(Identity, TransportState)> {
-        // SREMP uses the identity keys as the noise static key.
+        access: Option<&HandshakeAccess>,
+    ) -> CoreResult<(Self, P2PConnectionReader)> {
+        // SREMP uses the X25519 form of the identity keys as the noise static key.
         let remote_static_key = noise
             .get_remote_static()
             .ok_or(CoreError::NoisePeerHasNoPublicKey(remote))?;
 
-        let peer_key_bytes: &[u8; ed25519_dalek::PUBLIC_KEY_LENGTH] = remote_static_key
+        let peer_dh_key: [u8; 32] = remote_static_key
             .try_into()
             .map_err(|_| CoreError::PeerKeyIsMalformed(remote))?;
 
-        let peer_public_key = ed25519_dalek::VerifyingKey::from_bytes(peer_key_bytes)
-            .map_err(|e| CoreError::PeerKeyIsInvalid { remote, source: e })?;
+        if let Some(access) = access {
+            // dropping the stream closes the connection
+            access
+                .check(&peer_dh_key)
+                .map_err(|reason| CoreError::ConnectionRejected(remote, reason))?;
+        }
 
-        let mut transport = noise.into_transport_mode()?;
+        let (send_cipher, recv_cipher) = cipher::split(noise.into_stateless_transport_mode()?);
+        let (read_half, write_half) = stream.into_split();
+        let (writer, writer_task) = ConnectionWriter::spawn(remote, write_half, send_cipher);
+        let mut reader = P2PConnectionReader {
+            stream: read_half,
+            cipher: recv_cipher,
+            reassembler: Reassembler::default(),
+        };
+
+        let exchange = tokio::time::timeout(
+            timeouts.identity,
+            Self::exchange_identity(&writer, &mut reader, user, remote, peer_dh_key),
+        )
+        .await
+        .unwrap_or(Err(CoreError::Timeout {
+            remote,
+            stage: "identity exchange",
+        }));
+        match exchange {
+            Ok(peer_identity) => {
+                log::debug!("Noise Handshake and identity exchange with peer {remote} successful");
+                Ok((
+                    Self {
+                        peer_identity,
+                        protocol_version,
+                        writer,
+                        writer_task,
+                    },
+                    reader,
+                ))
+            }
+            Err(e) => {
+                // the writer task shuts the stream down once it is closed
+                writer.close();
+                Err(e)
+            }
+        }
+    }
 
+    async fn exchange_identity(
+        writer: &ConnectionWriter,
+        reader: &mut P2PConnectionReader,
+        user: &UserIdentity,
+        remote: std::net::SocketAddr,
+        peer_dh_key: [u8; 32],
+    ) -> CoreResult<Identity> {
         // both send before receiving, then listen for the incoming identity response
         // That way, the identity exchange is simultaneous and we dont need to program an order of
         // who sends first
 
         log::debug!("Sending identity to peer");
-        let mut len = transport.write_message(&rmp_serde::to_vec(&user.identity)?, buf)?;
-        Frame::raw(&buf[..len])?.send(stream).await?;
+        writer
+            .send(&FrameBody::Identity(user.identity.clone()))
+            .await?;
 
         log::debug!("Receiving identity from peer");
-        let frame = Frame::recv(stream).await?;
-        len = transport.read_message(frame.data(), buf)?;
-        let peer_identity: Identity = rmp_serde::from_slice(&buf[..len])?;
+        let peer_identity = match reader.recv().await?.body {
+            FrameBody::Identity(identity) => identity,
+            other => {
+                writer.send_detached(FrameBody::Error("expected the identity".to_string()));
+                return Err(CoreError::UnexpectedPacket {
+                    remote,
+                    kind: other.kind(),
+                });
+            }
+        };
 
         // FIXME: username might be a super long string, we should add some validator for the
         // username.
 
-        if peer_identity.public_key != peer_public_key {
+        if peer_identity.dh_public_key() != peer_dh_key {
             return Err(CoreError::PeerKeyIsInvalid {
                 remote,
                 source: ed25519_dalek::SignatureError::new(),
             });
         }
 
-        log::debug!("Noise Handshake and identity exchange with peer {remote} successful");
-
-        Ok((peer_identity, transport))
+        Ok(peer_identity)
     }
 
     async fn disconnect(mut self) -> CoreResult<()> {
-        self.stream.shutdown().await?;
+        if let Err(e) = self.writer.send(&FrameBody::Goodbye).await {
+            log::debug!("Could not say goodbye to the peer: {e}");
+        }
+        // the writer task shuts the stream down once everything queued has been sent
+        self.writer.close();
+        if let Err(e) = (&mut self.writer_task).await {
+            log::warn!("Writer task of the connection did not finish cleanly: {e}");
+        }
         Ok(())
     }
 
@@ -196,33 +470,73 @@ impl P2PConnection {
         &self.peer_identity
     }
 
-    /// Closes the [`net::TcpStream`] on error
-    async fn dead_switch<T, F>(stream: &mut net::TcpStream, f: F) -> CoreResult<T>
+    fn writer(&self) -> ConnectionWriter {
+        self.writer.clone()
+    }
+
+    fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Closes the [`net::TcpStream`] on error, or if `f` is not done by the `deadline`
+    async fn dead_switch<T, F>(
+        stream: &mut net::TcpStream,
+        remote: std::net::SocketAddr,
+        deadline: Instant,
+        f: F,
+    ) -> CoreResult<T>
     where
         F: AsyncFnOnce(&mut net::TcpStream) -> CoreResult<T>,
     {
-        match f(stream).await {
+        let result = tokio::time::timeout_at(deadline, f(stream))
+            .await
+            .unwrap_or(Err(CoreError::Timeout {
+                remote,
+                stage: "handshake",
+            }));
+        match result {
             Ok(t) => Ok(t),
             Err(e) => {
-                stream
-                    .shutdown()
-                    .await
-                    .expect("could not shutdown the stream on error in dead switch");
+                // the peer might have closed the stream already
+                if let Err(shutdown_error) = stream.shutdown().await {
+                    log::debug!("Could not shut down the stream with {remote}: {shutdown_error}");
+                }
                 Err(e)
             }
         }
     }
 
-    fn noise_builder<'a>(user: &'a UserIdentity) -> CoreResult<snow::Builder<'a>> {
-        Ok(snow::Builder::new(NOISE_PARAMS.clone())
-            .local_private_key(user.private_key().as_bytes())?)
+    fn noise_builder(private_key: &[u8]) -> CoreResult<snow::Builder<'_>> {
+        Ok(snow::Builder::new(NOISE_PARAMS.clone()).local_private_key(private_key)?)
     }
 
     fn noise_initiator(user: &UserIdentity) -> CoreResult<snow::HandshakeState> {
-        Ok(Self::noise_builder(user)?.build_initiator()?)
+        Ok(Self::noise_builder(&user.dh_private_key())?.build_initiator()?)
     }
 
     fn noise_responder(user: &UserIdentity) -> CoreResult<snow::HandshakeState> {
-        Ok(Self::noise_builder(user)?.build_responder()?)
+        Ok(Self::noise_builder(&user.dh_private_key())?.build_responder()?)
+    }
+}
+
+impl Drop for P2PConnection {
+    fn drop(&mut self) {
+        // the reader job holds on to a writer as well, but the connection is over once it is
+        // dropped
+        self.writer.close();
+    }
+}
+
+impl P2PConnectionReader {
+    async fn recv(&mut self) -> CoreResult<Packet> {
+        loop {
+            let frame = Frame::recv(&mut self.stream).await?;
+            let chunk: Chunk = rmp_serde::from_slice(&self.cipher.decrypt(&frame)?)?;
+            if let Some((id, data)) = self.reassembler.insert(chunk)? {
+                let body = FrameBody::decode(&data)?;
+                log::trace!("Received {} packet {id}", body.kind());
+                return Ok(Packet { id, body });
+            }
+        }
     }
 }
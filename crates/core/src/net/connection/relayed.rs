@@ -0,0 +1,149 @@
+use ed25519_dalek::VerifyingKey;
+
+use super::{FrameBody, P2PConnection, P2PConnectionReader, Packet, Timeouts};
+use crate::{
+    error::{CoreError, CoreResult},
+    identity::{Identity, UserIdentity},
+    net::relay::{Challenge, RelayCapabilities, RelayPacket, RelayRegister},
+};
+
+/// A connection to a relay server, which stores and forwards messages for the user
+///
+/// The transport is the same as with a peer, but the relay is not a contact: after the
+/// identity exchange, the user registers with it, see [`crate::net::relay`].
+#[derive(Debug)]
+#[must_use]
+pub struct RelayedConnection {
+    transport: P2PConnection,
+    challenge: Challenge,
+    capabilities: RelayCapabilities,
+}
+
+#[derive(Debug)]
+#[must_use]
+pub struct RelayedConnectionReader {
+    transport: P2PConnectionReader,
+}
+
+impl RelayedConnection {
+    pub(super) async fn connect_to(
+        remote: std::net::SocketAddr,
+        user: &UserIdentity,
+        timeouts: &Timeouts,
+    ) -> CoreResult<(Self, RelayedConnectionReader)> {
+        let (transport, mut reader) =
+            P2PConnection::connect_to(remote, user, timeouts, None).await?;
+        if !transport.peer_identity.flags.is_relay_server {
+            transport.disconnect().await?;
+            return Err(CoreError::NotARelay(remote));
+        }
+
+        let registration = tokio::time::timeout(
+            timeouts.identity,
+            Self::register(&transport, &mut reader, user, remote),
+        )
+        .await
+        .unwrap_or(Err(CoreError::Timeout {
+            remote,
+            stage: "relay registration",
+        }));
+        match registration {
+            Ok((challenge, capabilities)) => {
+                log::debug!("Registered with relay {remote}");
+                Ok((
+                    Self {
+                        transport,
+                        challenge,
+                        capabilities,
+                    },
+                    RelayedConnectionReader { transport: reader },
+                ))
+            }
+            Err(e) => {
+                transport.disconnect().await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Answers the challenge of the relay with a signed [`RelayRegister`].
+    async fn register(
+        transport: &P2PConnection,
+        reader: &mut P2PConnectionReader,
+        user: &UserIdentity,
+        remote: std::net::SocketAddr,
+    ) -> CoreResult<(Challenge, RelayCapabilities)> {
+        let challenge = match reader.recv().await?.body {
+            FrameBody::Relay(RelayPacket::Challenge(challenge)) => challenge,
+            other => {
+                return Err(CoreError::UnexpectedPacket {
+                    remote,
+                    kind: other.kind(),
+                });
+            }
+        };
+
+        #[allow(clippy::cast_possible_truncation)] // MAX_MESSAGE_SIZE is 16 MiB
+        let wanted = RelayCapabilities {
+            max_message_size: super::MAX_MESSAGE_SIZE as u32,
+            max_storage_duration: std::time::Duration::MAX,
+        };
+        let register = RelayRegister::sign(
+            user,
+            &transport.peer_identity.public_key,
+            &challenge,
+            wanted,
+        );
+        transport
+            .writer
+            .send(&FrameBody::Relay(RelayPacket::Register(register)))
+            .await?;
+
+        match reader.recv().await?.body {
+            FrameBody::Relay(RelayPacket::Registered(capabilities)) => {
+                Ok((challenge, capabilities))
+            }
+            other => Err(CoreError::UnexpectedPacket {
+                remote,
+                kind: other.kind(),
+            }),
+        }
+    }
+
+    /// The key of the relay, which requests to it are signed for
+    pub fn relay_key(&self) -> &VerifyingKey {
+        &self.transport.peer_identity.public_key
+    }
+
+    /// The challenge the relay sent when registering, which requests to it are signed for
+    pub fn challenge(&self) -> &Challenge {
+        &self.challenge
+    }
+
+    /// The limits the relay applies to the user
+    pub fn capabilities(&self) -> &RelayCapabilities {
+        &self.capabilities
+    }
+
+    pub(super) async fn disconnect(self) -> CoreResult<()> {
+        self.transport.disconnect().await
+    }
+
+    pub(super) async fn peer_identity(&self) -> &Identity {
+        self.transport.peer_identity().await
+    }
+
+    pub(super) fn writer(&self) -> super::ConnectionWriter {
+        self.transport.writer()
+    }
+
+    pub(super) fn protocol_version(&self) -> super::ProtocolVersion {
+        self.transport.protocol_version()
+    }
+}
+
+impl RelayedConnectionReader {
+    pub(super) async fn recv(&mut self) -> CoreResult<Packet> {
+        self.transport.recv().await
+    }
+}
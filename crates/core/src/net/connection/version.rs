@@ -0,0 +1,178 @@
+use std::{fmt::Display, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::frame::Frame;
+use crate::error::{CoreError, CoreResult};
+
+/// Name every SREMP peer announces in its [`VersionHeader`]
+pub const PROTOCOL_NAME: &str = "SREMP";
+/// The protocol version implemented here
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 2, minor: 0 };
+
+/// Version of the SREMP protocol spoken over a connection
+///
+/// Peers can talk to each other if the major versions are equal. A connection then uses the
+/// lower minor version of both peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ProtocolVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+/// The first data sent by each peer, before the noise handshake starts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct VersionHeader {
+    protocol_name: String,
+    version_major: u8,
+    version_minor: u8,
+}
+
+impl ProtocolVersion {
+    /// Finds the version to use with a peer, if the peer is compatible at all.
+    #[must_use]
+    pub fn negotiate(self, peer: Self) -> Option<Self> {
+        (self.major == peer.major).then(|| self.min(peer))
+    }
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl VersionHeader {
+    fn ours() -> Self {
+        Self {
+            protocol_name: PROTOCOL_NAME.to_string(),
+            version_major: PROTOCOL_VERSION.major,
+            version_minor: PROTOCOL_VERSION.minor,
+        }
+    }
+
+    fn version(&self) -> ProtocolVersion {
+        ProtocolVersion {
+            major: self.version_major,
+            minor: self.version_minor,
+        }
+    }
+}
+
+/// Exchanges the [`VersionHeader`] with the peer, returning the version to use for the
+/// connection.
+///
+/// Like the identity exchange, both peers send their header before receiving the other one.
+pub(super) async fn exchange_version<S>(
+    stream: &mut S,
+    remote: SocketAddr,
+) -> CoreResult<ProtocolVersion>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    log::debug!("Sending version header {PROTOCOL_VERSION}");
+    Frame::raw(&rmp_serde::to_vec(&VersionHeader::ours())?)?
+        .send(stream)
+        .await?;
+
+    log::debug!("Receiving version header");
+    let frame = Frame::recv(stream).await?;
+    let header: VersionHeader =
+        rmp_serde::from_slice(frame.data()).map_err(|_| CoreError::IncompatiblePeer {
+            remote,
+            reason: "it did not send a version header".to_string(),
+        })?;
+
+    if header.protocol_name != PROTOCOL_NAME {
+        return Err(CoreError::IncompatiblePeer {
+            remote,
+            reason: format!(
+                "it speaks {:?} instead of {PROTOCOL_NAME}",
+                header.protocol_name
+            ),
+        });
+    }
+
+    let version = PROTOCOL_VERSION
+        .negotiate(header.version())
+        .ok_or_else(|| CoreError::IncompatiblePeer {
+            remote,
+            reason: format!(
+                "it uses version {}, but version {PROTOCOL_VERSION} is required",
+                header.version()
+            ),
+        })?;
+
+    log::debug!("Using protocol version {version} with {remote}");
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REMOTE: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 1);
+
+    /// Lets a peer that sends `header` exchange versions with us
+    async fn exchange_with(header: &VersionHeader) -> CoreResult<ProtocolVersion> {
+        let (mut ours, mut theirs) = tokio::io::duplex(1024);
+        let peer = async {
+            Frame::raw(&rmp_serde::to_vec(header).unwrap())
+                .unwrap()
+                .send(&mut theirs)
+                .await
+                .unwrap();
+            let frame = Frame::recv(&mut theirs).await.unwrap();
+            rmp_serde::from_slice::<VersionHeader>(frame.data()).unwrap()
+        };
+        let (version, sent) = tokio::join!(exchange_version(&mut ours, REMOTE), peer);
+        assert_eq!(sent, VersionHeader::ours());
+        version
+    }
+
+    fn header(protocol_name: &str, major: u8, minor: u8) -> VersionHeader {
+        VersionHeader {
+            protocol_name: protocol_name.to_string(),
+            version_major: major,
+            version_minor: minor,
+        }
+    }
+
+    #[test]
+    fn only_equal_major_versions_negotiate() {
+        let v = |major, minor| ProtocolVersion { major, minor };
+        assert_eq!(v(1, 2).negotiate(v(1, 0)), Some(v(1, 0)));
+        assert_eq!(v(1, 0).negotiate(v(1, 2)), Some(v(1, 0)));
+        assert_eq!(v(1, 0).negotiate(v(2, 0)), None);
+    }
+
+    #[test]
+    fn header_survives_encoding() {
+        let ours = VersionHeader::ours();
+        let decoded: VersionHeader =
+            rmp_serde::from_slice(&rmp_serde::to_vec(&ours).unwrap()).unwrap();
+        assert_eq!(decoded, ours);
+        assert_eq!(decoded.version(), PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn compatible_peer_is_accepted() {
+        let peer = header(PROTOCOL_NAME, PROTOCOL_VERSION.major, u8::MAX);
+        assert_eq!(exchange_with(&peer).await.unwrap(), PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn incompatible_peers_are_refused() {
+        for peer in [
+            header(PROTOCOL_NAME, PROTOCOL_VERSION.major + 1, 0),
+            header("HTTP", PROTOCOL_VERSION.major, PROTOCOL_VERSION.minor),
+        ] {
+            assert!(matches!(
+                exchange_with(&peer).await,
+                Err(CoreError::IncompatiblePeer { remote: REMOTE, .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::net::SocketAddr;
+
+use async_channel::{Receiver, Sender};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+use super::{FrameBody, chunk, cipher::SendCipher};
+use crate::error::{CoreError, CoreResult};
+
+/// How many payloads may be queued for a connection before senders have to wait
+const WRITER_QUEUE_CAPACITY: usize = 32;
+
+/// Handle to the task that sends data over a connection.
+///
+/// Cloning the handle is cheap, and sending only waits for the writer task, so that no other
+/// lock has to be held while data goes over the network.
+#[derive(Debug, Clone)]
+pub struct ConnectionWriter {
+    remote: SocketAddr,
+    outgoing: Sender<Outgoing>,
+}
+
+/// Plaintext waiting to be encrypted and sent by the writer task
+#[derive(Debug)]
+struct Outgoing {
+    data: Vec<u8>,
+    sent: oneshot::Sender<CoreResult<u64>>,
+}
+
+impl ConnectionWriter {
+    /// Spawns the writer task for a stream, with the handle to use it.
+    pub(super) fn spawn<W>(
+        remote: SocketAddr,
+        stream: W,
+        cipher: SendCipher,
+    ) -> (Self, JoinHandle<()>)
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (outgoing, queue) = async_channel::bounded(WRITER_QUEUE_CAPACITY);
+        let task = tokio::spawn(job_writer(remote, stream, cipher, queue));
+        (Self { remote, outgoing }, task)
+    }
+
+    /// Sends a [`FrameBody`] to the peer, returning once it was written to the network.
+    ///
+    /// Returns the id of the packet, which the peer uses to acknowledge it.
+    pub async fn send(&self, body: &FrameBody) -> CoreResult<u64> {
+        let data = body.encode()?;
+        let (sent, sent_rx) = oneshot::channel();
+        self.outgoing
+            .send(Outgoing { data, sent })
+            .await
+            .map_err(|_| CoreError::ConnectionClosed(self.remote))?;
+        sent_rx
+            .await
+            .map_err(|_| CoreError::ConnectionClosed(self.remote))?
+    }
+
+    /// Queues a [`FrameBody`] for the peer without waiting for it, for replies from the reader.
+    ///
+    /// The reader must not wait for the writer, otherwise both peers could end up waiting for
+    /// each other to read. Replies go through the same queue as everything else, so they keep
+    /// their order, and they are dropped if the queue is full because the peer does not read.
+    pub fn send_detached(&self, body: FrameBody) {
+        let data = match body.encode() {
+            Ok(data) => data,
+            Err(e) => {
+                log::debug!("Could not encode {} for {}: {e}", body.kind(), self.remote);
+                return;
+            }
+        };
+        // nobody waits for the result
+        let (sent, _) = oneshot::channel();
+        if let Err(e) = self.outgoing.try_send(Outgoing { data, sent }) {
+            let reason = if e.is_full() {
+                "the queue is full"
+            } else {
+                "it is closed"
+            };
+            log::debug!(
+                "Could not send {} to {}: {reason}",
+                body.kind(),
+                self.remote
+            );
+        }
+    }
+
+    /// The address of the peer this writer sends to.
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Stops accepting new data. The writer task finishes once the queue is empty.
+    pub(super) fn close(&self) {
+        self.outgoing.close();
+    }
+}
+
+async fn job_writer<W>(
+    remote: SocketAddr,
+    mut stream: W,
+    mut cipher: SendCipher,
+    queue: Receiver<Outgoing>,
+) where
+    W: AsyncWrite + Unpin,
+{
+    let mut next_message_id: u64 = 0;
+    while let Ok(outgoing) = queue.recv().await {
+        let message_id = next_message_id;
+        let chunks = match chunk::split(message_id, &outgoing.data) {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                _ = outgoing.sent.send(Err(e));
+                continue;
+            }
+        };
+        next_message_id += 1;
+
+        let mut result = Ok(message_id);
+        let mut failed = false;
+        for chunk in chunks {
+            // the nonce only advances when encrypting succeeds, so the session stays usable
+            let frame = match rmp_serde::to_vec(&chunk)
+                .map_err(Into::into)
+                .and_then(|data| cipher.encrypt(&data))
+            {
+                Ok(frame) => frame,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            if let Err(e) = frame.send(&mut stream).await {
+                result = Err(e);
+                failed = true;
+                break;
+            }
+        }
+
+        // the sender might not be interested in the result anymore
+        _ = outgoing.sent.send(result);
+        if failed {
+            log::warn!("Could not write to the connection with {remote}, closing it");
+            break;
+        }
+    }
+
+    queue.close();
+    if let Err(e) = stream.shutdown().await {
+        log::debug!("Could not shut down the connection with {remote}: {e}");
+    }
+    log::debug!("Writer for the connection with {remote} has finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::connection::{
+        chunk::{Chunk, Reassembler},
+        cipher::{self, RecvCipher},
+        frame::Frame,
+    };
+
+    /// A finished noise session, split into the sending side and the receiving side of the peer
+    fn session() -> (SendCipher, RecvCipher) {
+        let params: snow::params::NoiseParams =
+            "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+        let mut initiator = snow::Builder::new(params.clone())
+            .build_initiator()
+            .unwrap();
+        let mut responder = snow::Builder::new(params).build_responder().unwrap();
+        let mut buf = [0u8; 1024];
+        let mut payload = [0u8; 1024];
+        let len = initiator.write_message(&[], &mut buf).unwrap();
+        responder.read_message(&buf[..len], &mut payload).unwrap();
+        let len = responder.write_message(&[], &mut buf).unwrap();
+        initiator.read_message(&buf[..len], &mut payload).unwrap();
+
+        let (send, _) = cipher::split(initiator.into_stateless_transport_mode().unwrap());
+        let (_, recv) = cipher::split(responder.into_stateless_transport_mode().unwrap());
+        (send, recv)
+    }
+
+    /// Reads everything the writer sends until it shuts down the stream
+    async fn receive_all(
+        stream: &mut (impl tokio::io::AsyncRead + Unpin),
+        mut cipher: RecvCipher,
+    ) -> Vec<FrameBody> {
+        let mut reassembler = Reassembler::default();
+        let mut bodies = Vec::new();
+        while let Ok(frame) = Frame::recv(stream).await {
+            let chunk: Chunk = rmp_serde::from_slice(&cipher.decrypt(&frame).unwrap()).unwrap();
+            if let Some((_, data)) = reassembler.insert(chunk).unwrap() {
+                bodies.push(FrameBody::decode(&data).unwrap());
+            }
+        }
+        bodies
+    }
+
+    fn remote() -> SocketAddr {
+        "127.0.0.1:9999".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn detached_replies_keep_their_order() {
+        let (send, recv) = session();
+        let (ours, mut theirs) = tokio::io::duplex(1024 * 1024);
+        let (writer, task) = ConnectionWriter::spawn(remote(), ours, send);
+
+        for value in 0..WRITER_QUEUE_CAPACITY as u64 {
+            writer.send_detached(FrameBody::Pong(value));
+        }
+        writer.close();
+        task.await.unwrap();
+
+        let values: Vec<u64> = receive_all(&mut theirs, recv)
+            .await
+            .into_iter()
+            .map(|body| match body {
+                FrameBody::Pong(value) => value,
+                other => panic!("unexpected {}", other.kind()),
+            })
+            .collect();
+        assert_eq!(
+            values,
+            (0..WRITER_QUEUE_CAPACITY as u64).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn detached_replies_are_dropped_when_the_queue_is_full() {
+        let (send, recv) = session();
+        let (ours, mut theirs) = tokio::io::duplex(1024 * 1024);
+        let (writer, task) = ConnectionWriter::spawn(remote(), ours, send);
+
+        // nothing is awaited in between, so the writer task cannot empty the queue
+        let sent = WRITER_QUEUE_CAPACITY as u64 * 2;
+        for value in 0..sent {
+            writer.send_detached(FrameBody::Pong(value));
+        }
+        writer.close();
+        task.await.unwrap();
+
+        let received = receive_all(&mut theirs, recv).await;
+        assert_eq!(received.len(), WRITER_QUEUE_CAPACITY);
+        assert!(
+            matches!(received.last(), Some(FrameBody::Pong(value)) if *value == WRITER_QUEUE_CAPACITY as u64 - 1)
+        );
+    }
+}
@@ -1,18 +1,32 @@
-use std::{collections::hash_map::Entry, net::SocketAddr};
+use std::{io::ErrorKind, net::SocketAddr, sync::Arc};
 
 use async_channel::{Receiver, Sender};
-use log::{debug, error, info, warn};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net,
-};
+use chrono::Utc;
+use ed25519_dalek::VerifyingKey;
+use log::{debug, error, info, trace, warn};
+use tokio::net;
+use zeroize::Zeroizing;
 
 use crate::{
+    chat::{
+        Chat,
+        messages::{DeliveryState, Message, MessageId},
+        receipts::{Receipt, ReceiptKind},
+    },
+    crypto::ratchet::RatchetMessage,
     error::{CoreError, CoreResult},
-    net::{NetworkCommand, NetworkEvent, connection::Connection},
+    identity::{ContactIdentity, Identity, Trust, UserIdentity, format_key},
+    net::{
+        NetworkCommand, NetworkEvent, RejectionReason,
+        connection::{Connection, ConnectionReader, ConnectionWriter, FrameBody, Timeouts},
+        relay::RelayPacket,
+    },
     state::{ConnectionData, State, StateSync},
 };
 
+/// How long the listener job waits for a connection before it looks at the state again
+const LISTENER_ACCEPT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_millis(100);
+
 impl State {
     pub(crate) async fn job_network_command_processing(
         state: &StateSync,
@@ -20,28 +34,54 @@ impl State {
         event_channel: &mut Sender<NetworkEvent>,
     ) -> CoreResult<()> {
         let cmd = command_channel.recv().await?;
-        let event = state.write().await.process_network_command(cmd).await?;
+        let event = match Self::process_network_command(state, cmd, event_channel).await {
+            Ok(event) => event,
+            Err(e) => {
+                error!("Could not process network command: {e}");
+                return Ok(());
+            }
+        };
         event_channel.send(event).await?;
         Ok(())
     }
 
-    // TODO: monitor connection or something? or create a job for each connection anyways?
-
     pub(crate) async fn job_network_listener(
         state: &StateSync,
         command_channel: &mut Receiver<NetworkCommand>,
         event_channel: &mut Sender<NetworkEvent>,
     ) -> CoreResult<()> {
-        if state.read().await.listener.is_some() {
-            let mut state_b = state.write().await;
-            let listener = state_b.listener.as_mut().unwrap();
-            let (stream, remote) = match listener.accept().await {
-                Ok(s) => s,
-                Err(e) => {
-                    warn!("Could not accept connection attempt to listener: {e}");
-                    return Ok(());
-                }
-            };
+        // the lock must not be held while waiting for connections, as the connection readers
+        // need it to store incoming messages
+        let listener = state.read().await.listener.clone();
+        if let Some(listener) = listener {
+            let (stream, remote) =
+                match tokio::time::timeout(LISTENER_ACCEPT_TIMEOUT, listener.accept()).await {
+                    Ok(Ok(s)) => s,
+                    Ok(Err(e)) => {
+                        warn!("Could not accept connection attempt to listener: {e}");
+                        return Ok(());
+                    }
+                    Err(_timeout) => return Ok(()),
+                };
+            let denied = state
+                .read()
+                .await
+                .settings
+                .listener_access
+                .is_denied(remote.ip());
+            if denied {
+                // dropping the stream closes it, the handshake would reveal our identity
+                drop(stream);
+                warn!("Refusing connection from denied address {remote}");
+                event_channel
+                    .send(NetworkEvent::ConnectionRejected(
+                        remote,
+                        None,
+                        RejectionReason::DeniedAddress,
+                    ))
+                    .await?;
+                return Ok(());
+            }
             let state_c = state.clone();
             let evt_c = event_channel.clone();
             let cmd_c = command_channel.clone();
@@ -59,49 +99,295 @@ impl State {
         Ok(())
     }
 
+    /// Reads from a single connection until it is closed, and handles every packet the peer
+    /// sends.
+    ///
+    /// One of these jobs is spawned for every established connection.
+    async fn job_connection_reader(
+        state: StateSync,
+        remote: SocketAddr,
+        peer_identity: Identity,
+        mut reader: ConnectionReader,
+        writer: ConnectionWriter,
+        timeouts: Timeouts,
+        event_channel: Sender<NetworkEvent>,
+    ) -> CoreResult<()> {
+        loop {
+            let packet = match reader.recv_keepalive(&writer, &timeouts).await {
+                Ok(packet) => packet,
+                Err(CoreError::IO(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    info!("Peer {remote} has closed the connection");
+                    break;
+                }
+                Err(e) => {
+                    warn!("Could not read from the connection with {remote}: {e}");
+                    writer.send_detached(FrameBody::Error(e.to_string()));
+                    break;
+                }
+            };
+
+            match packet.body {
+                FrameBody::Message(encrypted) => {
+                    writer.send_detached(FrameBody::Ack(packet.id));
+                    let Some((msg_id, event)) =
+                        Self::receive_encrypted(&state, remote, &peer_identity, &encrypted).await
+                    else {
+                        continue;
+                    };
+                    // a repeated message might mean that our receipt was lost, so always send it
+                    match state
+                        .read()
+                        .await
+                        .sign_receipt(msg_id, ReceiptKind::Delivered)
+                    {
+                        Ok(receipt) => writer.send_detached(FrameBody::Receipt(receipt)),
+                        Err(e) => warn!("Could not sign the receipt for message {msg_id}: {e}"),
+                    }
+                    match event {
+                        Some(event) => event_channel.send(event).await?,
+                        None => debug!("Peer {remote} sent message {msg_id} again, dropping it"),
+                    }
+                }
+                FrameBody::Receipt(receipt) => {
+                    if let Err(e) = receipt.verify(&peer_identity.public_key) {
+                        warn!("Peer {remote} sent a bad receipt: {e}");
+                        continue;
+                    }
+                    let changed = state.write().await.update_delivery(
+                        &peer_identity.public_key,
+                        receipt.message_id,
+                        receipt.delivery_state(),
+                    );
+                    if let Some(delivery) = changed {
+                        Self::autosave(&state).await;
+                        event_channel
+                            .send(NetworkEvent::MessageStateChanged(
+                                peer_identity.public_key,
+                                receipt.message_id,
+                                delivery,
+                            ))
+                            .await?;
+                    }
+                }
+                FrameBody::Ack(id) => debug!("Peer {remote} acknowledged packet {id}"),
+                FrameBody::Ping(value) => writer.send_detached(FrameBody::Pong(value)),
+                FrameBody::Pong(value) => trace!("Peer {remote} answered ping {value}"),
+                FrameBody::Goodbye => {
+                    info!("Peer {remote} is closing the connection");
+                    break;
+                }
+                FrameBody::Error(reason) => {
+                    warn!("Peer {remote} is closing the connection because of an error: {reason}");
+                    break;
+                }
+                FrameBody::Identity(_) => {
+                    warn!("Peer {remote} sent its identity again, closing the connection");
+                    writer.send_detached(FrameBody::Error(
+                        "the identity was already exchanged".to_string(),
+                    ));
+                    break;
+                }
+                FrameBody::Relay(packet) => {
+                    warn!(
+                        "Peer {remote} sent a {} packet, but is not a relay, closing the connection",
+                        packet.kind()
+                    );
+                    writer.send_detached(FrameBody::Error("not a relay".to_string()));
+                    break;
+                }
+                FrameBody::Rendezvous(packet) => {
+                    warn!(
+                        "Peer {remote} sent a {} packet, but is not a rendezvous server, closing the connection",
+                        packet.kind()
+                    );
+                    writer.send_detached(FrameBody::Error("not a rendezvous server".to_string()));
+                    break;
+                }
+            }
+        }
+
+        // if the connection is not active anymore, it was closed on purpose by us
+        let removed = state.write().await.active_connections.remove(&remote);
+        if removed.is_some() {
+            event_channel
+                .send(NetworkEvent::ConnectionLost(
+                    remote,
+                    peer_identity.public_key,
+                ))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Decrypts a chat message from a peer, and stores it in the chat with the peer.
+    ///
+    /// Returns the id of the message, with the event for it if the message is new. Messages
+    /// that can not be decrypted are dropped, [`None`] is returned for them.
+    pub(super) async fn receive_encrypted(
+        state: &StateSync,
+        remote: SocketAddr,
+        peer_identity: &Identity,
+        encrypted: &RatchetMessage,
+    ) -> Option<(MessageId, Option<NetworkEvent>)> {
+        let decrypted = state
+            .write()
+            .await
+            .decrypt_message(&peer_identity.public_key, encrypted);
+        // the session has moved on either way
+        Self::autosave(state).await;
+        let msg = match decrypted {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Could not decrypt a message from peer {remote}: {e}");
+                return None;
+            }
+        };
+        if msg.meta().author_key != peer_identity.public_key {
+            warn!("Peer {remote} sent a message with a foreign author, dropping it");
+            return None;
+        }
+
+        let msg_id = msg.id();
+        let (msg, is_request) = {
+            let mut state = state.write().await;
+            (
+                state.receive_message(peer_identity, msg),
+                state.requests.contains_key(&peer_identity.public_key),
+            )
+        };
+        let Some(msg) = msg else {
+            return Some((msg_id, None));
+        };
+        Self::autosave(state).await;
+        let event = if is_request {
+            NetworkEvent::ContactRequest(remote, peer_identity.public_key, msg)
+        } else {
+            NetworkEvent::IncomingMessage(remote, peer_identity.public_key, msg)
+        };
+        Some((msg_id, Some(event)))
+    }
+
     pub(crate) async fn process_network_command(
-        &mut self,
+        state: &StateSync,
         command: NetworkCommand,
+        event_channel: &Sender<NetworkEvent>,
     ) -> CoreResult<NetworkEvent> {
         info!("Processing Network Command: {command}");
         let event = match command {
-            NetworkCommand::Connect(remote) => self.connect_to(remote).await?,
-            NetworkCommand::StartListener(listen_addr) => self.listen(listen_addr).await?,
+            NetworkCommand::Connect(remote) => {
+                Self::connect_to(state, remote, None, event_channel).await?
+            }
+            NetworkCommand::ConnectRelay(remote) => {
+                Self::connect_relay(state, remote, event_channel).await?
+            }
+            NetworkCommand::StartListener(listen_addr) => {
+                let event = state.write().await.listen(listen_addr).await?;
+                Self::spawn_register(state, event_channel);
+                event
+            }
             NetworkCommand::StopListener => {
-                if let Some(listener) = self.listener.take() {
-                    info!("Stopping listener");
-                    drop(listener);
-                } else {
-                    warn!("No listener currently exists!")
+                let registration = {
+                    let mut state = state.write().await;
+                    if let Some(listener) = state.listener.take() {
+                        info!("Stopping listener");
+                        drop(listener);
+                    } else {
+                        warn!("No listener currently exists!")
+                    }
+                    state.registration.take()
+                };
+                if let Some(registration) = registration {
+                    Self::spawn_unregister(state, registration);
                 }
                 NetworkEvent::ListenerStopped
             }
-            _ => todo!(),
+            NetworkCommand::Disconnect(remote) => Self::disconnect(state, remote).await?,
+            NetworkCommand::SendMessage(contact, msg) => {
+                Self::send_message(state, contact, msg, event_channel).await?
+            }
+            NetworkCommand::MarkRead(contact_key) => Self::mark_read(state, contact_key).await?,
+            NetworkCommand::Lookup(key) => {
+                NetworkEvent::PeerLookedUp(key, Self::lookup(state, key).await?)
+            }
+            NetworkCommand::ConnectToContact(key) => {
+                // boxed like the connection in it, debug builds run out of stack otherwise
+                Box::pin(Self::connect_to_contact(state, key, event_channel)).await?
+            }
         };
         info!("Event emerged after processing the Network Command: {event}");
         Ok(event)
     }
 
     async fn init_connection(
-        &mut self,
+        state: &StateSync,
         remote: SocketAddr,
         connection: Connection,
+        reader: ConnectionReader,
+        incoming: bool,
+        event_channel: &Sender<NetworkEvent>,
     ) -> CoreResult<NetworkEvent> {
         debug!("Initializing TLS connection for {remote}");
         let remote_identity = connection.peer_identity().await.clone();
+        let writer = connection.writer();
 
-        match self.active_connections.entry(remote) {
+        let (events, timeouts) = {
+            let mut state = state.write().await;
             // we already have a connection with this socket addr???
-            Entry::Occupied(_en) => {
+            if state.active_connections.contains_key(&remote) {
+                drop(state);
                 warn!("Duplicated connection, closing second connection...");
                 connection.disconnect().await?;
                 return Ok(NetworkEvent::ConnectionAborted(remote));
             }
-            Entry::Vacant(en) => en.insert(ConnectionData {
-                conn: connection,
-                iden: remote_identity.clone(),
-            }),
+            let events = match state.admit_peer(remote, &remote_identity, incoming, Utc::now()) {
+                Ok(events) => events,
+                Err(reason) => {
+                    drop(state);
+                    warn!("Rejecting the connection with {remote}: {reason}");
+                    connection.disconnect().await?;
+                    return Ok(NetworkEvent::ConnectionRejected(
+                        remote,
+                        Some(remote_identity.public_key),
+                        reason,
+                    ));
+                }
+            };
+            state.active_connections.insert(
+                remote,
+                ConnectionData {
+                    conn: connection,
+                    iden: remote_identity.clone(),
+                },
+            );
+            (events, state.settings.timeouts)
         };
+        Self::autosave(state).await;
+        for event in events {
+            event_channel.send(event).await?;
+        }
+
+        let state_c = state.clone();
+        let evt_c = event_channel.clone();
+        let peer_identity = remote_identity.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Self::job_connection_reader(
+                state_c,
+                remote,
+                peer_identity,
+                reader,
+                writer,
+                timeouts,
+                evt_c,
+            )
+            .await
+            {
+                log::error!("Error while reading from connection with {remote}: {e}")
+            }
+        });
+
+        // messages might have been waiting for the contact
+        Self::spawn_flush_outbox(state, remote_identity.public_key, event_channel);
 
         Ok(NetworkEvent::ConnectionEstablished(
             remote,
@@ -109,26 +395,338 @@ impl State {
         ))
     }
 
-    async fn connect_to(&mut self, remote: SocketAddr) -> CoreResult<NetworkEvent> {
-        let user_identity = self
-            .user_identity
-            .as_ref()
-            .ok_or(CoreError::NoUserIdentity)?;
-        let connection = Connection::connect_to(remote, user_identity).await?;
-        self.init_connection(remote, connection).await
+    /// Connects to `remote`. If `contact` is given, the peer has to be that contact, see
+    /// [`Connection::connect_to_contact`].
+    pub(super) async fn connect_to(
+        state: &StateSync,
+        remote: SocketAddr,
+        contact: Option<&VerifyingKey>,
+        event_channel: &Sender<NetworkEvent>,
+    ) -> CoreResult<NetworkEvent> {
+        let user_identity = Self::user_identity(state).await?;
+        let timeouts = state.read().await.settings.timeouts;
+        let (connection, reader) = match contact {
+            Some(contact) => {
+                Connection::connect_to_contact(remote, contact, &user_identity, &timeouts).await?
+            }
+            None => Connection::connect_to(remote, &user_identity, &timeouts).await?,
+        };
+        Self::init_connection(state, remote, connection, reader, false, event_channel).await
     }
 
     async fn connect_from(
-        &mut self,
+        state: &StateSync,
         stream: net::TcpStream,
         remote: SocketAddr,
+        event_channel: &Sender<NetworkEvent>,
+    ) -> CoreResult<NetworkEvent> {
+        let user_identity = Self::user_identity(state).await?;
+        let (timeouts, access) = {
+            let state = state.read().await;
+            (state.settings.timeouts, state.handshake_access())
+        };
+        let accepted =
+            Connection::connect_from_listener(stream, remote, &user_identity, &timeouts, &access)
+                .await;
+        let (connection, reader) = match accepted {
+            Err(CoreError::ConnectionRejected(remote, reason)) => {
+                // the identity of the peer is not known before the identity exchange
+                return Ok(NetworkEvent::ConnectionRejected(remote, None, reason));
+            }
+            accepted => accepted?,
+        };
+        Self::init_connection(state, remote, connection, reader, true, event_channel).await
+    }
+
+    pub(super) async fn disconnect(
+        state: &StateSync,
+        remote: SocketAddr,
+    ) -> CoreResult<NetworkEvent> {
+        // removing the connection first keeps its reader from reporting the loss as well
+        let connection = state
+            .write()
+            .await
+            .active_connections
+            .remove(&remote)
+            .ok_or(CoreError::NoConnection(remote))?;
+        let peer_key = connection.iden.public_key;
+
+        connection.conn.disconnect().await?;
+
+        Ok(NetworkEvent::ConnectionLost(remote, peer_key))
+    }
+
+    /// Clones the user identity, so that the handshake can happen without holding the lock.
+    ///
+    /// The prekeys are refreshed first, as the identity is about to be sent to a peer.
+    pub(super) async fn user_identity(state: &StateSync) -> CoreResult<UserIdentity> {
+        let (user, changed) = {
+            let mut state = state.write().await;
+            let user = state
+                .user_identity
+                .as_mut()
+                .ok_or(CoreError::NoUserIdentity)?;
+            let changed = user.refresh_prekeys(Utc::now());
+            (user.clone(), changed)
+        };
+        if changed {
+            Self::autosave(state).await;
+        }
+        Ok(user)
+    }
+
+    /// Queues the message in the outbox of the contact, and sends it if the contact is
+    /// connected.
+    async fn send_message(
+        state: &StateSync,
+        contact: ContactIdentity,
+        msg: Message,
+        event_channel: &Sender<NetworkEvent>,
     ) -> CoreResult<NetworkEvent> {
-        let user_identity = self
+        let contact_key = contact.identity.public_key;
+        let queued = state.write().await.queue_message(contact, msg.clone());
+        if !queued {
+            warn!(
+                "Message {} was sent already, not sending it again",
+                msg.id()
+            );
+        }
+        Self::autosave(state).await;
+        Self::spawn_flush_outbox(state, contact_key, event_channel);
+        Ok(NetworkEvent::MessageQueued(contact_key, msg))
+    }
+
+    /// Flushes the outbox of the contact in the background, see [`State::flush_outbox`].
+    pub(super) fn spawn_flush_outbox(
+        state: &StateSync,
+        contact_key: VerifyingKey,
+        event_channel: &Sender<NetworkEvent>,
+    ) {
+        let state = state.clone();
+        let event_channel = event_channel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Self::flush_outbox(&state, contact_key, &event_channel).await {
+                log::error!("Error while sending queued messages: {e}");
+            }
+        });
+    }
+
+    /// Encrypts a message for a contact and sends it over the connection at `remote`.
+    ///
+    /// If that is a connection to a relay, the message is stored on the relay for the contact.
+    pub(super) async fn write_message(
+        state: &StateSync,
+        remote: SocketAddr,
+        contact_key: VerifyingKey,
+        msg: &Message,
+    ) -> CoreResult<()> {
+        let (writer, body) = {
+            let mut state = state.write().await;
+            let connection = state
+                .active_connections
+                .get(&remote)
+                .ok_or(CoreError::NoConnection(remote))?;
+            let relayed = connection.conn.relay().is_some();
+            if !relayed && connection.iden.public_key != contact_key {
+                return Err(CoreError::ConnectionContactMismatch(remote));
+            }
+            let writer = connection.conn.writer();
+            let contact = if relayed {
+                state
+                    .known_identities
+                    .get(&contact_key)
+                    .ok_or(CoreError::UnknownContact(contact_key))?
+                    .identity
+                    .clone()
+            } else {
+                connection.iden.clone()
+            };
+            let encrypted = state.encrypt_message(&contact, msg)?;
+            let body = if relayed {
+                let store = state.sign_store(contact_key, msg.id(), &encrypted)?;
+                FrameBody::Relay(RelayPacket::Store(store))
+            } else {
+                FrameBody::Message(encrypted)
+            };
+            (writer, body)
+        };
+
+        writer.send(&body).await?;
+        Ok(())
+    }
+
+    /// Marks the messages from a contact as seen, and sends read receipts for them.
+    async fn mark_read(state: &StateSync, contact_key: VerifyingKey) -> CoreResult<NetworkEvent> {
+        let (receipts, writer) = {
+            let mut state = state.write().await;
+            let user_key = state
+                .user_identity
+                .as_ref()
+                .ok_or(CoreError::NoUserIdentity)?
+                .identity
+                .public_key;
+            let chat = state
+                .chats
+                .get_mut(&contact_key)
+                .ok_or(CoreError::UnknownChat(contact_key))?;
+
+            let unread: Vec<MessageId> = chat
+                .messages()
+                .filter(|msg| msg.meta().author_key != user_key && !msg.meta().seen)
+                .map(Message::id)
+                .collect();
+            for id in &unread {
+                let meta = chat
+                    .message_mut(id)
+                    .expect("unread message is in the chat")
+                    .meta_mut();
+                meta.seen = true;
+                meta.delivery.advance(DeliveryState::Read);
+            }
+
+            let receipts = if state.settings.read_receipts {
+                unread
+                    .iter()
+                    .map(|id| state.sign_receipt(*id, ReceiptKind::Read))
+                    .collect::<CoreResult<Vec<_>>>()?
+            } else {
+                Vec::new()
+            };
+            let writer = state
+                .active_connections
+                .values()
+                .find(|data| data.iden.public_key == contact_key)
+                .map(|data| data.conn.writer());
+            (receipts, writer)
+        };
+        Self::autosave(state).await;
+
+        match writer {
+            Some(writer) => {
+                for receipt in receipts {
+                    writer.send(&FrameBody::Receipt(receipt)).await?;
+                }
+            }
+            None if !receipts.is_empty() => {
+                debug!(
+                    "No connection with {}, the read receipts are not sent",
+                    format_key(&contact_key)
+                );
+            }
+            None => (),
+        }
+
+        Ok(NetworkEvent::ChatRead(contact_key))
+    }
+
+    fn sign_receipt(&self, message_id: MessageId, kind: ReceiptKind) -> CoreResult<Receipt> {
+        let user = self
+            .user_identity
+            .as_ref()
+            .ok_or(CoreError::NoUserIdentity)?;
+        Ok(Receipt::sign(user, message_id, kind))
+    }
+
+    /// Moves one of our messages in the chat with a contact to a new [`DeliveryState`].
+    ///
+    /// Returns the new state if it has changed. [`DeliveryState::Failed`] is only taken over
+    /// while the message is still pending.
+    pub(super) fn update_delivery(
+        &mut self,
+        contact_key: &VerifyingKey,
+        message_id: MessageId,
+        to: DeliveryState,
+    ) -> Option<DeliveryState> {
+        let user_key = self.user_identity.as_ref()?.identity.public_key;
+        let msg = self.chats.get_mut(contact_key)?.message_mut(&message_id)?;
+        if msg.meta().author_key != user_key {
+            return None;
+        }
+
+        let delivery = &mut msg.meta_mut().delivery;
+        let changed = match to {
+            DeliveryState::Failed if *delivery == DeliveryState::Pending => {
+                *delivery = DeliveryState::Failed;
+                true
+            }
+            DeliveryState::Failed => false,
+            to => delivery.advance(to),
+        };
+        changed.then_some(*delivery)
+    }
+
+    /// Encrypts a message for a contact with the ratchet sessions of that contact.
+    fn encrypt_message(&mut self, contact: &Identity, msg: &Message) -> CoreResult<RatchetMessage> {
+        let user = self
             .user_identity
             .as_ref()
             .ok_or(CoreError::NoUserIdentity)?;
-        let connection = Connection::connect_from(stream, remote, user_identity).await?;
-        self.init_connection(remote, connection).await
+        let plaintext = Zeroizing::new(rmp_serde::to_vec(msg)?);
+        self.sessions
+            .entry(contact.public_key)
+            .or_default()
+            .encrypt(user, contact, &plaintext)
+    }
+
+    /// Decrypts a message from a peer with the ratchet sessions of that peer.
+    fn decrypt_message(
+        &mut self,
+        peer_key: &VerifyingKey,
+        encrypted: &RatchetMessage,
+    ) -> CoreResult<Message> {
+        let user = self
+            .user_identity
+            .as_mut()
+            .ok_or(CoreError::NoUserIdentity)?;
+        let plaintext = Zeroizing::new(
+            self.sessions
+                .entry(*peer_key)
+                .or_default()
+                .decrypt(user, peer_key, encrypted)?,
+        );
+        // a one-time prekey might have been used up
+        user.refresh_prekeys(Utc::now());
+        Ok(rmp_serde::from_slice(&plaintext)?)
+    }
+
+    /// Stores a message from a peer, creating the contact on first contact.
+    ///
+    /// Peers without a chat get one if they are trusted, otherwise the message goes to their
+    /// contact request in [`State::requests`].
+    ///
+    /// Returns [`None`] if the message was already received before.
+    fn receive_message(&mut self, peer_identity: &Identity, mut msg: Message) -> Option<Message> {
+        let now = Utc::now();
+        msg.meta_mut().time_received = now;
+        msg.meta_mut().seen = false;
+        msg.meta_mut().delivery = DeliveryState::Delivered;
+
+        let contact = self
+            .known_identities
+            .entry(peer_identity.public_key)
+            .and_modify(|contact| contact.set_last_seen(now))
+            .or_insert_with(|| ContactIdentity {
+                identity: peer_identity.clone(),
+                trust: Trust::Unknown,
+                first_seen: now,
+                last_seen: now,
+                last_address: None,
+                pinned: false,
+            })
+            .clone();
+
+        let chats = if self.chats.contains_key(&peer_identity.public_key)
+            || contact.trust == Trust::Trusted
+        {
+            &mut self.chats
+        } else {
+            &mut self.requests
+        };
+        chats
+            .entry(peer_identity.public_key)
+            .or_insert_with(|| Chat::new(contact))
+            .add_message(msg.clone())
+            .then_some(msg)
     }
 
     async fn listen(&mut self, listen_addr: SocketAddr) -> CoreResult<NetworkEvent> {
@@ -139,7 +737,7 @@ impl State {
         let listener = net::TcpListener::bind(listen_addr).await?;
         let listen_addr = listener.local_addr()?;
 
-        self.listener = Some(listener);
+        self.listener = Some(Arc::new(listener));
 
         Ok(NetworkEvent::ListenerStarted(listen_addr))
     }
@@ -151,9 +749,83 @@ impl State {
         event_channel: Sender<NetworkEvent>,
         _command_channel: Receiver<NetworkCommand>,
     ) -> CoreResult<()> {
-        let event = state.write().await.connect_from(stream, remote).await?;
+        let event = Self::connect_from(&state, stream, remote, &event_channel).await?;
         event_channel.send(event).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::UserIdentity;
+
+    fn message_from(peer: &Identity, text: &str) -> Message {
+        Message::new_text(text, Utc::now(), peer.public_key)
+    }
+
+    #[test]
+    fn unknown_peer_lands_in_the_requests() {
+        let mut state = State::default();
+        let stranger = UserIdentity::build("stranger").unwrap().identity;
+        let msg = message_from(&stranger, "hello");
+        assert!(state.receive_message(&stranger, msg.clone()).is_some());
+        // the same message is stored only once
+        assert!(state.receive_message(&stranger, msg).is_none());
+
+        assert!(state.chats.is_empty());
+        assert_eq!(state.requests[&stranger.public_key].messages().len(), 1);
+        assert_eq!(
+            state.known_identities[&stranger.public_key].trust,
+            Trust::Unknown
+        );
+
+        state.accept_request(&stranger.public_key).unwrap();
+        assert!(state.requests.is_empty());
+        state.receive_message(&stranger, message_from(&stranger, "again"));
+        assert_eq!(state.chats[&stranger.public_key].messages().len(), 2);
+    }
+
+    #[test]
+    fn trusted_contact_gets_a_chat() {
+        let mut state = State::default();
+        let friend = UserIdentity::build("friend").unwrap().identity;
+        state.known_identities.insert(
+            friend.public_key,
+            ContactIdentity {
+                identity: friend.clone(),
+                trust: Trust::Trusted,
+                first_seen: Utc::now(),
+                last_seen: Utc::now(),
+                last_address: None,
+                pinned: false,
+            },
+        );
+        state.receive_message(&friend, message_from(&friend, "hello"));
+        assert!(state.requests.is_empty());
+        assert_eq!(state.chats[&friend.public_key].messages().len(), 1);
+    }
+
+    #[test]
+    fn rejected_and_blocked_requests_are_gone() {
+        let mut state = State::default();
+        let stranger = UserIdentity::build("stranger").unwrap().identity;
+        state.receive_message(&stranger, message_from(&stranger, "hello"));
+        state.reject_request(&stranger.public_key).unwrap();
+        assert!(state.requests.is_empty());
+        assert!(matches!(
+            state.accept_request(&stranger.public_key),
+            Err(CoreError::NoContactRequest(_))
+        ));
+
+        // writing again makes a new request
+        state.receive_message(&stranger, message_from(&stranger, "hello?"));
+        state.block_contact(&stranger.public_key).unwrap();
+        assert!(state.requests.is_empty());
+        assert_eq!(
+            state.known_identities[&stranger.public_key].trust,
+            Trust::Rejected
+        );
+    }
+}
@@ -1,28 +1,47 @@
 use std::{fmt::Display, net::SocketAddr};
 
 use async_channel::{Receiver, Sender};
+use chrono::{DateTime, Utc};
 use ed25519_dalek::VerifyingKey;
 use log::info;
 
 use crate::{
-    chat::messages::Message,
+    chat::messages::{DeliveryState, Message, MessageId},
     error::CoreResult,
     identity::{ContactIdentity, format_key},
+    net::rendezvous::{Endpoint, PeerInfo},
     state::{State, StateSync},
 };
 
 pub mod connection;
 mod jobs;
+pub mod outbox;
+mod policy;
+pub mod reconnect;
+pub mod relay;
+pub mod rendezvous;
+pub use policy::{AcceptPolicy, IpNet, ListenerAccess, RejectionReason, parse_network};
+pub use reconnect::ReconnectState;
 
 #[derive(Debug, Clone)]
 #[allow(clippy::large_enum_variant)]
 pub enum NetworkCommand {
     Connect(SocketAddr),
+    /// Connects to the relay server at the address, see [`relay::client`]
+    ConnectRelay(SocketAddr),
     Disconnect(SocketAddr),
-    SendMessage(SocketAddr, ContactIdentity, Message),
+    /// Queues a message for the contact, it is sent as soon as the contact is connected
+    SendMessage(ContactIdentity, Message),
     /// Associated [SocketAddr] is the local addres on which to listen, not a remote address
     StartListener(SocketAddr),
     StopListener,
+    /// Marks all messages in the chat with a contact as seen, sending read receipts if enabled
+    MarkRead(VerifyingKey),
+    /// Looks up where the contact can be reached on the rendezvous server, see
+    /// [`rendezvous::client`]
+    Lookup(VerifyingKey),
+    /// Looks up the contact on the rendezvous server, and connects to it
+    ConnectToContact(VerifyingKey),
 }
 
 #[derive(Debug, Clone)]
@@ -31,11 +50,37 @@ pub enum NetworkEvent {
     ConnectionLost(SocketAddr, VerifyingKey),
     IncomingMessage(SocketAddr, VerifyingKey, Message),
     MessageSent(SocketAddr, VerifyingKey, Message),
+    /// A message was put into the outbox of the contact, see [`outbox`]
+    MessageQueued(VerifyingKey, Message),
     /// We stopped connecting for some reason
     ConnectionAborted(SocketAddr),
     ConnectionReset(SocketAddr),
     ListenerStarted(SocketAddr),
     ListenerStopped,
+    /// A message we sent in the chat with the contact has reached a new [`DeliveryState`]
+    MessageStateChanged(VerifyingKey, MessageId, DeliveryState),
+    /// All messages in the chat with the contact were marked as seen
+    ChatRead(VerifyingKey),
+    /// A peer without a chat sent a message, which was put into its contact request, see
+    /// [`crate::chat::requests`]
+    ContactRequest(SocketAddr, VerifyingKey, Message),
+    /// The peer was disconnected, contains its key if the handshake was done already
+    ConnectionRejected(SocketAddr, Option<VerifyingKey>, RejectionReason),
+    /// A peer uses the username of a known contact, but with another key. Either the contact
+    /// has a new key, or someone pretends to be them.
+    ///
+    /// Contains the username, the key of the known contact and the new key.
+    KeyChanged(SocketAddr, String, VerifyingKey, VerifyingKey),
+    /// A known contact now uses another username, contains the old and the new username
+    UsernameChanged(VerifyingKey, String, String),
+    /// Reconnecting to the contact has progressed, see [`reconnect`]
+    ReconnectStateChanged(VerifyingKey, ReconnectState),
+    /// The listener was registered with the rendezvous server at the address, contains the
+    /// endpoint it was registered as and when the registration expires
+    RendezvousRegistered(SocketAddr, Endpoint, DateTime<Utc>),
+    /// A contact was looked up on the rendezvous server, contains what the server knows about
+    /// it, if anything
+    PeerLookedUp(VerifyingKey, Option<PeerInfo>),
 }
 
 macro_rules! start_backend_job {
@@ -77,6 +122,22 @@ impl State {
             rt,
             "network listener job has failed"
         );
+        start_backend_job!(
+            rc_state,
+            command_channel,
+            event_channel,
+            State::job_reconnect,
+            rt,
+            "reconnect job has failed"
+        );
+        start_backend_job!(
+            rc_state,
+            command_channel,
+            event_channel,
+            State::job_rendezvous,
+            rt,
+            "rendezvous job has failed"
+        );
         info!("Background workers have started");
         Ok(())
     }
@@ -89,12 +150,17 @@ impl Display for NetworkCommand {
             "{}",
             match self {
                 Self::Connect(addr) => format!("Connect to {addr}"),
+                Self::ConnectRelay(addr) => format!("Connect to the relay {addr}"),
                 Self::Disconnect(addr) => format!("Disconnect from {addr}"),
-                Self::SendMessage(addr, id, _msg) =>
-                    format!("Send Message to {addr}: {}", id.identity.username()),
+                Self::SendMessage(id, _msg) =>
+                    format!("Send Message to {}", id.identity.username()),
                 Self::StartListener(addr) =>
                     format!("Start listening for incoming connection on {addr}"),
                 Self::StopListener => "Stop listening for incoming connections".to_string(),
+                Self::MarkRead(key) => format!("Mark the chat with {} as read", format_key(key)),
+                Self::Lookup(key) => format!("Look up {}", format_key(key)),
+                Self::ConnectToContact(key) =>
+                    format!("Look up and connect to {}", format_key(key)),
             }
         )
     }
@@ -112,6 +178,10 @@ impl Display for NetworkEvent {
                     format!("Peer {addr} ({}) has disconnected", format_key(key)),
                 Self::IncomingMessage(addr, key, _msg) =>
                     format!("Message received from {addr} ({})", format_key(key)),
+                Self::ContactRequest(addr, key, _msg) =>
+                    format!("Contact request received from {addr} ({})", format_key(key)),
+                Self::MessageQueued(key, msg) =>
+                    format!("Message {} to {} was queued", msg.id(), format_key(key)),
                 Self::MessageSent(addr, key, _msg) =>
                     format!("Message sent to {addr} ({})", format_key(key)),
                 Self::ConnectionAborted(addr) =>
@@ -121,6 +191,32 @@ impl Display for NetworkEvent {
                 Self::ListenerStopped => "Listener for incoming connection was stopped".to_string(),
                 Self::ConnectionReset(addr) =>
                     format!("Bad connection awards from {addr} was aborted",),
+                Self::MessageStateChanged(key, id, delivery) =>
+                    format!("Message {id} to {} is now {delivery}", format_key(key)),
+                Self::ChatRead(key) => format!("Chat with {} was read", format_key(key)),
+                Self::ConnectionRejected(addr, Some(key), reason) => format!(
+                    "Connection with {addr} ({}) was rejected: {reason}",
+                    format_key(key)
+                ),
+                Self::ConnectionRejected(addr, None, reason) =>
+                    format!("Connection with {addr} was rejected: {reason}"),
+                Self::KeyChanged(addr, username, old, new) => format!(
+                    "Peer {addr} uses the username {username:?} of {} with the new key {}",
+                    format_key(old),
+                    format_key(new)
+                ),
+                Self::UsernameChanged(key, old, new) => format!(
+                    "{} changed their username from {old:?} to {new:?}",
+                    format_key(key)
+                ),
+                Self::ReconnectStateChanged(key, reconnect_state) =>
+                    format!("Reconnecting to {}: {reconnect_state}", format_key(key)),
+                Self::RendezvousRegistered(server, endpoint, expires_at) => format!(
+                    "Registered as {endpoint} with the rendezvous server {server} until {expires_at}"
+                ),
+                Self::PeerLookedUp(key, Some(peer)) =>
+                    format!("Found {} at {}", format_key(key), peer.endpoint),
+                Self::PeerLookedUp(key, None) => format!("{} is not registered", format_key(key)),
             }
         )
     }
@@ -0,0 +1,301 @@
+//! Messages waiting to be sent to contacts
+//!
+//! Every message the user sends goes through the outbox of the contact, which is stored with the
+//! state. The outbox is flushed in order whenever the contact is connected, or the user is
+//! connected to a [relay](crate::net::relay::client). A message that fails to send is retried
+//! after a growing delay, as long as there is a way to reach the contact. A message that could
+//! not be sent [`MAX_SEND_ATTEMPTS`] times is given up and marked as [`DeliveryState::Failed`].
+
+use std::{net::SocketAddr, time::Duration};
+
+use async_channel::Sender;
+use ed25519_dalek::VerifyingKey;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chat::{
+        Chat,
+        messages::{DeliveryState, Message, MessageId},
+    },
+    error::CoreResult,
+    identity::ContactIdentity,
+    net::NetworkEvent,
+    state::{State, StateSync},
+};
+
+/// How often sending a message may fail before it is given up
+pub const MAX_SEND_ATTEMPTS: u32 = 5;
+/// Delay before retrying a message after the first failed attempt, doubled for every further one
+const SEND_RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// A message in the outbox, the message itself is stored in the chat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub id: MessageId,
+    /// How often sending the message has failed
+    pub attempts: u32,
+}
+
+impl State {
+    /// Stores a message of the user in the chat with the contact, and queues it for sending.
+    ///
+    /// Returns `false` if the message is in the chat already, it is not queued again then.
+    pub fn queue_message(&mut self, contact: ContactIdentity, mut msg: Message) -> bool {
+        let contact_key = contact.identity.public_key;
+        msg.meta_mut().delivery = DeliveryState::Pending;
+        let id = msg.id();
+
+        // writing to a peer accepts its contact request
+        let chat = self
+            .requests
+            .remove(&contact_key)
+            .unwrap_or_else(|| Chat::new(contact));
+        if !self
+            .chats
+            .entry(contact_key)
+            .or_insert(chat)
+            .add_message(msg)
+        {
+            return false;
+        }
+        self.outbox
+            .entry(contact_key)
+            .or_default()
+            .push_back(QueuedMessage { id, attempts: 0 });
+        true
+    }
+
+    /// Sends the queued messages for a contact in order, as long as it is connected.
+    ///
+    /// Only one flush runs per contact at a time, starting another one does nothing.
+    pub(super) async fn flush_outbox(
+        state: &StateSync,
+        contact_key: VerifyingKey,
+        event_channel: &Sender<NetworkEvent>,
+    ) -> CoreResult<()> {
+        if !state.write().await.flushing.insert(contact_key) {
+            return Ok(());
+        }
+
+        loop {
+            let next = state.write().await.next_queued(&contact_key);
+            let Some((remote, queued, msg)) = next else {
+                break;
+            };
+            let result = Self::write_message(state, remote, contact_key, &msg).await;
+
+            let (event, retry_in) = {
+                let mut state = state.write().await;
+                match result {
+                    Ok(()) => {
+                        state.pop_queued(&contact_key, queued.id);
+                        let event = state
+                            .update_delivery(&contact_key, queued.id, DeliveryState::Sent)
+                            .map(|_| {
+                                let mut msg = msg;
+                                msg.meta_mut().delivery.advance(DeliveryState::Sent);
+                                NetworkEvent::MessageSent(remote, contact_key, msg)
+                            });
+                        (event, None)
+                    }
+                    Err(e) if state.fail_queued(&contact_key, queued.id) => {
+                        warn!(
+                            "Giving up on sending message {} to {remote}: {e}",
+                            queued.id
+                        );
+                        let event = state
+                            .update_delivery(&contact_key, queued.id, DeliveryState::Failed)
+                            .map(|delivery| {
+                                NetworkEvent::MessageStateChanged(contact_key, queued.id, delivery)
+                            });
+                        (event, None)
+                    }
+                    Err(e) => {
+                        debug!("Could not send message {} to {remote}: {e}", queued.id);
+                        // the order must be kept, so the rest waits for the retry
+                        (
+                            None,
+                            Some(SEND_RETRY_BASE_DELAY * (1 << queued.attempts.min(16))),
+                        )
+                    }
+                }
+            };
+            Self::autosave(state).await;
+            if let Some(event) = event {
+                event_channel.send(event).await?;
+            }
+            if let Some(delay) = retry_in {
+                // the flush stays marked as running, the retry ends it if the contact is gone
+                tokio::time::sleep(delay).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the next message to send to a contact, with the address of its connection.
+    ///
+    /// Ends the flush if there is nothing to send or the contact can not be reached.
+    fn next_queued(
+        &mut self,
+        contact_key: &VerifyingKey,
+    ) -> Option<(SocketAddr, QueuedMessage, Message)> {
+        let next = loop {
+            let Some(queued) = self
+                .outbox
+                .get(contact_key)
+                .and_then(|q| q.front())
+                .copied()
+            else {
+                self.outbox.remove(contact_key);
+                break None;
+            };
+            // a direct connection is preferred over storing the message on a relay
+            let remote = self
+                .active_connections
+                .find_socket_addr_for_contact(contact_key)
+                .or_else(|| self.active_connections.find_relay());
+            let msg = self
+                .chats
+                .get(contact_key)
+                .and_then(|chat| chat.message(&queued.id))
+                .cloned();
+            match (remote, msg) {
+                (Some(remote), Some(msg)) => break Some((remote, queued, msg)),
+                (None, _) => break None,
+                // the chat was deleted meanwhile
+                (Some(_), None) => self.pop_queued(contact_key, queued.id),
+            }
+        };
+        if next.is_none() {
+            self.flushing.remove(contact_key);
+        }
+        next
+    }
+
+    /// Counts a failed attempt to send the message at the front of the outbox of a contact.
+    ///
+    /// Returns `true` if the message is given up, as it has failed [`MAX_SEND_ATTEMPTS`] times.
+    /// It is removed from the outbox then.
+    fn fail_queued(&mut self, contact_key: &VerifyingKey, id: MessageId) -> bool {
+        let Some(front) = self
+            .outbox
+            .get_mut(contact_key)
+            .and_then(|queue| queue.front_mut())
+            .filter(|queued| queued.id == id)
+        else {
+            return false;
+        };
+        front.attempts += 1;
+        if front.attempts < MAX_SEND_ATTEMPTS {
+            return false;
+        }
+        self.pop_queued(contact_key, id);
+        true
+    }
+
+    fn pop_queued(&mut self, contact_key: &VerifyingKey, id: MessageId) {
+        if let Some(queue) = self.outbox.get_mut(contact_key) {
+            if queue.front().is_some_and(|queued| queued.id == id) {
+                queue.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::identity::{Trust, UserIdentity};
+
+    /// A state with a user, a contact and a message of the user for the contact
+    fn state_with_message() -> (State, ContactIdentity, Message) {
+        let user = UserIdentity::build("user").unwrap();
+        let contact = ContactIdentity::build(
+            "contact",
+            UserIdentity::build("contact").unwrap().identity.public_key,
+            Trust::Unknown,
+            Utc::now(),
+            Utc::now(),
+        )
+        .unwrap();
+        let msg = Message::new_text("hello", Utc::now(), user.identity.public_key);
+        let state = State {
+            user_identity: Some(user),
+            ..Default::default()
+        };
+        (state, contact, msg)
+    }
+
+    fn queued_ids(state: &State, contact_key: &VerifyingKey) -> Vec<MessageId> {
+        state
+            .outbox
+            .get(contact_key)
+            .map(|queue| queue.iter().map(|queued| queued.id).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn messages_are_queued_once_in_order() {
+        let (mut state, contact, first) = state_with_message();
+        let contact_key = contact.identity.public_key;
+        let second = Message::new_text("again", Utc::now(), first.meta().author_key);
+
+        assert!(state.queue_message(contact.clone(), first.clone()));
+        assert!(state.queue_message(contact.clone(), second.clone()));
+        assert!(!state.queue_message(contact, first.clone()));
+        assert_eq!(queued_ids(&state, &contact_key), [first.id(), second.id()]);
+        assert_eq!(
+            state.chats[&contact_key]
+                .message(&first.id())
+                .unwrap()
+                .meta()
+                .delivery,
+            DeliveryState::Pending
+        );
+
+        // only the message at the front is taken off
+        state.pop_queued(&contact_key, second.id());
+        assert_eq!(queued_ids(&state, &contact_key), [first.id(), second.id()]);
+        state.pop_queued(&contact_key, first.id());
+        assert_eq!(queued_ids(&state, &contact_key), [second.id()]);
+    }
+
+    #[test]
+    fn flush_ends_without_a_connection() {
+        let (mut state, contact, msg) = state_with_message();
+        let contact_key = contact.identity.public_key;
+        state.queue_message(contact, msg.clone());
+        state.flushing.insert(contact_key);
+
+        assert!(state.next_queued(&contact_key).is_none());
+        assert!(!state.flushing.contains(&contact_key));
+        // the message waits for the next connection
+        assert_eq!(queued_ids(&state, &contact_key), [msg.id()]);
+    }
+
+    #[test]
+    fn message_is_given_up_after_too_many_attempts() {
+        let (mut state, contact, msg) = state_with_message();
+        let contact_key = contact.identity.public_key;
+        state.queue_message(contact, msg.clone());
+
+        for _ in 1..MAX_SEND_ATTEMPTS {
+            assert!(!state.fail_queued(&contact_key, msg.id()));
+        }
+        assert_eq!(
+            state.outbox[&contact_key][0].attempts,
+            MAX_SEND_ATTEMPTS - 1
+        );
+        assert!(state.fail_queued(&contact_key, msg.id()));
+        assert!(queued_ids(&state, &contact_key).is_empty());
+        assert!(!state.fail_queued(&contact_key, msg.id()));
+
+        assert_eq!(
+            state.update_delivery(&contact_key, msg.id(), DeliveryState::Failed),
+            Some(DeliveryState::Failed)
+        );
+    }
+}
@@ -0,0 +1,483 @@
+//! Decides which peers may connect, and stay connected once their identity is known
+
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+};
+
+use chrono::{DateTime, Utc};
+use ed25519_dalek::VerifyingKey;
+pub use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{CoreError, CoreResult},
+    identity::{ContactIdentity, Identity, Trust},
+    net::NetworkEvent,
+    state::State,
+};
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectionReason {
+    /// The user has marked the key of the peer as [`Trust::Rejected`]
+    RejectedContact,
+    /// The address of the peer is in [`ListenerAccess::denied_networks`]
+    DeniedAddress,
+    /// The listener only accepts known contacts, see [`AcceptPolicy::KnownContacts`]
+    NotAKnownContact,
+    /// The key of the peer is not in [`ListenerAccess::allowlist`]
+    NotAllowed,
+    /// A connection to a contact reached a peer with another key
+    UnexpectedPeer,
+}
+
+/// Which peers may connect to the listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum AcceptPolicy {
+    /// Anyone may connect
+    #[default]
+    Open,
+    /// Only peers the user already has a chat with, or has marked as [`Trust::Trusted`]
+    KnownContacts,
+    /// Only the keys in [`ListenerAccess::allowlist`]
+    Allowlist,
+}
+
+/// Access control for connections that peers open to the listener
+///
+/// Connections the user opens are not restricted by this.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct ListenerAccess {
+    pub policy: AcceptPolicy,
+    /// Keys that may connect with [`AcceptPolicy::Allowlist`]
+    pub allowlist: HashSet<VerifyingKey>,
+    /// Connections from these networks are closed before the handshake, whatever the policy
+    pub denied_networks: Vec<IpNet>,
+}
+
+/// The [`ListenerAccess`] of the state, by noise static key
+///
+/// The static key of a peer is known right after the noise handshake, before any identity is
+/// sent, so a peer that may not connect learns nothing about the user and cannot use up its
+/// one-time prekeys. The static key is the X25519 form of the identity key, see
+/// [`Identity::dh_public_key`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HandshakeAccess {
+    policy: AcceptPolicy,
+    /// Static keys that pass the policy
+    admitted: HashSet<[u8; 32]>,
+    /// Static keys of rejected contacts
+    rejected: HashSet<[u8; 32]>,
+    /// The only static key that may answer, when connecting to a known contact
+    expected: Option<[u8; 32]>,
+}
+
+impl HandshakeAccess {
+    /// Only lets the contact with the identity key `key` through, for connecting to it.
+    pub(crate) fn expecting(key: &VerifyingKey) -> Self {
+        Self {
+            expected: Some(key.to_montgomery().to_bytes()),
+            ..Default::default()
+        }
+    }
+
+    /// Checks the noise static key of a peer after the handshake.
+    pub(crate) fn check(&self, static_key: &[u8; 32]) -> Result<(), RejectionReason> {
+        if self
+            .expected
+            .is_some_and(|expected| expected != *static_key)
+        {
+            return Err(RejectionReason::UnexpectedPeer);
+        }
+        if self.rejected.contains(static_key) {
+            return Err(RejectionReason::RejectedContact);
+        }
+        match self.policy {
+            AcceptPolicy::Open => Ok(()),
+            _ if self.admitted.contains(static_key) => Ok(()),
+            AcceptPolicy::KnownContacts => Err(RejectionReason::NotAKnownContact),
+            AcceptPolicy::Allowlist => Err(RejectionReason::NotAllowed),
+        }
+    }
+}
+
+impl ListenerAccess {
+    /// Checks if connections from `addr` are refused before the handshake.
+    pub fn is_denied(&self, addr: IpAddr) -> bool {
+        // an IPv4 peer can reach a dual stack listener with an IPv4-mapped IPv6 address
+        let addr = addr.to_canonical();
+        self.denied_networks.iter().any(|net| net.contains(&addr))
+    }
+}
+
+/// Parses a network like `192.0.2.0/24` or a single address like `2001:db8::1`, for
+/// [`ListenerAccess::denied_networks`].
+///
+/// # Errors
+///
+/// Fails with [`CoreError::InvalidNetwork`] if the text is neither.
+pub fn parse_network(text: &str) -> CoreResult<IpNet> {
+    let text = text.trim();
+    text.parse::<IpNet>()
+        .or_else(|_| text.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| CoreError::InvalidNetwork(text.to_string()))
+}
+
+impl State {
+    /// Checks the identity a peer presented after the handshake, trusting keys on first use.
+    ///
+    /// Every peer is remembered in the known identities, and the stored identity of a known
+    /// contact is replaced with the one it presented, as only the owner of the key could have
+    /// sent it. Changes that the user should know about are returned as events:
+    ///
+    /// - [`NetworkEvent::KeyChanged`] if the username of a known contact comes with a new key
+    /// - [`NetworkEvent::UsernameChanged`] if a known key comes with a new username
+    ///
+    /// Peers that connected to the listener, so with `incoming` set, must also pass the
+    /// [`ListenerAccess`] in the settings.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the [`RejectionReason`] if the peer must be disconnected.
+    pub(crate) fn admit_peer(
+        &mut self,
+        remote: SocketAddr,
+        peer: &Identity,
+        incoming: bool,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<NetworkEvent>, RejectionReason> {
+        self.check_access(&peer.public_key, incoming)?;
+        let mut events = Vec::new();
+
+        let Some(contact) = self.known_identities.get_mut(&peer.public_key) else {
+            // a new key using the name of a known contact might be someone pretending to be them
+            events.extend(
+                self.known_identities
+                    .values()
+                    .filter(|contact| contact.identity.username == peer.username)
+                    .map(|contact| {
+                        NetworkEvent::KeyChanged(
+                            remote,
+                            peer.username.clone(),
+                            contact.identity.public_key,
+                            peer.public_key,
+                        )
+                    }),
+            );
+            self.known_identities.insert(
+                peer.public_key,
+                ContactIdentity {
+                    identity: peer.clone(),
+                    trust: Trust::Unknown,
+                    first_seen: now,
+                    last_seen: now,
+                    last_address: (!incoming).then_some(remote),
+                    pinned: false,
+                },
+            );
+            return Ok(events);
+        };
+
+        if contact.identity.username != peer.username {
+            events.push(NetworkEvent::UsernameChanged(
+                peer.public_key,
+                contact.identity.username.clone(),
+                peer.username.clone(),
+            ));
+        }
+        contact.identity = peer.clone();
+        contact.set_last_seen(now);
+        // the address of an incoming connection is not where the peer listens
+        if !incoming {
+            contact.last_address = Some(remote);
+        }
+
+        // the chat keeps its own copy of the contact
+        let contact = contact.clone();
+        for chats in [&mut self.chats, &mut self.requests] {
+            if let Some(chat) = chats.get_mut(&peer.public_key) {
+                *chat.contact_mut() = contact.clone();
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Takes the [`ListenerAccess`] in terms of noise static keys, for checking peers before
+    /// the identity exchange.
+    pub(crate) fn handshake_access(&self) -> HandshakeAccess {
+        let mut access = HandshakeAccess {
+            policy: self.settings.listener_access.policy,
+            ..Default::default()
+        };
+        // only keys the state knows of can pass, and going through `check_access` keeps both
+        // checks in agreement
+        let keys = self
+            .known_identities
+            .keys()
+            .chain(self.chats.keys())
+            .chain(&self.settings.listener_access.allowlist);
+        for key in keys {
+            let static_key = key.to_montgomery().to_bytes();
+            match self.check_access(key, true) {
+                Ok(()) => {
+                    access.admitted.insert(static_key);
+                }
+                Err(RejectionReason::RejectedContact) => {
+                    access.rejected.insert(static_key);
+                }
+                Err(_) => (),
+            }
+        }
+        access
+    }
+
+    fn check_access(&self, key: &VerifyingKey, incoming: bool) -> Result<(), RejectionReason> {
+        let trust = self.known_identities.get(key).map(|contact| contact.trust);
+        if trust == Some(Trust::Rejected) {
+            return Err(RejectionReason::RejectedContact);
+        }
+        if !incoming {
+            return Ok(());
+        }
+
+        let access = &self.settings.listener_access;
+        match access.policy {
+            AcceptPolicy::Open => Ok(()),
+            AcceptPolicy::KnownContacts
+                if self.chats.contains_key(key) || trust == Some(Trust::Trusted) =>
+            {
+                Ok(())
+            }
+            AcceptPolicy::KnownContacts => Err(RejectionReason::NotAKnownContact),
+            AcceptPolicy::Allowlist if access.allowlist.contains(key) => Ok(()),
+            AcceptPolicy::Allowlist => Err(RejectionReason::NotAllowed),
+        }
+    }
+}
+
+impl Display for AcceptPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Open => "Everyone",
+                Self::KnownContacts => "Known contacts",
+                Self::Allowlist => "Allowlist",
+            }
+        )
+    }
+}
+
+impl Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::RejectedContact => "the contact was rejected",
+                Self::DeniedAddress => "the address is denied",
+                Self::NotAKnownContact => "only known contacts may connect",
+                Self::NotAllowed => "the key is not on the allowlist",
+                Self::UnexpectedPeer => "the peer is not the expected contact",
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::UserIdentity;
+
+    const REMOTE: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 1);
+
+    fn peer(username: &str) -> Identity {
+        UserIdentity::build(username).unwrap().identity
+    }
+
+    #[test]
+    fn new_peer_is_trusted_on_first_use() {
+        let mut state = State::default();
+        let alice = peer("alice");
+        let events = state.admit_peer(REMOTE, &alice, false, Utc::now()).unwrap();
+        assert!(events.is_empty());
+
+        let contact = &state.known_identities[&alice.public_key];
+        assert_eq!(contact.identity, alice);
+        assert_eq!(contact.trust, Trust::Unknown);
+        assert!(
+            state
+                .admit_peer(REMOTE, &alice, false, Utc::now())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn known_username_with_a_new_key_is_reported() {
+        let mut state = State::default();
+        let alice = peer("alice");
+        state.admit_peer(REMOTE, &alice, false, Utc::now()).unwrap();
+
+        let impostor = peer("alice");
+        let events = state
+            .admit_peer(REMOTE, &impostor, false, Utc::now())
+            .unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [NetworkEvent::KeyChanged(REMOTE, username, old, new)]
+                if username == "alice" && *old == alice.public_key && *new == impostor.public_key
+        ));
+        // both keys are known now, and neither replaces the other
+        assert_eq!(state.known_identities.len(), 2);
+    }
+
+    #[test]
+    fn known_key_with_a_new_username_is_reported() {
+        let mut state = State::default();
+        let alice = peer("alice");
+        state.admit_peer(REMOTE, &alice, false, Utc::now()).unwrap();
+
+        let mut renamed = alice.clone();
+        renamed.username = "alicia".to_string();
+        let events = state
+            .admit_peer(REMOTE, &renamed, false, Utc::now())
+            .unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [NetworkEvent::UsernameChanged(key, old, new)]
+                if *key == alice.public_key && old == "alice" && new == "alicia"
+        ));
+        assert_eq!(
+            state.known_identities[&alice.public_key].identity.username,
+            "alicia"
+        );
+    }
+
+    #[test]
+    fn rejected_contact_is_refused() {
+        let mut state = State::default();
+        let alice = peer("alice");
+        state.admit_peer(REMOTE, &alice, false, Utc::now()).unwrap();
+        state
+            .known_identities
+            .get_mut(&alice.public_key)
+            .unwrap()
+            .trust = Trust::Rejected;
+
+        let mut renamed = alice.clone();
+        renamed.username = "alicia".to_string();
+        assert_eq!(
+            state
+                .admit_peer(REMOTE, &renamed, false, Utc::now())
+                .unwrap_err(),
+            RejectionReason::RejectedContact
+        );
+        // nothing a rejected contact sends is stored
+        assert_eq!(
+            state.known_identities[&alice.public_key].identity.username,
+            "alice"
+        );
+    }
+
+    #[test]
+    fn listener_access_follows_the_policy() {
+        let mut state = State::default();
+        let stranger = peer("stranger");
+        let friend = peer("friend");
+        let chatting = peer("chatting");
+        state
+            .admit_peer(REMOTE, &friend, false, Utc::now())
+            .unwrap();
+        state.set_trust(&friend.public_key, Trust::Trusted).unwrap();
+        state
+            .admit_peer(REMOTE, &chatting, false, Utc::now())
+            .unwrap();
+        let contact = state.known_identities[&chatting.public_key].clone();
+        state
+            .chats
+            .insert(chatting.public_key, crate::chat::Chat::new(contact));
+
+        let check = |state: &State, peer: &Identity| state.check_access(&peer.public_key, true);
+
+        assert!(check(&state, &stranger).is_ok());
+
+        state.settings.listener_access.policy = AcceptPolicy::KnownContacts;
+        assert_eq!(
+            check(&state, &stranger),
+            Err(RejectionReason::NotAKnownContact)
+        );
+        assert!(check(&state, &friend).is_ok());
+        assert!(check(&state, &chatting).is_ok());
+
+        state.settings.listener_access.policy = AcceptPolicy::Allowlist;
+        state
+            .settings
+            .listener_access
+            .allowlist
+            .insert(stranger.public_key);
+        assert!(check(&state, &stranger).is_ok());
+        assert_eq!(check(&state, &friend), Err(RejectionReason::NotAllowed));
+
+        // peers we connect to ourselves are not restricted
+        assert!(state.check_access(&friend.public_key, false).is_ok());
+    }
+
+    #[test]
+    fn only_the_expected_contact_passes() {
+        let friend = peer("friend");
+        let stranger = peer("stranger");
+        let access = HandshakeAccess::expecting(&friend.public_key);
+
+        assert!(access.check(&friend.dh_public_key()).is_ok());
+        assert_eq!(
+            access.check(&stranger.dh_public_key()),
+            Err(RejectionReason::UnexpectedPeer)
+        );
+    }
+
+    #[test]
+    fn rejected_contact_is_refused_in_both_directions() {
+        let mut state = State::default();
+        let alice = peer("alice");
+        state.admit_peer(REMOTE, &alice, false, Utc::now()).unwrap();
+        state.set_trust(&alice.public_key, Trust::Rejected).unwrap();
+        state
+            .settings
+            .listener_access
+            .allowlist
+            .insert(alice.public_key);
+        state.settings.listener_access.policy = AcceptPolicy::Allowlist;
+        for incoming in [false, true] {
+            assert_eq!(
+                state.check_access(&alice.public_key, incoming),
+                Err(RejectionReason::RejectedContact)
+            );
+        }
+    }
+
+    #[test]
+    fn denied_networks_match_addresses() {
+        let access = ListenerAccess {
+            denied_networks: vec![
+                parse_network("192.0.2.0/24").unwrap(),
+                parse_network(" 2001:db8::1 ").unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert!(access.is_denied("192.0.2.77".parse().unwrap()));
+        assert!(access.is_denied("::ffff:192.0.2.77".parse().unwrap()));
+        assert!(access.is_denied("2001:db8::1".parse().unwrap()));
+        assert!(!access.is_denied("2001:db8::2".parse().unwrap()));
+        assert!(!access.is_denied("198.51.100.1".parse().unwrap()));
+        assert!(matches!(
+            parse_network("192.0.2.0/33"),
+            Err(CoreError::InvalidNetwork(_))
+        ));
+    }
+}
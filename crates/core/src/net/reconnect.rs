@@ -0,0 +1,287 @@
+//! Reconnecting to contacts after their connection dropped
+//!
+//! A contact is reconnected to if it is [pinned](crate::identity::ContactIdentity::pinned), or
+//! if there are messages for it in the [outbox](crate::net::outbox). Connections go to the
+//! [last address](crate::identity::ContactIdentity::last_address) of the contact, and failed
+//! attempts are retried with a jittered exponential backoff.
+
+use std::{fmt::Display, net::SocketAddr};
+
+use async_channel::{Receiver, Sender};
+use chrono::{DateTime, TimeDelta, Utc};
+use ed25519_dalek::VerifyingKey;
+use log::{debug, info};
+use rand::Rng;
+
+use crate::{
+    error::{CoreError, CoreResult},
+    identity::Trust,
+    net::{NetworkCommand, NetworkEvent},
+    state::{State, StateSync},
+};
+
+/// How often the reconnect job looks for contacts to reconnect to
+const RECONNECT_CHECK_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+/// Delay after the first failed attempt, doubled for every further one
+const RECONNECT_BASE_DELAY: TimeDelta = TimeDelta::seconds(2);
+/// The delay does not grow beyond this
+const RECONNECT_MAX_DELAY: TimeDelta = TimeDelta::minutes(10);
+
+/// Where reconnecting to a contact is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconnectState {
+    /// Trying to connect to the address
+    Connecting(SocketAddr),
+    /// The last attempt failed, the next one is made at the given time
+    Backoff(DateTime<Utc>),
+    /// The contact is connected again
+    Connected(SocketAddr),
+}
+
+/// Failed attempts to reconnect to a contact
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Backoff {
+    attempts: u32,
+    next_attempt: DateTime<Utc>,
+    connecting: bool,
+}
+
+impl Backoff {
+    /// Counts a failed attempt and picks the time of the next one.
+    fn fail(&mut self, now: DateTime<Utc>) -> DateTime<Utc> {
+        self.attempts = self.attempts.saturating_add(1);
+        self.connecting = false;
+        let delay = RECONNECT_BASE_DELAY
+            .checked_mul(1 << self.attempts.min(16))
+            .map_or(RECONNECT_MAX_DELAY, |delay| delay.min(RECONNECT_MAX_DELAY));
+        // the jitter keeps contacts from all reconnecting to each other at the same time
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+        #[allow(clippy::cast_possible_truncation)] // the delay is at most a few minutes
+        let delay = TimeDelta::milliseconds((delay.num_milliseconds() as f64 * jitter) as i64);
+        self.next_attempt = now + delay;
+        self.next_attempt
+    }
+}
+
+impl State {
+    /// Sets whether a connection to a contact should be kept open.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::UnknownContact`] if there is no contact with that key.
+    pub fn set_pinned(&mut self, key: &VerifyingKey, pinned: bool) -> CoreResult<()> {
+        self.known_identities
+            .get_mut(key)
+            .ok_or(CoreError::UnknownContact(*key))?
+            .pinned = pinned;
+        for chats in [&mut self.chats, &mut self.requests] {
+            if let Some(chat) = chats.get_mut(key) {
+                chat.contact_mut().pinned = pinned;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks if the contact should be connected, because it is pinned or there are messages
+    /// for it in the outbox.
+    pub fn wants_connection(&self, key: &VerifyingKey) -> bool {
+        let Some(contact) = self.known_identities.get(key) else {
+            return false;
+        };
+        if contact.trust == Trust::Rejected {
+            return false;
+        }
+        contact.pinned || self.outbox.get(key).is_some_and(|queue| !queue.is_empty())
+    }
+
+    /// Reconnects to contacts whose connection dropped, see the [module documentation](self).
+    pub(crate) async fn job_reconnect(
+        state: &StateSync,
+        _command_channel: &mut Receiver<NetworkCommand>,
+        event_channel: &mut Sender<NetworkEvent>,
+    ) -> CoreResult<()> {
+        tokio::time::sleep(RECONNECT_CHECK_INTERVAL).await;
+
+        let due = state.write().await.due_reconnects(Utc::now());
+        for (key, remote) in due {
+            let state = state.clone();
+            let event_channel = event_channel.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::reconnect(&state, key, remote, &event_channel).await {
+                    log::error!("Error while reconnecting to {remote}: {e}");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Finds the contacts that should be reconnected to now, and marks them as connecting.
+    fn due_reconnects(&mut self, now: DateTime<Utc>) -> Vec<(VerifyingKey, SocketAddr)> {
+        // contacts that are connected again start over
+        let connections = &self.active_connections;
+        self.reconnects.retain(|key, backoff| {
+            backoff.connecting || connections.find_socket_addr_for_contact(key).is_none()
+        });
+
+        let candidates: Vec<(VerifyingKey, SocketAddr)> = self
+            .known_identities
+            .values()
+            .filter_map(|contact| Some((contact.identity.public_key, contact.last_address?)))
+            .filter(|(key, _)| {
+                self.active_connections
+                    .find_socket_addr_for_contact(key)
+                    .is_none()
+                    && self.wants_connection(key)
+            })
+            .collect();
+
+        candidates
+            .into_iter()
+            .filter(|(key, _)| {
+                let backoff = self.reconnects.entry(*key).or_default();
+                if backoff.connecting || backoff.next_attempt > now {
+                    return false;
+                }
+                backoff.connecting = true;
+                true
+            })
+            .collect()
+    }
+
+    async fn reconnect(
+        state: &StateSync,
+        key: VerifyingKey,
+        remote: SocketAddr,
+        event_channel: &Sender<NetworkEvent>,
+    ) -> CoreResult<()> {
+        info!("Reconnecting to {remote}");
+        event_channel
+            .send(NetworkEvent::ReconnectStateChanged(
+                key,
+                ReconnectState::Connecting(remote),
+            ))
+            .await?;
+
+        // someone else might have taken over the address, and is refused during the handshake
+        let result = Self::connect_to(state, remote, Some(&key), event_channel).await;
+        let connected = matches!(result, Ok(NetworkEvent::ConnectionEstablished(..)));
+        let reconnect_state = {
+            let mut state = state.write().await;
+            if connected {
+                state.reconnects.remove(&key);
+                ReconnectState::Connected(remote)
+            } else {
+                let until = state.reconnects.entry(key).or_default().fail(Utc::now());
+                ReconnectState::Backoff(until)
+            }
+        };
+        match result {
+            Ok(event) => event_channel.send(event).await?,
+            Err(e) => debug!("Could not reconnect to {remote}: {e}"),
+        }
+        event_channel
+            .send(NetworkEvent::ReconnectStateChanged(key, reconnect_state))
+            .await?;
+        Ok(())
+    }
+}
+
+impl Display for ReconnectState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connecting(remote) => write!(f, "connecting to {remote}"),
+            Self::Backoff(until) => write!(f, "retrying at {}", until.format("%H:%M:%S")),
+            Self::Connected(remote) => write!(f, "connected to {remote}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{Identity, UserIdentity};
+
+    fn remote() -> SocketAddr {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    /// Adds a contact that was last reached at [`remote`]
+    fn add_contact(state: &mut State, username: &str, pinned: bool) -> Identity {
+        let peer = UserIdentity::build(username).unwrap().identity;
+        state
+            .admit_peer(remote(), &peer, false, Utc::now())
+            .unwrap();
+        let contact = state.known_identities.get_mut(&peer.public_key).unwrap();
+        contact.last_address = Some(remote());
+        contact.pinned = pinned;
+        peer
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_maximum() {
+        let now = Utc::now();
+        let mut backoff = Backoff::default();
+        for attempt in 1..=40 {
+            let delay = backoff.fail(now) - now;
+            let full = RECONNECT_BASE_DELAY
+                .checked_mul(1 << attempt.min(16))
+                .unwrap()
+                .min(RECONNECT_MAX_DELAY);
+            // the jitter takes off up to half of the delay
+            assert!(delay <= full, "attempt {attempt}: {delay} > {full}");
+            assert!(delay >= full / 2, "attempt {attempt}: {delay} < {full}/2");
+            assert_eq!(backoff.next_attempt, now + delay);
+            assert!(!backoff.connecting);
+        }
+        assert_eq!(backoff.attempts, 40);
+        assert!(backoff.fail(now) - now <= RECONNECT_MAX_DELAY);
+    }
+
+    #[test]
+    fn only_wanted_contacts_are_reconnected() {
+        let mut state = State {
+            user_identity: Some(UserIdentity::build("user").unwrap()),
+            ..Default::default()
+        };
+        let pinned = add_contact(&mut state, "pinned", true);
+        let idle = add_contact(&mut state, "idle", false);
+        let rejected = add_contact(&mut state, "rejected", true);
+        state
+            .set_trust(&rejected.public_key, Trust::Rejected)
+            .unwrap();
+
+        assert!(state.wants_connection(&pinned.public_key));
+        assert!(!state.wants_connection(&idle.public_key));
+        assert!(!state.wants_connection(&rejected.public_key));
+        assert_eq!(
+            state.due_reconnects(Utc::now()),
+            vec![(pinned.public_key, remote())]
+        );
+    }
+
+    #[test]
+    fn reconnects_wait_for_the_backoff() {
+        let mut state = State::default();
+        let peer = add_contact(&mut state, "pinned", true);
+        let now = Utc::now();
+        assert_eq!(state.due_reconnects(now), vec![(peer.public_key, remote())]);
+        // an attempt is under way
+        assert!(state.due_reconnects(now).is_empty());
+
+        let next_attempt = state
+            .reconnects
+            .get_mut(&peer.public_key)
+            .unwrap()
+            .fail(now);
+        assert!(state.due_reconnects(now).is_empty());
+        assert!(
+            state
+                .due_reconnects(next_attempt - TimeDelta::milliseconds(1))
+                .is_empty()
+        );
+        assert_eq!(
+            state.due_reconnects(next_attempt),
+            vec![(peer.public_key, remote())]
+        );
+    }
+}
@@ -0,0 +1,428 @@
+//! Reaching contacts through a relay server
+//!
+//! While the user is connected to a relay, messages for contacts without a direct connection
+//! are stored on the relay. Messages the relay holds for the user are retrieved right after
+//! registering, and the relay passes on new ones as long as the connection is open. They are
+//! handled like messages from a direct connection, and confirmed to the relay, which passes the
+//! confirmation on to the sender.
+//!
+//! Messages are stored on the relay the user is connected to, so contacts need to use the same
+//! relay to get them.
+
+use std::{io::ErrorKind, net::SocketAddr};
+
+use async_channel::Sender;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::VerifyingKey;
+use log::{debug, info, trace, warn};
+
+use crate::{
+    chat::messages::{DeliveryState, MessageId},
+    crypto::ratchet::RatchetMessage,
+    error::{CoreError, CoreResult},
+    identity::{Identity, Trust, format_key},
+    net::{
+        NetworkEvent,
+        connection::{Connection, ConnectionReader, ConnectionWriter, FrameBody, Timeouts},
+        relay::{DeliveryConfirmation, RelayPacket, RetrieveMessages, StoreMessage, StoredMessage},
+    },
+    state::{ConnectionData, State, StateSync},
+};
+
+/// Length of the key prefix that stands in for the username of an unknown sender
+const UNKNOWN_SENDER_NAME_LEN: usize = 16;
+
+impl State {
+    /// Connects to a relay server, and retrieves the messages it holds for the user.
+    pub(in crate::net) async fn connect_relay(
+        state: &StateSync,
+        remote: SocketAddr,
+        event_channel: &Sender<NetworkEvent>,
+    ) -> CoreResult<NetworkEvent> {
+        let user_identity = Self::user_identity(state).await?;
+        let timeouts = state.read().await.settings.timeouts;
+        let (connection, reader) =
+            Connection::connect_relay(remote, &user_identity, &timeouts).await?;
+        let relay_identity = connection.peer_identity().await.clone();
+        let writer = connection.writer();
+
+        let (retrieve, waiting) = {
+            let mut state = state.write().await;
+            if state.active_connections.contains_key(&remote) {
+                drop(state);
+                warn!("Duplicated connection, closing second connection...");
+                connection.disconnect().await?;
+                return Ok(NetworkEvent::ConnectionAborted(remote));
+            }
+            state.active_connections.insert(
+                remote,
+                ConnectionData {
+                    conn: connection,
+                    iden: relay_identity.clone(),
+                },
+            );
+            let retrieve = state.sign_retrieve(remote, None)?;
+            // contacts that are not connected can be reached through the relay now
+            let waiting: Vec<VerifyingKey> = state
+                .outbox
+                .keys()
+                .filter(|key| {
+                    state
+                        .active_connections
+                        .find_socket_addr_for_contact(key)
+                        .is_none()
+                })
+                .copied()
+                .collect();
+            (retrieve, waiting)
+        };
+        info!("Registered with relay {remote}");
+
+        let state_c = state.clone();
+        let evt_c = event_channel.clone();
+        let reader_writer = writer.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                Self::job_relay_reader(state_c, remote, reader, reader_writer, timeouts, evt_c)
+                    .await
+            {
+                log::error!("Error while reading from relay {remote}: {e}")
+            }
+        });
+
+        writer
+            .send(&FrameBody::Relay(RelayPacket::Retrieve(retrieve)))
+            .await?;
+        for contact_key in waiting {
+            Self::spawn_flush_outbox(state, contact_key, event_channel);
+        }
+
+        Ok(NetworkEvent::ConnectionEstablished(
+            remote,
+            relay_identity.public_key,
+        ))
+    }
+
+    /// Reads from the connection to a relay until it is closed.
+    async fn job_relay_reader(
+        state: StateSync,
+        remote: SocketAddr,
+        mut reader: ConnectionReader,
+        writer: ConnectionWriter,
+        timeouts: Timeouts,
+        event_channel: Sender<NetworkEvent>,
+    ) -> CoreResult<()> {
+        loop {
+            let packet = match reader.recv_keepalive(&writer, &timeouts).await {
+                Ok(packet) => packet,
+                Err(CoreError::IO(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    info!("Relay {remote} has closed the connection");
+                    break;
+                }
+                Err(e) => {
+                    warn!("Could not read from the connection with relay {remote}: {e}");
+                    writer.send_detached(FrameBody::Error(e.to_string()));
+                    break;
+                }
+            };
+
+            match packet.body {
+                FrameBody::Relay(RelayPacket::Batch(batch)) => {
+                    debug!(
+                        "Relay {remote} handed over {} messages",
+                        batch.messages.len()
+                    );
+                    let last = batch.messages.last().map(|stored| stored.timestamp);
+                    for stored in batch.messages {
+                        Self::receive_stored(&state, remote, &writer, stored, &event_channel)
+                            .await?;
+                    }
+                    if batch.has_more {
+                        let retrieve = state.read().await.sign_retrieve(remote, last)?;
+                        writer.send_detached(FrameBody::Relay(RelayPacket::Retrieve(retrieve)));
+                    }
+                }
+                FrameBody::Relay(RelayPacket::DeliveryConfirmation(confirmation)) => {
+                    if let Err(e) = state.read().await.check_confirmation(remote, &confirmation) {
+                        warn!("Relay {remote} passed on a bad delivery confirmation: {e}");
+                        continue;
+                    }
+                    let changed = state.write().await.update_delivery(
+                        &confirmation.delivered_to,
+                        confirmation.message_id,
+                        DeliveryState::Delivered,
+                    );
+                    if let Some(delivery) = changed {
+                        Self::autosave(&state).await;
+                        event_channel
+                            .send(NetworkEvent::MessageStateChanged(
+                                confirmation.delivered_to,
+                                confirmation.message_id,
+                                delivery,
+                            ))
+                            .await?;
+                    }
+                }
+                FrameBody::Relay(RelayPacket::StoreResponse(response)) => {
+                    if response.success {
+                        debug!("Relay {remote} stored message {}", response.message_id);
+                        continue;
+                    }
+                    warn!(
+                        "Relay {remote} did not store message {}: {}",
+                        response.message_id,
+                        response.reason.as_deref().unwrap_or("no reason given")
+                    );
+                    let failed = state.write().await.fail_relayed(response.message_id);
+                    if let Some(contact_key) = failed {
+                        Self::autosave(&state).await;
+                        event_channel
+                            .send(NetworkEvent::MessageStateChanged(
+                                contact_key,
+                                response.message_id,
+                                DeliveryState::Failed,
+                            ))
+                            .await?;
+                    }
+                }
+                FrameBody::Ack(id) => debug!("Relay {remote} acknowledged packet {id}"),
+                FrameBody::Ping(value) => writer.send_detached(FrameBody::Pong(value)),
+                FrameBody::Pong(value) => trace!("Relay {remote} answered ping {value}"),
+                FrameBody::Goodbye => {
+                    info!("Relay {remote} is closing the connection");
+                    break;
+                }
+                FrameBody::Error(reason) => {
+                    warn!("Relay {remote} is closing the connection because of an error: {reason}");
+                    break;
+                }
+                other => {
+                    warn!(
+                        "Relay {remote} sent an unexpected {} packet, closing the connection",
+                        other.kind()
+                    );
+                    writer.send_detached(FrameBody::Error(format!(
+                        "unexpected {} packet",
+                        other.kind()
+                    )));
+                    break;
+                }
+            }
+        }
+
+        // if the connection is not active anymore, it was closed on purpose by us
+        let removed = state.write().await.active_connections.remove(&remote);
+        if let Some(relay) = removed {
+            event_channel
+                .send(NetworkEvent::ConnectionLost(remote, relay.iden.public_key))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles a message the relay held for the user, and confirms it to the relay.
+    ///
+    /// Messages from unknown keys become contact requests. Messages that are dropped, because
+    /// they come from a rejected contact or cannot be read, are confirmed as well, so that the
+    /// relay does not keep them.
+    async fn receive_stored(
+        state: &StateSync,
+        remote: SocketAddr,
+        writer: &ConnectionWriter,
+        stored: StoredMessage,
+        event_channel: &Sender<NetworkEvent>,
+    ) -> CoreResult<()> {
+        let event = Self::read_stored(state, remote, &stored).await;
+        // a repeated message might mean that our confirmation was lost, so always send it
+        match state
+            .read()
+            .await
+            .sign_confirmation(remote, &stored, Utc::now())
+        {
+            Ok(confirmation) => writer.send_detached(FrameBody::Relay(
+                RelayPacket::DeliveryConfirmation(confirmation),
+            )),
+            Err(e) => warn!(
+                "Could not sign the confirmation for message {}: {e}",
+                stored.message_id
+            ),
+        }
+        if let Some(event) = event {
+            event_channel.send(event).await?;
+        }
+        Ok(())
+    }
+
+    /// Decrypts and stores a message the relay held for the user.
+    ///
+    /// Returns the event for the message, or [`None`] if it was dropped.
+    async fn read_stored(
+        state: &StateSync,
+        remote: SocketAddr,
+        stored: &StoredMessage,
+    ) -> Option<NetworkEvent> {
+        let known = state
+            .read()
+            .await
+            .known_identities
+            .get(&stored.sender)
+            .map(|contact| (contact.trust, contact.identity.clone()));
+        let peer_identity = match known {
+            Some((Trust::Rejected, _)) => {
+                info!(
+                    "Relay {remote} holds message {} from a rejected contact, dropping it",
+                    stored.message_id
+                );
+                return None;
+            }
+            Some((_, identity)) => identity,
+            None => {
+                info!(
+                    "Relay {remote} holds message {} from the unknown key {}, it becomes a \
+                     contact request",
+                    stored.message_id,
+                    format_key(&stored.sender)
+                );
+                Self::unknown_sender(stored.sender)
+            }
+        };
+        let encrypted: RatchetMessage = match rmp_serde::from_slice(&stored.encrypted_blob) {
+            Ok(encrypted) => encrypted,
+            Err(e) => {
+                warn!(
+                    "Relay {remote} holds a broken message {}, dropping it: {e}",
+                    stored.message_id
+                );
+                return None;
+            }
+        };
+
+        let (_, event) = Self::receive_encrypted(state, remote, &peer_identity, &encrypted).await?;
+        if event.is_none() {
+            debug!(
+                "Relay {remote} handed over message {} again, dropping it",
+                stored.message_id
+            );
+        }
+        event
+    }
+
+    /// The identity of a sender that the user has never been connected to.
+    ///
+    /// Only the key is known, so the start of it stands in for the username. The peer presents
+    /// its real identity once it connects directly, which replaces this one.
+    fn unknown_sender(key: VerifyingKey) -> Identity {
+        let mut name = format_key(&key);
+        name.truncate(UNKNOWN_SENDER_NAME_LEN);
+        Identity {
+            username: name,
+            public_key: key,
+            flags: Default::default(),
+            extensions: Default::default(),
+        }
+    }
+
+    /// Signs an encrypted message for a contact, for storing it on a relay.
+    pub(in crate::net) fn sign_store(
+        &self,
+        recipient: VerifyingKey,
+        message_id: MessageId,
+        encrypted: &RatchetMessage,
+    ) -> CoreResult<StoreMessage> {
+        let user = self
+            .user_identity
+            .as_ref()
+            .ok_or(CoreError::NoUserIdentity)?;
+        Ok(StoreMessage::sign(
+            user,
+            recipient,
+            message_id,
+            rmp_serde::to_vec(encrypted)?,
+        ))
+    }
+
+    /// Signs a request for the messages that the relay at `remote` holds for the user.
+    fn sign_retrieve(
+        &self,
+        remote: SocketAddr,
+        since: Option<DateTime<Utc>>,
+    ) -> CoreResult<RetrieveMessages> {
+        let user = self
+            .user_identity
+            .as_ref()
+            .ok_or(CoreError::NoUserIdentity)?;
+        let relay = self
+            .active_connections
+            .get(&remote)
+            .and_then(|data| data.conn.relay())
+            .ok_or(CoreError::NoConnection(remote))?;
+        Ok(RetrieveMessages::sign(
+            user,
+            relay.relay_key(),
+            relay.challenge(),
+            since,
+        ))
+    }
+
+    /// Signs the confirmation for a message that the relay at `remote` handed out.
+    fn sign_confirmation(
+        &self,
+        remote: SocketAddr,
+        stored: &StoredMessage,
+        now: DateTime<Utc>,
+    ) -> CoreResult<DeliveryConfirmation> {
+        let user = self
+            .user_identity
+            .as_ref()
+            .ok_or(CoreError::NoUserIdentity)?;
+        Ok(DeliveryConfirmation::sign(
+            user,
+            self.relay_key(remote)?,
+            stored.sender,
+            stored.message_id,
+            now,
+        ))
+    }
+
+    /// Checks that a confirmation the relay at `remote` passed on is for a message of the user,
+    /// stored with that relay.
+    fn check_confirmation(
+        &self,
+        remote: SocketAddr,
+        confirmation: &DeliveryConfirmation,
+    ) -> CoreResult<()> {
+        let user = self
+            .user_identity
+            .as_ref()
+            .ok_or(CoreError::NoUserIdentity)?;
+        if confirmation.sender != user.identity.public_key {
+            return Err(CoreError::InvalidSignature("delivery confirmation"));
+        }
+        confirmation.verify(self.relay_key(remote)?)
+    }
+
+    /// The key of the relay at `remote`.
+    fn relay_key(&self, remote: SocketAddr) -> CoreResult<&VerifyingKey> {
+        self.active_connections
+            .get(&remote)
+            .and_then(|data| data.conn.relay())
+            .map(|relay| relay.relay_key())
+            .ok_or(CoreError::NoConnection(remote))
+    }
+
+    /// Marks one of our messages as failed after the relay refused to store it.
+    ///
+    /// Returns the contact the message was for, if the message was still only sent.
+    fn fail_relayed(&mut self, message_id: MessageId) -> Option<VerifyingKey> {
+        let user_key = self.user_identity.as_ref()?.identity.public_key;
+        self.chats.iter_mut().find_map(|(contact_key, chat)| {
+            let msg = chat.message_mut(&message_id)?;
+            if msg.meta().author_key != user_key || msg.meta().delivery != DeliveryState::Sent {
+                return None;
+            }
+            msg.meta_mut().delivery = DeliveryState::Failed;
+            Some(*contact_key)
+        })
+    }
+}
@@ -0,0 +1,437 @@
+//! The relay protocol, see section 8 of the specification
+//!
+//! Relays store end-to-end encrypted messages for recipients that are not online, and hand them
+//! out once the recipient asks for them. A relay is connected to like any peer, with the noise
+//! handshake and the identity exchange. After that, the relay sends a [`Challenge`], which the
+//! client signs in its [`RelayRegister`] to prove that it holds the key of its identity.
+//!
+//! All packets of this protocol are carried as [`RelayPacket`] in
+//! [`FrameBody::Relay`](crate::net::connection::FrameBody::Relay).
+//!
+//! Clients connect to a relay with [`NetworkCommand::ConnectRelay`](crate::net::NetworkCommand),
+//! see the [`client`] module for how it is used.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chat::messages::MessageId,
+    error::{CoreError, CoreResult},
+    identity::UserIdentity,
+};
+
+pub mod client;
+
+/// Prefix of the signed data of a [`RelayRegister`]
+const REGISTER_SIGNATURE_CONTEXT: &[u8] = b"SREMP relay register v1";
+/// Prefix of the signed data of a [`RetrieveMessages`]
+const RETRIEVE_SIGNATURE_CONTEXT: &[u8] = b"SREMP relay retrieve v1";
+/// Prefix of the signed data of a [`StoreMessage`]
+const STORE_SIGNATURE_CONTEXT: &[u8] = b"SREMP relay store v1";
+/// Prefix of the signed data of a [`DeliveryConfirmation`]
+const DELIVERY_SIGNATURE_CONTEXT: &[u8] = b"SREMP relay delivery v1";
+
+/// Random value the relay asks a client to sign, so that old signatures can not be replayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Challenge(#[serde(with = "serde_bytes")] [u8; 32]);
+
+/// What a relay or a client can handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayCapabilities {
+    /// Largest encrypted blob that can be stored, in bytes
+    pub max_message_size: u32,
+    /// How long a message is kept before it is dropped, even if it was not retrieved
+    pub max_storage_duration: Duration,
+}
+
+/// Registers a client with the relay, signed with its identity key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayRegister {
+    pub identity: VerifyingKey,
+    auth_signature: Signature,
+    /// What the client wants, the relay answers with what it will actually do
+    pub capabilities: RelayCapabilities,
+}
+
+/// Asks the relay to keep an encrypted message for a recipient
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreMessage {
+    pub recipient: VerifyingKey,
+    #[serde(with = "serde_bytes")]
+    pub encrypted_blob: Vec<u8>,
+    sender_signature: Signature,
+    pub message_id: MessageId,
+}
+
+/// The answer of the relay to a [`StoreMessage`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreResponse {
+    pub success: bool,
+    pub stored_at: DateTime<Utc>,
+    pub message_id: MessageId,
+    /// Why the message was not stored
+    pub reason: Option<String>,
+}
+
+/// Asks the relay for the messages stored for the client, signed with its identity key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetrieveMessages {
+    pub identity: VerifyingKey,
+    auth_signature: Signature,
+    /// Only messages stored after this time are wanted
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// Messages the relay hands out to their recipient, oldest first
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageBatch {
+    pub messages: Vec<StoredMessage>,
+    /// There are more messages than fit into the batch, they can be retrieved with
+    /// [`RetrieveMessages::since`] set to the time of the last message
+    pub has_more: bool,
+}
+
+/// An encrypted message held by the relay
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub message_id: MessageId,
+    pub sender: VerifyingKey,
+    /// When the relay stored the message
+    pub timestamp: DateTime<Utc>,
+    #[serde(with = "serde_bytes")]
+    pub encrypted_blob: Vec<u8>,
+}
+
+/// Confirms that the recipient has the message, so the relay can drop it and tell the sender
+///
+/// The signature covers the relay and the sender of the message, so that a confirmation can
+/// not be passed on to another relay or be used for a message with the same id from another
+/// sender.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryConfirmation {
+    pub message_id: MessageId,
+    /// Who stored the message with the relay
+    pub sender: VerifyingKey,
+    pub delivered_to: VerifyingKey,
+    pub timestamp: DateTime<Utc>,
+    signature: Signature,
+}
+
+/// A packet of the relay protocol
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelayPacket {
+    /// Sent by the relay right after the identity exchange
+    Challenge(Challenge),
+    /// `RELAY_REGISTER`, the answer of the client to the [`RelayPacket::Challenge`]
+    Register(RelayRegister),
+    /// The relay has accepted the registration, with the capabilities it applies
+    Registered(RelayCapabilities),
+    /// `STORE_MESSAGE`
+    Store(StoreMessage),
+    /// `STORE_RESPONSE`
+    StoreResponse(StoreResponse),
+    /// `RETRIEVE_MESSAGES`
+    Retrieve(RetrieveMessages),
+    /// `MESSAGE_BATCH`, the answer to [`RelayPacket::Retrieve`]. The relay also sends these
+    /// unasked when a message for a registered client arrives.
+    Batch(MessageBatch),
+    /// `DELIVERY_CONFIRMATION`, sent by the recipient and passed on to the sender
+    DeliveryConfirmation(DeliveryConfirmation),
+}
+
+impl Challenge {
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl RelayCapabilities {
+    /// The capabilities that both sides can handle.
+    pub fn limit(&self, other: &Self) -> Self {
+        Self {
+            max_message_size: self.max_message_size.min(other.max_message_size),
+            max_storage_duration: self.max_storage_duration.min(other.max_storage_duration),
+        }
+    }
+}
+
+impl RelayRegister {
+    pub fn sign(
+        user: &UserIdentity,
+        relay: &VerifyingKey,
+        challenge: &Challenge,
+        capabilities: RelayCapabilities,
+    ) -> Self {
+        Self {
+            identity: user.identity.public_key,
+            auth_signature: user.private_key.sign(&auth_data(
+                REGISTER_SIGNATURE_CONTEXT,
+                relay,
+                challenge,
+            )),
+            capabilities,
+        }
+    }
+
+    /// Checks that the registration answers the `challenge` of the `relay`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidSignature`] if the signature does not match.
+    pub fn verify(&self, relay: &VerifyingKey, challenge: &Challenge) -> CoreResult<()> {
+        self.identity
+            .verify_strict(
+                &auth_data(REGISTER_SIGNATURE_CONTEXT, relay, challenge),
+                &self.auth_signature,
+            )
+            .map_err(|_| CoreError::InvalidSignature("relay registration"))
+    }
+}
+
+impl RetrieveMessages {
+    pub fn sign(
+        user: &UserIdentity,
+        relay: &VerifyingKey,
+        challenge: &Challenge,
+        since: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            identity: user.identity.public_key,
+            auth_signature: user
+                .private_key
+                .sign(&Self::signed_data(relay, challenge, since)),
+            since,
+        }
+    }
+
+    /// Checks that the request was made for the `challenge` of the `relay`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidSignature`] if the signature does not match.
+    pub fn verify(&self, relay: &VerifyingKey, challenge: &Challenge) -> CoreResult<()> {
+        self.identity
+            .verify_strict(
+                &Self::signed_data(relay, challenge, self.since),
+                &self.auth_signature,
+            )
+            .map_err(|_| CoreError::InvalidSignature("message retrieval"))
+    }
+
+    fn signed_data(
+        relay: &VerifyingKey,
+        challenge: &Challenge,
+        since: Option<DateTime<Utc>>,
+    ) -> Vec<u8> {
+        let mut data = auth_data(RETRIEVE_SIGNATURE_CONTEXT, relay, challenge);
+        if let Some(since) = since {
+            data.push(1);
+            data.extend_from_slice(&since.timestamp_micros().to_be_bytes());
+        } else {
+            data.push(0);
+        }
+        data
+    }
+}
+
+impl StoreMessage {
+    pub fn sign(
+        user: &UserIdentity,
+        recipient: VerifyingKey,
+        message_id: MessageId,
+        encrypted_blob: Vec<u8>,
+    ) -> Self {
+        Self {
+            sender_signature: user.private_key.sign(&Self::signed_data(
+                &recipient,
+                message_id,
+                &encrypted_blob,
+            )),
+            recipient,
+            encrypted_blob,
+            message_id,
+        }
+    }
+
+    /// Checks that the message was stored by `sender`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidSignature`] if the signature does not match.
+    pub fn verify(&self, sender: &VerifyingKey) -> CoreResult<()> {
+        sender
+            .verify_strict(
+                &Self::signed_data(&self.recipient, self.message_id, &self.encrypted_blob),
+                &self.sender_signature,
+            )
+            .map_err(|_| CoreError::InvalidSignature("stored message"))
+    }
+
+    fn signed_data(recipient: &VerifyingKey, message_id: MessageId, blob: &[u8]) -> Vec<u8> {
+        let mut data = STORE_SIGNATURE_CONTEXT.to_vec();
+        data.extend_from_slice(recipient.as_bytes());
+        data.extend_from_slice(message_id.as_bytes());
+        data.extend_from_slice(blob);
+        data
+    }
+}
+
+impl DeliveryConfirmation {
+    pub fn sign(
+        user: &UserIdentity,
+        relay: &VerifyingKey,
+        sender: VerifyingKey,
+        message_id: MessageId,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            message_id,
+            sender,
+            delivered_to: user.identity.public_key,
+            timestamp,
+            signature: user
+                .private_key
+                .sign(&Self::signed_data(relay, &sender, message_id, timestamp)),
+        }
+    }
+
+    /// Checks that the confirmation was signed by [`Self::delivered_to`], for the `relay`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidSignature`] if the signature does not match.
+    pub fn verify(&self, relay: &VerifyingKey) -> CoreResult<()> {
+        self.delivered_to
+            .verify_strict(
+                &Self::signed_data(relay, &self.sender, self.message_id, self.timestamp),
+                &self.signature,
+            )
+            .map_err(|_| CoreError::InvalidSignature("delivery confirmation"))
+    }
+
+    fn signed_data(
+        relay: &VerifyingKey,
+        sender: &VerifyingKey,
+        message_id: MessageId,
+        timestamp: DateTime<Utc>,
+    ) -> Vec<u8> {
+        let mut data = DELIVERY_SIGNATURE_CONTEXT.to_vec();
+        data.extend_from_slice(relay.as_bytes());
+        data.extend_from_slice(sender.as_bytes());
+        data.extend_from_slice(message_id.as_bytes());
+        data.extend_from_slice(&timestamp.timestamp_micros().to_be_bytes());
+        data
+    }
+}
+
+impl RelayPacket {
+    /// Short name of the packet type, for logging
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Challenge(_) => "relay challenge",
+            Self::Register(_) => "relay register",
+            Self::Registered(_) => "relay registered",
+            Self::Store(_) => "store message",
+            Self::StoreResponse(_) => "store response",
+            Self::Retrieve(_) => "retrieve messages",
+            Self::Batch(_) => "message batch",
+            Self::DeliveryConfirmation(_) => "delivery confirmation",
+        }
+    }
+}
+
+/// The data signed to answer a [`Challenge`], bound to the relay it came from
+fn auth_data(context: &[u8], relay: &VerifyingKey, challenge: &Challenge) -> Vec<u8> {
+    let mut data = context.to_vec();
+    data.extend_from_slice(relay.as_bytes());
+    data.extend_from_slice(&challenge.0);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay_key() -> VerifyingKey {
+        UserIdentity::build("relay").unwrap().identity.public_key
+    }
+
+    #[test]
+    fn registration_answers_the_challenge_of_the_relay() {
+        let user = UserIdentity::build("alice").unwrap();
+        let relay = relay_key();
+        let challenge = Challenge::generate();
+        let capabilities = RelayCapabilities {
+            max_message_size: 1024,
+            max_storage_duration: Duration::from_secs(60),
+        };
+        let register = RelayRegister::sign(&user, &relay, &challenge, capabilities);
+        assert!(register.verify(&relay, &challenge).is_ok());
+
+        // the answer is only good for this challenge of this relay
+        assert!(register.verify(&relay, &Challenge::generate()).is_err());
+        assert!(register.verify(&relay_key(), &challenge).is_err());
+
+        let mut impostor = register.clone();
+        impostor.identity = UserIdentity::build("alice").unwrap().identity.public_key;
+        assert!(matches!(
+            impostor.verify(&relay, &challenge),
+            Err(CoreError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn retrieval_is_bound_to_the_challenge_and_the_time() {
+        let user = UserIdentity::build("alice").unwrap();
+        let relay = relay_key();
+        let challenge = Challenge::generate();
+        for since in [None, Some(Utc::now())] {
+            let retrieve = RetrieveMessages::sign(&user, &relay, &challenge, since);
+            assert!(retrieve.verify(&relay, &challenge).is_ok());
+            assert!(retrieve.verify(&relay, &Challenge::generate()).is_err());
+
+            let mut earlier = retrieve.clone();
+            earlier.since = Some(DateTime::UNIX_EPOCH);
+            assert!(earlier.verify(&relay, &challenge).is_err());
+        }
+    }
+
+    #[test]
+    fn stored_message_is_verified_with_the_sender_key() {
+        let user = UserIdentity::build("alice").unwrap();
+        let recipient = UserIdentity::build("bob").unwrap().identity.public_key;
+        let store = StoreMessage::sign(&user, recipient, MessageId::generate(), vec![1, 2, 3]);
+        assert!(store.verify(&user.identity.public_key).is_ok());
+        assert!(store.verify(&recipient).is_err());
+
+        let mut altered = store.clone();
+        altered.encrypted_blob.push(4);
+        assert!(altered.verify(&user.identity.public_key).is_err());
+    }
+
+    #[test]
+    fn delivery_confirmation_is_signed_by_the_recipient() {
+        let user = UserIdentity::build("bob").unwrap();
+        let relay = relay_key();
+        let sender = UserIdentity::build("alice").unwrap().identity.public_key;
+        let confirmation =
+            DeliveryConfirmation::sign(&user, &relay, sender, MessageId::generate(), Utc::now());
+        assert!(confirmation.verify(&relay).is_ok());
+
+        // the confirmation is only good for this relay and this sender
+        assert!(confirmation.verify(&relay_key()).is_err());
+        let mut other_sender = confirmation.clone();
+        other_sender.sender = UserIdentity::build("mallory").unwrap().identity.public_key;
+        assert!(other_sender.verify(&relay).is_err());
+
+        let mut forged = confirmation.clone();
+        forged.delivered_to = UserIdentity::build("bob").unwrap().identity.public_key;
+        assert!(forged.verify(&relay).is_err());
+        let mut altered = confirmation;
+        altered.message_id = MessageId::generate();
+        assert!(altered.verify(&relay).is_err());
+    }
+}
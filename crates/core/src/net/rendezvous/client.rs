@@ -0,0 +1,364 @@
+//! Publishing the listener on a rendezvous server and finding contacts there
+//!
+//! While a listener is running and a [rendezvous server](crate::state::Settings::rendezvous) is
+//! set, the endpoint of the listener is registered with the server, and renewed before the
+//! registration expires. Contacts can be looked up by their key with
+//! [`NetworkCommand::Lookup`](crate::net::NetworkCommand::Lookup), or connected to with
+//! [`NetworkCommand::ConnectToContact`](crate::net::NetworkCommand::ConnectToContact).
+//!
+//! Each request opens a short connection to the rendezvous server, which is closed once the
+//! answer is there.
+
+use std::net::SocketAddr;
+
+use async_channel::{Receiver, Sender};
+use chrono::{DateTime, TimeDelta, Utc};
+use ed25519_dalek::VerifyingKey;
+use log::{debug, info, warn};
+
+use crate::{
+    error::{CoreError, CoreResult},
+    identity::{UserIdentity, format_key},
+    net::{
+        NetworkCommand, NetworkEvent,
+        connection::{Connection, FrameBody, Timeouts},
+        rendezvous::{Endpoint, LookupRequest, PeerInfo, RegisterRequest, RendezvousPacket},
+    },
+    state::{State, StateSync},
+};
+
+/// How often the rendezvous job checks if the registration is due for renewal
+const RENEWAL_CHECK_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(1);
+/// The time to live asked for, the server may grant a shorter one
+const REGISTRATION_TTL: u32 = 60 * 60;
+/// Delay before registering again after a registration failed
+const REGISTRATION_RETRY_DELAY: TimeDelta = TimeDelta::minutes(1);
+
+/// The registration of the listener with the rendezvous server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    pub server: SocketAddr,
+    pub endpoint: Endpoint,
+    /// When the registration ends, if the server has accepted it
+    pub expires_at: Option<DateTime<Utc>>,
+    /// When the registration is made again
+    pub renew_at: DateTime<Utc>,
+    registering: bool,
+}
+
+impl State {
+    /// Registers the listener right away, after it was started.
+    pub(in crate::net) fn spawn_register(state: &StateSync, event_channel: &Sender<NetworkEvent>) {
+        let state = state.clone();
+        let event_channel = event_channel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Self::renew_registration(&state, &event_channel).await {
+                log::error!("Error while registering with the rendezvous server: {e}");
+            }
+        });
+    }
+
+    /// Keeps the registration of the listener up to date, see the
+    /// [module documentation](self).
+    pub(crate) async fn job_rendezvous(
+        state: &StateSync,
+        _command_channel: &mut Receiver<NetworkCommand>,
+        event_channel: &mut Sender<NetworkEvent>,
+    ) -> CoreResult<()> {
+        tokio::time::sleep(RENEWAL_CHECK_INTERVAL).await;
+        Self::renew_registration(state, event_channel).await
+    }
+
+    /// Ends the registration after the listener was stopped, so that contacts do not try to
+    /// reach it.
+    pub(in crate::net) fn spawn_unregister(state: &StateSync, registration: Registration) {
+        if registration.expires_at.is_none() {
+            return;
+        }
+        let state = state.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let (user, timeouts) = Self::rendezvous_context(&state).await?;
+                Self::register(
+                    &user,
+                    &timeouts,
+                    &registration.server,
+                    registration.endpoint,
+                    0,
+                )
+                .await
+            }
+            .await;
+            match result {
+                Ok(_) => info!(
+                    "Unregistered from the rendezvous server {}",
+                    registration.server
+                ),
+                Err(e) => warn!(
+                    "Could not unregister from the rendezvous server {}: {e}",
+                    registration.server
+                ),
+            }
+        });
+    }
+
+    async fn renew_registration(
+        state: &StateSync,
+        event_channel: &Sender<NetworkEvent>,
+    ) -> CoreResult<()> {
+        let Some((server, endpoint)) = state.write().await.due_registration(Utc::now())? else {
+            return Ok(());
+        };
+        debug!("Registering {endpoint} with the rendezvous server {server}");
+
+        let result = async {
+            let (user, timeouts) = Self::rendezvous_context(state).await?;
+            Self::register(
+                &user,
+                &timeouts,
+                &server,
+                endpoint.clone(),
+                REGISTRATION_TTL,
+            )
+            .await
+        }
+        .await;
+
+        let now = Utc::now();
+        let event = {
+            let mut state = state.write().await;
+            let Some(registration) = state
+                .registration
+                .as_mut()
+                .filter(|registration| registration.server == server)
+                .filter(|registration| registration.endpoint == endpoint)
+            else {
+                // the listener or the server changed meanwhile
+                return Ok(());
+            };
+            registration.registering = false;
+            match result {
+                Ok((expires_at, renewal_interval)) => {
+                    info!("Registered {endpoint} with the rendezvous server {server}");
+                    registration.expires_at = Some(expires_at);
+                    registration.renew_at =
+                        (now + TimeDelta::seconds(renewal_interval.into())).min(expires_at);
+                    NetworkEvent::RendezvousRegistered(server, endpoint, expires_at)
+                }
+                Err(e) => {
+                    warn!("Could not register with the rendezvous server {server}: {e}");
+                    registration.renew_at = now + REGISTRATION_RETRY_DELAY;
+                    return Ok(());
+                }
+            }
+        };
+        event_channel.send(event).await?;
+        Ok(())
+    }
+
+    /// Finds out if the listener needs to be registered, and marks it as registering.
+    ///
+    /// The registration follows the listener and the rendezvous server in the settings, it is
+    /// made again as soon as either of them changes.
+    fn due_registration(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> CoreResult<Option<(SocketAddr, Endpoint)>> {
+        let (Some(listener), Some(server)) = (&self.listener, self.settings.rendezvous) else {
+            self.registration = None;
+            return Ok(None);
+        };
+        let endpoint = Endpoint::from(listener.local_addr()?);
+        let registration = match &mut self.registration {
+            Some(registration)
+                if registration.server == server && registration.endpoint == endpoint =>
+            {
+                registration
+            }
+            _ => self.registration.insert(Registration {
+                server,
+                endpoint,
+                expires_at: None,
+                renew_at: now,
+                registering: false,
+            }),
+        };
+        if registration.registering || registration.renew_at > now {
+            return Ok(None);
+        }
+        registration.registering = true;
+        Ok(Some((registration.server, registration.endpoint.clone())))
+    }
+
+    /// Looks up where a contact can be reached on the rendezvous server.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::NoRendezvousServer`] if no rendezvous server is set, or if the
+    /// server cannot be asked.
+    pub(in crate::net) async fn lookup(
+        state: &StateSync,
+        key: VerifyingKey,
+    ) -> CoreResult<Option<PeerInfo>> {
+        let server = state
+            .read()
+            .await
+            .settings
+            .rendezvous
+            .ok_or(CoreError::NoRendezvousServer)?;
+        let (user, timeouts) = Self::rendezvous_context(state).await?;
+        let packet = RendezvousPacket::Lookup(LookupRequest::target(key));
+        let response = match Self::rendezvous_request(&user, &timeouts, &server, |_| packet).await?
+        {
+            RendezvousPacket::Found(response) => response,
+            other => {
+                return Err(CoreError::UnexpectedPacket {
+                    remote: server,
+                    kind: other.kind(),
+                });
+            }
+        };
+        if let Some(reason) = response.error_message {
+            return Err(CoreError::RendezvousRefused(server, reason));
+        }
+        // the server must not hand out someone else for the contact
+        Ok(response
+            .peers
+            .into_iter()
+            .find(|peer| peer.identity.public_key == key))
+    }
+
+    /// Looks up a contact on the rendezvous server, and connects to it.
+    ///
+    /// Every address of the endpoint of the contact is tried, until one connects.
+    pub(in crate::net) async fn connect_to_contact(
+        state: &StateSync,
+        key: VerifyingKey,
+        event_channel: &Sender<NetworkEvent>,
+    ) -> CoreResult<NetworkEvent> {
+        let Some(peer) = Self::lookup(state, key).await? else {
+            info!(
+                "{} is not registered with the rendezvous server",
+                format_key(&key)
+            );
+            return Ok(NetworkEvent::PeerLookedUp(key, None));
+        };
+        let endpoint = peer.endpoint.clone();
+        event_channel
+            .send(NetworkEvent::PeerLookedUp(key, Some(peer)))
+            .await?;
+
+        let mut last_error = None;
+        for remote in endpoint.resolve().await? {
+            // boxed, the connection is a large future and debug builds run out of stack otherwise
+            match Box::pin(Self::connect_to(state, remote, None, event_channel)).await {
+                Ok(NetworkEvent::ConnectionEstablished(remote, peer)) => {
+                    if peer != key {
+                        warn!(
+                            "{remote} was registered for {}, but belongs to {}",
+                            format_key(&key),
+                            format_key(&peer)
+                        );
+                    }
+                    return Ok(NetworkEvent::ConnectionEstablished(remote, peer));
+                }
+                Ok(event) => return Ok(event),
+                Err(e) => {
+                    debug!("Could not connect to {remote} for {endpoint}: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or(CoreError::InvalidEndpoint(endpoint.to_string())))
+    }
+
+    async fn rendezvous_context(state: &StateSync) -> CoreResult<(UserIdentity, Timeouts)> {
+        let user = Self::user_identity(state).await?;
+        let timeouts = state.read().await.settings.timeouts;
+        Ok((user, timeouts))
+    }
+
+    /// Registers `endpoint` with the rendezvous server.
+    ///
+    /// Returns when the registration expires, and after how many seconds it should be renewed.
+    async fn register(
+        user: &UserIdentity,
+        timeouts: &Timeouts,
+        server: &SocketAddr,
+        endpoint: Endpoint,
+        ttl_seconds: u32,
+    ) -> CoreResult<(DateTime<Utc>, u32)> {
+        let response = Self::rendezvous_request(user, timeouts, server, |server_key| {
+            RendezvousPacket::Register(RegisterRequest::sign(
+                user,
+                server_key,
+                endpoint,
+                ttl_seconds,
+            ))
+        })
+        .await?;
+        match response {
+            RendezvousPacket::Registered(response) if response.success => {
+                Ok((response.expires_at, response.renewal_interval))
+            }
+            RendezvousPacket::Registered(response) => Err(CoreError::RendezvousRefused(
+                *server,
+                response
+                    .error_message
+                    .unwrap_or_else(|| "no reason given".to_string()),
+            )),
+            other => Err(CoreError::UnexpectedPacket {
+                remote: *server,
+                kind: other.kind(),
+            }),
+        }
+    }
+
+    /// Sends a single request to the rendezvous server, and waits for the answer.
+    ///
+    /// The request is made once the key of the server is known, as registrations are signed
+    /// for it.
+    async fn rendezvous_request(
+        user: &UserIdentity,
+        timeouts: &Timeouts,
+        server: &SocketAddr,
+        request: impl FnOnce(&VerifyingKey) -> RendezvousPacket,
+    ) -> CoreResult<RendezvousPacket> {
+        let server = *server;
+        let (connection, mut reader) = Connection::connect_to(server, user, timeouts).await?;
+        let server_key = connection.peer_identity().await.public_key;
+        let writer = connection.writer();
+
+        let exchange = async {
+            writer
+                .send(&FrameBody::Rendezvous(request(&server_key)))
+                .await?;
+            loop {
+                match reader.recv().await?.body {
+                    FrameBody::Rendezvous(response) => return Ok(response),
+                    FrameBody::Ping(value) => writer.send_detached(FrameBody::Pong(value)),
+                    FrameBody::Ack(_) | FrameBody::Pong(_) => {}
+                    FrameBody::Error(reason) => {
+                        return Err(CoreError::RendezvousRefused(server, reason));
+                    }
+                    other => {
+                        return Err(CoreError::UnexpectedPacket {
+                            remote: server,
+                            kind: other.kind(),
+                        });
+                    }
+                }
+            }
+        };
+        let result = tokio::time::timeout(timeouts.identity, exchange)
+            .await
+            .unwrap_or(Err(CoreError::Timeout {
+                remote: server,
+                stage: "rendezvous request",
+            }));
+        if let Err(e) = connection.disconnect().await {
+            debug!("Could not close the connection with the rendezvous server {server}: {e}");
+        }
+        result
+    }
+}
@@ -0,0 +1,246 @@
+//! The rendezvous protocol, see section 7 of the specification
+//!
+//! Rendezvous servers keep a registry of where peers can be reached. A peer registers the
+//! endpoint of its listener with a [`RegisterRequest`], which expires after the time to live it
+//! asked for, and can look up others with a [`LookupRequest`].
+//!
+//! Unlike the specification says, rendezvous servers are not reached over TLS, but are connected
+//! to like any peer, with the noise handshake and the identity exchange. That authenticates the
+//! server by its key, without depending on certificates.
+//!
+//! All packets of this protocol are carried as [`RendezvousPacket`] in
+//! [`FrameBody::Rendezvous`](crate::net::connection::FrameBody::Rendezvous).
+//!
+//! See the [`client`] module for how the listener is registered and contacts are looked up.
+
+use std::{fmt::Display, net::SocketAddr};
+
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{CoreError, CoreResult},
+    identity::{Identity, UserIdentity},
+};
+
+pub mod client;
+
+/// Prefix of the signed data of a [`RegisterRequest`]
+const REGISTER_SIGNATURE_CONTEXT: &[u8] = b"SREMP rendezvous register v1";
+/// Longest host name an [`Endpoint`] can have, as for DNS names
+pub const MAX_HOST_LENGTH: usize = 253;
+
+/// Where a peer can be reached, by host name or address
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+/// Registers where the peer can be reached, signed with its identity key
+///
+/// A time to live of zero removes the registration. If the host of the endpoint is an
+/// unspecified address like `0.0.0.0`, the server uses the address the registration came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterRequest {
+    pub identity: Identity,
+    pub endpoint: Endpoint,
+    pub ttl_seconds: u32,
+    signature: Signature,
+}
+
+/// The answer of the rendezvous server to a [`RegisterRequest`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterResponse {
+    pub success: bool,
+    /// When the registration ends, unless it is renewed
+    pub expires_at: DateTime<Utc>,
+    /// After how many seconds the registration should be renewed
+    pub renewal_interval: u32,
+    pub error_message: Option<String>,
+}
+
+/// Asks the rendezvous server for registered peers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LookupRequest {
+    /// The peer to look up, the response has at most this one
+    pub target_identity: Option<VerifyingKey>,
+    /// Asks for all registered peers instead
+    pub list_all: bool,
+}
+
+/// The answer of the rendezvous server to a [`LookupRequest`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LookupResponse {
+    pub peers: Vec<PeerInfo>,
+    pub error_message: Option<String>,
+}
+
+/// A registered peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub identity: Identity,
+    pub endpoint: Endpoint,
+    /// When the peer was last connected to the rendezvous server
+    pub last_seen: DateTime<Utc>,
+    /// Whether the peer is connected to the rendezvous server right now
+    pub online: bool,
+}
+
+/// A packet of the rendezvous protocol
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RendezvousPacket {
+    /// `REGISTER_REQUEST`, sent by the client
+    Register(RegisterRequest),
+    /// `REGISTER_RESPONSE`, the answer of the server
+    Registered(RegisterResponse),
+    /// `LOOKUP_REQUEST`, sent by the client
+    Lookup(LookupRequest),
+    /// `LOOKUP_RESPONSE`, the answer of the server
+    Found(LookupResponse),
+}
+
+impl Endpoint {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+        }
+    }
+
+    /// Checks that the host is a plausible host name or address.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidEndpoint`] if the host is empty, too long, or has
+    /// whitespace in it.
+    pub fn validate(&self) -> CoreResult<()> {
+        if self.host.is_empty()
+            || self.host.len() > MAX_HOST_LENGTH
+            || self
+                .host
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(CoreError::InvalidEndpoint(self.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Looks up the addresses of the endpoint, a host name can have several.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the host cannot be resolved.
+    pub async fn resolve(&self) -> CoreResult<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await?
+            .collect())
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(value: SocketAddr) -> Self {
+        Self::new(value.ip().to_string(), value.port())
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // IPv6 addresses need brackets, or the port would look like part of them
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl RegisterRequest {
+    /// Signs a registration of the `endpoint` of the user with the rendezvous server that has
+    /// the key `server`.
+    pub fn sign(
+        user: &UserIdentity,
+        server: &VerifyingKey,
+        endpoint: Endpoint,
+        ttl_seconds: u32,
+    ) -> Self {
+        let signature = user.private_key.sign(&Self::signed_data(
+            server,
+            &user.identity,
+            &endpoint,
+            ttl_seconds,
+        ));
+        Self {
+            identity: user.identity.clone(),
+            endpoint,
+            ttl_seconds,
+            signature,
+        }
+    }
+
+    /// Checks that the registration was made by the key of its identity, for the rendezvous
+    /// server that has the key `server`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::InvalidSignature`] if the signature does not match.
+    pub fn verify(&self, server: &VerifyingKey) -> CoreResult<()> {
+        self.identity
+            .public_key
+            .verify_strict(
+                &Self::signed_data(server, &self.identity, &self.endpoint, self.ttl_seconds),
+                &self.signature,
+            )
+            .map_err(|_| CoreError::InvalidSignature("rendezvous registration"))
+    }
+
+    fn signed_data(
+        server: &VerifyingKey,
+        identity: &Identity,
+        endpoint: &Endpoint,
+        ttl_seconds: u32,
+    ) -> Vec<u8> {
+        let mut data = REGISTER_SIGNATURE_CONTEXT.to_vec();
+        data.extend_from_slice(server.as_bytes());
+        data.extend_from_slice(identity.public_key.as_bytes());
+        for text in [&identity.username, &endpoint.host] {
+            // the length keeps the fields apart
+            data.extend_from_slice(&(text.len() as u64).to_be_bytes());
+            data.extend_from_slice(text.as_bytes());
+        }
+        data.extend_from_slice(&endpoint.port.to_be_bytes());
+        data.extend_from_slice(&ttl_seconds.to_be_bytes());
+        data
+    }
+}
+
+impl LookupRequest {
+    /// Looks up a single peer.
+    pub fn target(key: VerifyingKey) -> Self {
+        Self {
+            target_identity: Some(key),
+            list_all: false,
+        }
+    }
+
+    /// Lists all registered peers.
+    pub fn list_all() -> Self {
+        Self {
+            target_identity: None,
+            list_all: true,
+        }
+    }
+}
+
+impl RendezvousPacket {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Register(_) => "rendezvous register",
+            Self::Registered(_) => "rendezvous registered",
+            Self::Lookup(_) => "rendezvous lookup",
+            Self::Found(_) => "rendezvous lookup response",
+        }
+    }
+}
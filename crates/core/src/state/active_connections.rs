@@ -25,15 +25,18 @@ impl ActiveConnections {
     }
 
     pub fn find_socket_addr_for_contact(&self, key: &VerifyingKey) -> Option<SocketAddr> {
-        let mut kv: Vec<(&SocketAddr, &ConnectionData)> = self.inner.iter().collect();
-        kv.sort();
-        let correct_idx =
-            match kv.binary_search_by_key(&key.as_bytes(), |(_, v)| v.iden.public_key.as_bytes()) {
-                Ok(idx) => idx,
-                Err(_) => return None,
-            };
-        let conn = kv[correct_idx];
-        Some(*conn.0)
+        self.inner
+            .iter()
+            .find(|(_, data)| data.iden.public_key == *key)
+            .map(|(remote, _)| *remote)
+    }
+
+    /// Finds a connection to a relay server, which can reach contacts that are not connected.
+    pub fn find_relay(&self) -> Option<SocketAddr> {
+        self.inner
+            .iter()
+            .find(|(_, data)| data.conn.relay().is_some())
+            .map(|(remote, _)| *remote)
     }
 }
 
@@ -59,12 +62,7 @@ impl PartialEq for ConnectionData {
 
 impl PartialOrd for ConnectionData {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(
-            self.iden
-                .public_key
-                .as_bytes()
-                .cmp(other.iden.public_key.as_bytes()),
-        )
+        Some(self.cmp(other))
     }
 }
 
@@ -2,29 +2,125 @@ mod known_identities;
 pub use known_identities::*;
 mod active_connections;
 pub use active_connections::*;
+mod settings;
+pub use settings::*;
 use tokio::net::TcpListener;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
 
 use ed25519_dalek::VerifyingKey;
 use serde::{Deserialize, Serialize};
 
-use crate::{chat::Chat, identity::UserIdentity};
+use crate::{
+    chat::Chat,
+    crypto::ratchet::RatchetSessions,
+    error::{CoreError, CoreResult},
+    identity::{Trust, UserIdentity},
+    net::{outbox::QueuedMessage, reconnect::Backoff, rendezvous::client::Registration},
+    storage::Storage,
+};
 pub type StateSync = Arc<tokio::sync::RwLock<State>>;
 
+/// Everything the backend knows, the parts that are not skipped are stored on disk
+///
+/// Fields missing from an older state file are left at their default, see
+/// [`crate::storage`].
 #[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct State {
     pub known_identities: KnownIdentities,
     pub chats: HashMap<VerifyingKey, Chat>,
+    /// Chats with unknown peers that the user has not accepted yet, see
+    /// [`crate::chat::requests`]
+    pub requests: HashMap<VerifyingKey, Chat>,
+    /// End-to-end encryption sessions with the contacts, stored along with the chats
+    pub sessions: HashMap<VerifyingKey, RatchetSessions>,
     #[serde(skip)]
     pub active_connections: ActiveConnections,
     pub user_identity: Option<UserIdentity>,
+    pub settings: Settings,
     #[serde(skip)]
-    pub listener: Option<TcpListener>,
+    pub listener: Option<Arc<TcpListener>>,
+    /// Messages waiting to be sent to the contacts, see [`crate::net::outbox`]
+    pub outbox: HashMap<VerifyingKey, VecDeque<QueuedMessage>>,
+    /// Contacts whose outbox is being sent right now
+    #[serde(skip)]
+    pub flushing: HashSet<VerifyingKey>,
+    /// Failed attempts to reconnect to contacts, see [`crate::net::reconnect`]
+    #[serde(skip)]
+    pub reconnects: HashMap<VerifyingKey, Backoff>,
+    /// The registration of the listener with the rendezvous server, see
+    /// [`crate::net::rendezvous::client`]
+    #[serde(skip)]
+    pub registration: Option<Registration>,
+    /// Where the state is saved, it is not saved at all if this is [`None`]
+    #[serde(skip)]
+    pub storage: Option<Storage>,
 }
 
 impl State {
     pub fn to_sync(self) -> StateSync {
         Arc::new(tokio::sync::RwLock::new(self))
     }
+
+    /// Saves the state to its [`Storage`], if it has one.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the state cannot be written, see [`Storage::save`].
+    pub async fn save(state: &StateSync) -> CoreResult<()> {
+        let storage = state.read().await.storage.clone();
+        match storage {
+            Some(storage) => storage.save(state).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the passphrase the state is stored with, and saves it with the new one.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::WrongPassphrase`] if `old` is not the current passphrase, with
+    /// [`CoreError::StorageLocked`] if the storage has no passphrase yet, or if the state
+    /// cannot be saved.
+    pub async fn change_passphrase(state: &StateSync, old: &str, new: &str) -> CoreResult<()> {
+        let Some(mut storage) = state.read().await.storage.clone() else {
+            return Err(CoreError::StorageLocked);
+        };
+        if !storage.check_passphrase(old).await? {
+            return Err(CoreError::WrongPassphrase);
+        }
+        storage.set_passphrase(new).await?;
+        state.write().await.storage = Some(storage);
+        Self::save(state).await
+    }
+
+    /// Sets how far a contact is trusted, for example after verifying its
+    /// [`SafetyNumber`](crate::crypto::safety_number::SafetyNumber).
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::UnknownContact`] if there is no contact with that key.
+    pub fn set_trust(&mut self, key: &VerifyingKey, trust: Trust) -> CoreResult<()> {
+        self.known_identities
+            .get_mut(key)
+            .ok_or(CoreError::UnknownContact(*key))?
+            .trust = trust;
+        for chats in [&mut self.chats, &mut self.requests] {
+            if let Some(chat) = chats.get_mut(key) {
+                chat.contact_mut().trust = trust;
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the state after it was changed by the backend, errors are only logged.
+    pub(crate) async fn autosave(state: &StateSync) {
+        if let Err(e) = Self::save(state).await {
+            log::error!("Could not save the state: {e}");
+        }
+    }
 }
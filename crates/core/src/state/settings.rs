@@ -0,0 +1,31 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::net::{ListenerAccess, connection::Timeouts};
+
+/// Preferences of the user that change how the backend behaves
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Tell contacts when their messages were read
+    pub read_receipts: bool,
+    /// Which peers may connect to the listener
+    pub listener_access: ListenerAccess,
+    /// How long peers get to answer before their connection is given up
+    pub timeouts: Timeouts,
+    /// Where the listener is registered, and contacts are looked up, see
+    /// [`crate::net::rendezvous::client`]
+    pub rendezvous: Option<SocketAddr>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            read_receipts: true,
+            listener_access: ListenerAccess::default(),
+            timeouts: Timeouts::default(),
+            rendezvous: None,
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! Persisting the [`State`] on disk
+//!
+//! The state is stored in a single file, which is encrypted with a key derived from a passphrase
+//! of the user. The file is laid out like this, all numbers in big endian:
+//!
+//! | Field           | Size |
+//! |-----------------|------|
+//! | [`STORAGE_MAGIC`] | 5  |
+//! | [`STORAGE_VERSION`] | 2 |
+//! | Argon2 memory cost in KiB | 4 |
+//! | Argon2 iterations | 4 |
+//! | Argon2 parallelism | 4 |
+//! | Salt            | 16   |
+//! | Nonce           | 24   |
+//! | Ciphertext      | rest |
+//!
+//! The key is derived with Argon2id, and the state is encoded as MessagePack and encrypted with
+//! XChaCha20-Poly1305, with everything before the ciphertext as associated data. Only the
+//! persistent parts of the state are written, things like active connections are skipped.
+//!
+//! Structs are encoded with the names of their fields, so that files written by older versions
+//! still load: fields that are added later have `#[serde(default)]`, and fields that are gone
+//! are ignored. [`STORAGE_VERSION`] is therefore only increased if the layout of the file itself
+//! changes, not when the state gains a field.
+//!
+//! Writing is atomic: the data goes into a temporary file next to the state file first, which
+//! then replaces the old file.
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+use rand::RngCore;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use zeroize::Zeroizing;
+
+use crate::{
+    error::{CoreError, CoreResult, LoadError},
+    state::{State, StateSync},
+};
+
+/// Bytes at the start of every state file
+pub const STORAGE_MAGIC: &[u8; 5] = b"SREMP";
+/// Version of the layout of the state file, see the [module documentation](self) for when it
+/// changes
+pub const STORAGE_VERSION: u16 = 1;
+/// Name of the directory in the data directory of the user
+const STORAGE_DIR_NAME: &str = "sremp";
+/// Name of the file the state is stored in
+const STATE_FILE_NAME: &str = "state.sremp";
+
+/// Upper bounds for the Argon2 parameters in the header, which is only authenticated after the
+/// key was derived with them, so that a broken file cannot make loading take all memory or
+/// run forever
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 64;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = STORAGE_MAGIC.len() + 2 + 3 * 4 + SALT_LEN + NONCE_LEN;
+
+/// Location where the [`State`] is stored
+///
+/// A storage is locked until it has a key, either by loading the stored state with
+/// [`Storage::load`] or by setting a passphrase with [`Storage::set_passphrase`].
+#[derive(Debug, Clone)]
+pub struct Storage {
+    path: PathBuf,
+    key: Option<StorageKey>,
+    /// Makes sure only one save writes to the disk at a time
+    write_lock: Arc<Mutex<()>>,
+}
+
+/// Key derived from the passphrase of the user, with the parameters used to derive it
+#[derive(Clone)]
+struct StorageKey {
+    key: Zeroizing<[u8; KEY_LEN]>,
+    salt: [u8; SALT_LEN],
+    params: KdfParams,
+}
+
+/// Argon2id parameters, stored in the header so that they can be changed later
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl Storage {
+    /// Uses the given file to store the state.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            key: None,
+            write_lock: Default::default(),
+        }
+    }
+
+    /// Uses the default file in the data directory of the user, like
+    /// `$XDG_DATA_HOME/sremp/state.sremp`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the data directory of the user cannot be determined.
+    pub fn default_location() -> CoreResult<Self> {
+        let data_dir = dirs::data_dir().ok_or(CoreError::NoDataDirectory)?;
+        Ok(Self::new(
+            data_dir.join(STORAGE_DIR_NAME).join(STATE_FILE_NAME),
+        ))
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Checks if a stored state exists.
+    #[must_use]
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Checks if the storage has no key yet, and can therefore not be written.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.key.is_none()
+    }
+
+    /// Unlocks and loads the stored state. The returned [`State`] keeps using this storage.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`LoadError`] if no state is stored, the passphrase is wrong or the stored
+    /// state cannot be read, or with [`CoreError::IO`] if the file cannot be read.
+    pub async fn load(&mut self, passphrase: &str) -> CoreResult<State> {
+        let data = match tokio::fs::read(&self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(LoadError::Missing(self.path.clone()).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let (params, salt) = read_header(&data)?;
+        let key = StorageKey::derive(passphrase, salt, params).await?;
+        let mut state = decrypt(&key, &data)?;
+
+        self.key = Some(key);
+        state.storage = Some(self.clone());
+        log::info!("Loaded the state from {}", self.path.display());
+        Ok(state)
+    }
+
+    /// Derives a new key from the passphrase, which is used from the next save on.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the key cannot be derived.
+    pub async fn set_passphrase(&mut self, passphrase: &str) -> CoreResult<()> {
+        let mut salt = [0u8; SALT_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        self.key = Some(StorageKey::derive(passphrase, salt, KdfParams::default()).await?);
+        Ok(())
+    }
+
+    /// Checks if the passphrase is the one the current key was derived from.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::StorageLocked`] if the storage has no key yet.
+    pub async fn check_passphrase(&self, passphrase: &str) -> CoreResult<bool> {
+        let current = self.key.as_ref().ok_or(CoreError::StorageLocked)?;
+        let derived = StorageKey::derive(passphrase, current.salt, current.params).await?;
+        Ok(derived.key == current.key)
+    }
+
+    /// Writes the current state to the disk.
+    ///
+    /// The state is only locked while it is encoded, not while it is written.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`CoreError::StorageLocked`] if the storage has no key yet, or if the state
+    /// cannot be encoded or written.
+    pub async fn save(&self, state: &StateSync) -> CoreResult<()> {
+        let key = self.key.as_ref().ok_or(CoreError::StorageLocked)?;
+        // take the write lock before reading the state, so that an older state can never
+        // overwrite a newer one
+        let _guard = self.write_lock.lock().await;
+        let plaintext = Zeroizing::new(rmp_serde::to_vec_named(&*state.read().await)?);
+        let data = encrypt(key, &plaintext)?;
+        self.write(&data).await?;
+        log::trace!("Saved the state to {}", self.path.display());
+        Ok(())
+    }
+
+    async fn write(&self, data: &[u8]) -> CoreResult<()> {
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+impl StorageKey {
+    /// Derives the key on a blocking thread, as Argon2 is slow on purpose.
+    async fn derive(passphrase: &str, salt: [u8; SALT_LEN], params: KdfParams) -> CoreResult<Self> {
+        let passphrase = Zeroizing::new(passphrase.to_string());
+        tokio::task::spawn_blocking(move || {
+            let argon = Argon2::new(
+                Algorithm::Argon2id,
+                Version::V0x13,
+                Params::new(params.m_cost, params.t_cost, params.p_cost, Some(KEY_LEN))?,
+            );
+            let mut key = Zeroizing::new([0u8; KEY_LEN]);
+            argon.hash_password_into(passphrase.as_bytes(), &salt, key.as_mut())?;
+            Ok(Self { key, salt, params })
+        })
+        .await?
+    }
+}
+
+impl std::fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageKey")
+            .field("params", &self.params)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+fn encrypt(key: &StorageKey, plaintext: &[u8]) -> CoreResult<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+
+    let mut data = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    data.extend_from_slice(STORAGE_MAGIC);
+    data.extend_from_slice(&STORAGE_VERSION.to_be_bytes());
+    data.extend_from_slice(&key.params.m_cost.to_be_bytes());
+    data.extend_from_slice(&key.params.t_cost.to_be_bytes());
+    data.extend_from_slice(&key.params.p_cost.to_be_bytes());
+    data.extend_from_slice(&key.salt);
+    data.extend_from_slice(&nonce);
+
+    let ciphertext = XChaCha20Poly1305::new(key.key.as_ref().into())
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &data,
+            },
+        )
+        .map_err(|_| CoreError::StorageEncryption)?;
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+fn decrypt(key: &StorageKey, data: &[u8]) -> Result<State, LoadError> {
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let nonce = &header[HEADER_LEN - NONCE_LEN..];
+    let plaintext = Zeroizing::new(
+        XChaCha20Poly1305::new(key.key.as_ref().into())
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| LoadError::Decryption)?,
+    );
+    Ok(rmp_serde::from_slice(&plaintext)?)
+}
+
+/// Checks the header of a stored state and reads the parameters to derive the key.
+fn read_header(data: &[u8]) -> Result<(KdfParams, [u8; SALT_LEN]), LoadError> {
+    let Some(rest) = data.strip_prefix(STORAGE_MAGIC) else {
+        return Err(LoadError::NotAStateFile);
+    };
+    let Some((version, rest)) = rest.split_first_chunk::<2>() else {
+        return Err(LoadError::NotAStateFile);
+    };
+    let version = u16::from_be_bytes(*version);
+    if version != STORAGE_VERSION {
+        return Err(LoadError::VersionMismatch {
+            found: version,
+            expected: STORAGE_VERSION,
+        });
+    }
+    if data.len() < HEADER_LEN {
+        return Err(LoadError::NotAStateFile);
+    }
+
+    let read_u32 = |offset: usize| {
+        u32::from_be_bytes(rest[offset..offset + 4].try_into().expect("length is 4"))
+    };
+    let params = KdfParams {
+        m_cost: read_u32(0),
+        t_cost: read_u32(4),
+        p_cost: read_u32(8),
+    };
+    if params.m_cost > MAX_M_COST || params.t_cost > MAX_T_COST || params.p_cost > MAX_P_COST {
+        return Err(LoadError::NotAStateFile);
+    }
+    let salt = rest[12..12 + SALT_LEN]
+        .try_into()
+        .expect("length is SALT_LEN");
+    Ok((params, salt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::UserIdentity;
+
+    /// Parameters that keep the tests fast, the stored ones only need to be within the limits
+    const CHEAP_PARAMS: KdfParams = KdfParams {
+        m_cost: 8,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    async fn cheap_key(passphrase: &str) -> StorageKey {
+        StorageKey::derive(passphrase, [7; SALT_LEN], CHEAP_PARAMS)
+            .await
+            .unwrap()
+    }
+
+    fn temp_path() -> PathBuf {
+        let mut salt = [0u8; 8];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        std::env::temp_dir()
+            .join(format!("sremp-storage-{}", u64::from_be_bytes(salt)))
+            .join(STATE_FILE_NAME)
+    }
+
+    #[tokio::test]
+    async fn state_survives_a_round_trip() {
+        let path = temp_path();
+        let mut storage = Storage::new(&path);
+        storage.set_passphrase("correct horse").await.unwrap();
+        let state = State {
+            storage: Some(storage.clone()),
+            user_identity: Some(UserIdentity::build("alice").unwrap()),
+            ..Default::default()
+        }
+        .to_sync();
+        storage.save(&state).await.unwrap();
+
+        let mut reopened = Storage::new(&path);
+        assert!(reopened.is_locked());
+        let loaded = reopened.load("correct horse").await.unwrap();
+        assert!(!reopened.is_locked());
+        assert_eq!(
+            loaded.user_identity.unwrap().identity,
+            state.read().await.user_identity.as_ref().unwrap().identity
+        );
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrong_passphrase_is_refused() {
+        let data = encrypt(&cheap_key("correct horse").await, b"\x80").unwrap();
+        assert_eq!(read_header(&data).unwrap(), (CHEAP_PARAMS, [7; SALT_LEN]));
+        assert!(decrypt(&cheap_key("correct horse").await, &data).is_ok());
+
+        assert!(matches!(
+            decrypt(&cheap_key("battery staple").await, &data),
+            Err(LoadError::Decryption)
+        ));
+    }
+
+    #[tokio::test]
+    async fn tampered_state_is_refused() {
+        let key = cheap_key("correct horse").await;
+        let mut data = encrypt(&key, b"\x80").unwrap();
+        // the header is authenticated as well
+        data[HEADER_LEN - 1] ^= 1;
+        assert!(matches!(decrypt(&key, &data), Err(LoadError::Decryption)));
+    }
+
+    #[tokio::test]
+    async fn truncated_header_is_not_a_state_file() {
+        let data = encrypt(&cheap_key("correct horse").await, b"\x80").unwrap();
+        for len in [
+            0,
+            STORAGE_MAGIC.len(),
+            STORAGE_MAGIC.len() + 2,
+            HEADER_LEN - 1,
+        ] {
+            assert!(
+                matches!(read_header(&data[..len]), Err(LoadError::NotAStateFile)),
+                "{len} bytes"
+            );
+        }
+        assert!(matches!(
+            read_header(b"NOT A STATE FILE AT ALL"),
+            Err(LoadError::NotAStateFile)
+        ));
+    }
+
+    #[test]
+    fn user_identity_without_prekeys_gets_new_ones() {
+        /// A [`UserIdentity`] as it was stored before there were prekeys
+        #[derive(serde::Serialize)]
+        struct OldUserIdentity {
+            identity: crate::identity::Identity,
+            private_key: ed25519_dalek::SigningKey,
+            created: chrono::DateTime<chrono::Utc>,
+        }
+
+        let mut user = UserIdentity::build("alice").unwrap();
+        user.identity.extensions = None;
+        let old = OldUserIdentity {
+            identity: user.identity.clone(),
+            private_key: user.private_key.clone(),
+            created: user.created,
+        };
+        let mut loaded: UserIdentity =
+            rmp_serde::from_slice(&rmp_serde::to_vec_named(&old).unwrap()).unwrap();
+        assert!(loaded.identity.prekey_bundle().is_err());
+
+        assert!(loaded.refresh_prekeys(chrono::Utc::now()));
+        assert!(loaded.identity.prekey_bundle().is_ok());
+        assert!(!loaded.refresh_prekeys(chrono::Utc::now()));
+    }
+
+    #[test]
+    fn header_with_other_version_or_huge_costs_is_refused() {
+        let mut header = STORAGE_MAGIC.to_vec();
+        header.extend_from_slice(&(STORAGE_VERSION + 1).to_be_bytes());
+        header.resize(HEADER_LEN, 0);
+        assert!(matches!(
+            read_header(&header),
+            Err(LoadError::VersionMismatch { .. })
+        ));
+
+        let mut header = STORAGE_MAGIC.to_vec();
+        header.extend_from_slice(&STORAGE_VERSION.to_be_bytes());
+        for cost in [MAX_M_COST + 1, 1, 1] {
+            header.extend_from_slice(&cost.to_be_bytes());
+        }
+        header.resize(HEADER_LEN, 0);
+        assert!(matches!(
+            read_header(&header),
+            Err(LoadError::NotAStateFile)
+        ));
+    }
+}
@@ -2,4 +2,4 @@ use crate::state::AppStateRef;
 
 use gtk::Application;
 
-pub(super) fn register_actions(app: &Application, state: AppStateRef) {}
+pub(super) fn register_actions(_app: &Application, _state: AppStateRef) {}
@@ -5,10 +5,15 @@ use std::{
 
 use super::ids::*;
 use super::macros::simple_action;
-use crate::{gui::connect::dialog_connect, state::AppStateRef};
+use crate::{
+    gui::connect::{
+        dialog_connect, dialog_connect_relay, dialog_disconnect, dialog_listener_access,
+        dialog_rendezvous,
+    },
+    state::AppStateRef,
+};
 
 use gtk::{Application, prelude::*};
-use log::warn;
 use sremp_core::net::NetworkCommand;
 
 pub(super) fn register_actions(app: &Application, state: AppStateRef) {
@@ -22,13 +27,45 @@ pub(super) fn register_actions(app: &Application, state: AppStateRef) {
     simple_action!(app, state, app_c, state_c, A_ID_CONNECTION_CONNECT!(), {
         dialog_connect(&app_c.clone(), state_c.clone());
     });
+    simple_action!(app, state, app_c, state_c, A_ID_CONNECTION_RELAY!(), {
+        dialog_connect_relay(&app_c.clone(), state_c.clone());
+    });
+    simple_action!(app, state, app_c, state_c, A_ID_CONNECTION_DISCONNECT!(), {
+        dialog_disconnect(&app_c.clone(), state_c.clone());
+    });
+    simple_action!(app, state, app_c, state_c, A_ID_CONNECTION_ACCESS!(), {
+        dialog_listener_access(&app_c, state_c.clone());
+    });
+    simple_action!(app, state, app_c, state_c, A_ID_CONNECTION_RENDEZVOUS!(), {
+        dialog_rendezvous(&app_c, state_c.clone());
+    });
     simple_action!(
         app,
         state,
         _app_c,
-        _state_c,
-        A_ID_CONNECTION_DISCONNECT!(),
-        { warn!("Disconnecting is not yet implemented") }
+        state_c,
+        A_ID_CONNECTION_KEEP_CONNECTED!(),
+        {
+            let state = state_c.borrow();
+            let Some(chat) = state.selected_chat() else {
+                log::warn!("Select a chat to keep its contact connected");
+                return;
+            };
+            let contact = chat.contact();
+            let pinned = !contact.pinned;
+            let result = state
+                .core_mut()
+                .set_pinned(&contact.identity.public_key, pinned);
+            if let Err(e) = result.and_then(|()| state.save()) {
+                log::error!("Could not change if the contact is kept connected: {e}");
+                return;
+            }
+            log::info!(
+                "{} {} kept connected now",
+                contact.identity.username(),
+                if pinned { "is" } else { "is not" }
+            );
+        }
     );
 }
 
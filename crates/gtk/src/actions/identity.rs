@@ -1,6 +1,16 @@
 use super::ids::*;
 use super::macros::simple_action;
-use crate::{gui::identity::dialog_create_identity, state::AppStateRef};
+use crate::{
+    gui::{
+        identity::{
+            dialog_create_identity, dialog_verify_contact, has_user_identity,
+            show_contact_identity, show_user_identity,
+        },
+        passphrase::dialog_change_passphrase,
+        requests::dialog_contact_requests,
+    },
+    state::AppStateRef,
+};
 
 use gtk::{Application, prelude::*};
 
@@ -8,4 +18,59 @@ pub(super) fn register_actions(app: &Application, state: AppStateRef) {
     simple_action!(app, state, app_c, state_c, A_ID_IDENTITY_CREATE!(), {
         dialog_create_identity(&app_c, state_c.clone());
     });
+    simple_action!(
+        app,
+        state,
+        app_c,
+        state_c,
+        A_ID_IDENTITY_CHANGE_PASSPHRASE!(),
+        {
+            dialog_change_passphrase(&app_c, state_c.clone());
+        }
+    );
+    simple_action!(app, state, app_c, state_c, A_ID_IDENTITY_SHOW_USER!(), {
+        if !has_user_identity(&state_c) {
+            log::warn!("There is no identity to show yet");
+            return;
+        }
+        let user = state_c.borrow().core().user_identity.clone();
+        if let Some(user) = user {
+            show_user_identity(&app_c, user);
+        }
+    });
+    simple_action!(app, state, app_c, state_c, A_ID_IDENTITY_SHOW_CONTACT!(), {
+        let selected = state_c.borrow().selected_chat();
+        match selected {
+            Some(chat) => show_contact_identity(&app_c, chat.contact()),
+            None => log::warn!("Select a chat to show its contact"),
+        }
+    });
+    simple_action!(
+        app,
+        state,
+        app_c,
+        state_c,
+        A_ID_IDENTITY_VERIFY_CONTACT!(),
+        {
+            let selected = state_c.borrow().selected_chat();
+            match selected {
+                Some(chat) => dialog_verify_contact(
+                    &app_c,
+                    state_c.clone(),
+                    chat.contact().identity.public_key,
+                ),
+                None => log::warn!("Select a chat to verify its contact"),
+            }
+        }
+    );
+    simple_action!(
+        app,
+        state,
+        app_c,
+        state_c,
+        A_ID_IDENTITY_CONTACT_REQUESTS!(),
+        {
+            dialog_contact_requests(&app_c, state_c.clone());
+        }
+    );
 }
@@ -49,16 +49,27 @@ pub(crate) mod ids {
     );
     aid!(A_ID_SETTINGS_DELETE_IDENTITY, "settings.delete_identity");
     aid!(A_ID_SETTINGS_DELETE_CHATS, "settings.delete_chats");
+    aid!(A_ID_SETTINGS_READ_RECEIPTS, "settings.read_receipts");
 
     aid!(A_ID_CONNECTION_LISTEN, "connection.listen");
     aid!(A_ID_CONNECTION_CONNECT, "connection.connect");
+    aid!(A_ID_CONNECTION_RELAY, "connection.relay");
     aid!(A_ID_CONNECTION_DISCONNECT, "connection.disconnect");
+    aid!(A_ID_CONNECTION_ACCESS, "connection.access");
+    aid!(A_ID_CONNECTION_RENDEZVOUS, "connection.rendezvous");
+    aid!(A_ID_CONNECTION_KEEP_CONNECTED, "connection.keep_connected");
 
     aid!(A_ID_INFO, "info");
 
     aid!(A_ID_IDENTITY_CREATE, "identity.create");
     aid!(A_ID_IDENTITY_SHOW_USER, "identity.show_user");
-    aid!(A_ID_IDENTITY_SHOW_CONTACT, "identity.show_user");
+    aid!(
+        A_ID_IDENTITY_CHANGE_PASSPHRASE,
+        "identity.change_passphrase"
+    );
+    aid!(A_ID_IDENTITY_SHOW_CONTACT, "identity.show_contact");
+    aid!(A_ID_IDENTITY_VERIFY_CONTACT, "identity.verify_contact");
+    aid!(A_ID_IDENTITY_CONTACT_REQUESTS, "identity.contact_requests");
 }
 
 pub(super) fn register_actions(app: &Application, state: AppStateRef) {
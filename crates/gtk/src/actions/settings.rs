@@ -4,8 +4,29 @@ use crate::state::AppStateRef;
 
 use gtk::{Application, prelude::*};
 
-pub(super) fn register_actions(app: &Application, _state: AppStateRef) {
+pub(super) fn register_actions(app: &Application, state: AppStateRef) {
     simple_action!(app, A_ID_SETTINGS_DELETE_EVERYTHING!(), {
         println!("Delete Everything!");
     });
+
+    let read_receipts = state.borrow().core().settings.read_receipts;
+    let action = gtk::gio::SimpleAction::new_stateful(
+        A_ID_SETTINGS_READ_RECEIPTS!(),
+        None,
+        &read_receipts.to_variant(),
+    );
+    action.connect_activate(move |action, _| {
+        let enabled = !action
+            .state()
+            .and_then(|v| v.get::<bool>())
+            .unwrap_or(read_receipts);
+        action.set_state(&enabled.to_variant());
+
+        let state = state.borrow();
+        state.core_mut().settings.read_receipts = enabled;
+        if let Err(e) = state.save() {
+            log::error!("Could not save the settings: {e}");
+        }
+    });
+    app.add_action(&action);
 }
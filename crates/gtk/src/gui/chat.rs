@@ -2,7 +2,7 @@ use std::ops::Deref;
 
 use chrono::Utc;
 use gtk::prelude::*;
-use sremp_core::chat::messages::{Message, MessageText};
+use sremp_core::chat::messages::{DeliveryState, Message, MessageText};
 use sremp_core::identity::ContactIdentity;
 use sremp_core::net::NetworkCommand;
 
@@ -33,12 +33,20 @@ impl MessageBubble {
 
         let state_b = state.borrow();
         let core = state_b.core();
-        let author = match core.known_identities.get(&self.meta().author_key) {
-            Some(a) => a,
-            None => panic!("unknwon author: {:?}", self.meta().author_key.to_bytes()),
+        let author_key = self.meta().author_key;
+        let is_own = core
+            .user_identity
+            .as_ref()
+            .is_some_and(|user| user.identity.public_key == author_key);
+        let author = match core.known_identities.get(&author_key) {
+            Some(a) => &a.identity,
+            None => match &core.user_identity {
+                Some(user) if user.identity.public_key == author_key => &user.identity,
+                _ => panic!("unknwon author: {:?}", author_key.to_bytes()),
+            },
         };
 
-        let w_lbl_author = label(&author.identity.username);
+        let w_lbl_author = label(author.username());
         drop(core);
         drop(state_b);
         let w_lbl_time = label(self.meta().time_received);
@@ -48,6 +56,17 @@ impl MessageBubble {
 
         w_meta_box.append(&w_lbl_author);
         w_meta_box.append(&w_lbl_time);
+        if is_own {
+            let w_lbl_delivery = label("");
+            set_delivery(&w_lbl_delivery, self.meta().delivery);
+            w_lbl_delivery.set_margin_start(GUI_SPACING_XLARGE);
+            w_meta_box.append(&w_lbl_delivery);
+            // the event processor updates the ticks while the chat is open
+            state
+                .borrow_mut()
+                .tracked_widgets
+                .set_lbl_delivery(self.meta().id, w_lbl_delivery);
+        }
 
         w_meta_box.set_margin_top(GUI_SPACING_MID);
         w_meta_box.set_margin_bottom(GUI_SPACING_MID);
@@ -89,6 +108,23 @@ impl MessageBubble {
     }
 }
 
+/// Shows the [`DeliveryState`] of one of our messages on its label
+pub(crate) fn set_delivery(w_lbl_delivery: &gtk::Label, delivery: DeliveryState) {
+    w_lbl_delivery.set_text(delivery_ticks(delivery));
+    w_lbl_delivery.set_tooltip_text(Some(&delivery.to_string()));
+}
+
+/// Short indicator for the [`DeliveryState`] of one of our messages
+fn delivery_ticks(delivery: DeliveryState) -> &'static str {
+    match delivery {
+        DeliveryState::Failed => "✗",
+        DeliveryState::Pending => "…",
+        DeliveryState::Sent => "✓",
+        DeliveryState::Delivered => "✓✓",
+        DeliveryState::Read => "✓✓ read",
+    }
+}
+
 impl Deref for MessageBubble {
     type Target = Message;
 
@@ -150,7 +186,7 @@ pub(crate) fn widget_viewport_chat(
     vp_chat
 }
 
-fn widget_input_area(app: &gtk::Application, state: AppStateRef) -> impl IsA<gtk::Widget> {
+fn widget_input_area(_app: &gtk::Application, state: AppStateRef) -> impl IsA<gtk::Widget> {
     let w_frame = gtk::Frame::builder()
         .margin_top(GUI_SPACING_MID)
         .margin_bottom(GUI_SPACING_MID)
@@ -229,19 +265,20 @@ fn widget_input_area(app: &gtk::Application, state: AppStateRef) -> impl IsA<gtk
                 .borrow()
                 .selected_chat()
                 .expect("no chat is selected?");
-            let msg = Message::new_text(text, Utc::now(), chat.contact().identity.public_key);
+            let user_key = state
+                .borrow()
+                .core()
+                .user_identity
+                .as_ref()
+                .expect("no user identity exists")
+                .identity
+                .public_key;
+            let msg = Message::new_text(text, Utc::now(), user_key);
+            // the message waits in the outbox until the contact is connected
             state
                 .borrow()
                 .command_channel
-                .send_blocking(NetworkCommand::SendMessage(
-                    state
-                        .borrow()
-                        .core()
-                        .find_socket_addr_for_chat(&chat)
-                        .expect("chat has no open connection"),
-                    chat.contact().clone(),
-                    msg,
-                ))
+                .send_blocking(NetworkCommand::SendMessage(chat.contact().clone(), msg))
                 .expect("could push send message command");
             tb.set_text("");
         }
@@ -1,3 +1,4 @@
+use ed25519_dalek::VerifyingKey;
 use gtk::prelude::*;
 use sremp_core::chat::Chat;
 
@@ -10,7 +11,7 @@ pub(crate) fn widget_chats_list(
     state: AppStateRef,
 ) -> impl IsA<gtk::Widget> {
     let w_list = gtk::ListBox::builder()
-        .selection_mode(gtk::SelectionMode::None)
+        .selection_mode(gtk::SelectionMode::Single)
         .build();
 
     if state.borrow().core().chats.is_empty() {
@@ -34,10 +35,28 @@ pub(crate) fn widget_chats_list(
                 .build(),
         );
     } else {
-        for chat in state.borrow().core().chats.values() {
+        // the rows are in the same order as the keys
+        let mut keys = Vec::new();
+        // the cards register their widgets in the state, so it must not be borrowed meanwhile
+        let chats: Vec<(VerifyingKey, Chat)> = state
+            .borrow()
+            .core()
+            .chats
+            .iter()
+            .map(|(key, chat)| (*key, chat.clone()))
+            .collect();
+        for (key, chat) in &chats {
             let w_chat_card = widget_chat_card(app, state.clone(), chat);
             w_list.append(&w_chat_card);
+            keys.push(*key);
         }
+
+        w_list.connect_row_selected(move |_, row| {
+            let key = row.and_then(|row| usize::try_from(row.index()).ok().map(|i| keys[i]));
+            if let Err(e) = state.borrow_mut().set_selected_chat(key) {
+                log::error!("Could not select the chat: {e}");
+            }
+        });
     }
 
     gtk::Frame::builder()
@@ -51,9 +70,10 @@ pub(crate) fn widget_chats_list(
 
 pub(crate) fn widget_chat_card(
     _app: &gtk::Application,
-    _state: AppStateRef,
+    state: AppStateRef,
     chat: &Chat,
 ) -> impl IsA<gtk::Widget> {
+    let contact_key = chat.contact().identity.public_key;
     let w_box = gtk::Box::builder()
         .orientation(gtk::Orientation::Vertical)
         .margin_top(GUI_SPACING_LARGE)
@@ -64,6 +84,20 @@ pub(crate) fn widget_chat_card(
 
     w_box.append(&label(chat.contact().identity.username().to_string()));
 
+    let connected = state
+        .borrow()
+        .core()
+        .active_connections
+        .find_socket_addr_for_contact(&contact_key)
+        .is_some();
+    let w_status = label(if connected { "Connected" } else { "Offline" });
+    w_status.add_css_class("dim-label");
+    w_box.append(&w_status);
+    state
+        .borrow_mut()
+        .tracked_widgets
+        .set_lbl_chat_status(contact_key, w_status);
+
     gtk::Frame::builder()
         .margin_top(GUI_SPACING_MID)
         .margin_bottom(GUI_SPACING_MID)
@@ -1,15 +1,66 @@
 use crate::{gui::label, state::AppStateRef, utils::GUI_SPACING_MID};
 
+use ed25519_dalek::VerifyingKey;
 use gtk::prelude::*;
-use sremp_core::net::NetworkCommand;
+use sremp_core::{
+    identity::{format_key, parse_key},
+    net::{AcceptPolicy, ListenerAccess, NetworkCommand, parse_network},
+};
 
 pub(crate) fn dialog_connect(app: &gtk::Application, state: AppStateRef) {
+    let contacts = if state.borrow().core().settings.rendezvous.is_some() {
+        let mut contacts: Vec<(VerifyingKey, String)> = state
+            .borrow()
+            .core()
+            .chats
+            .iter()
+            .map(|(key, chat)| (*key, chat.contact().identity.username().to_string()))
+            .collect();
+        contacts.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.as_bytes().cmp(b.0.as_bytes())));
+        contacts
+    } else {
+        // contacts can only be found with a rendezvous server
+        Vec::new()
+    };
+    dialog_connect_to(
+        app,
+        state,
+        "Establish a new Connection",
+        "51673",
+        NetworkCommand::Connect,
+        contacts,
+    );
+}
+
+pub(crate) fn dialog_connect_relay(app: &gtk::Application, state: AppStateRef) {
+    dialog_connect_to(
+        app,
+        state,
+        "Connect to a Relay",
+        "7117",
+        NetworkCommand::ConnectRelay,
+        Vec::new(),
+    );
+}
+
+/// Asks for an address, and sends the command made from it.
+///
+/// If `contacts` are given, one of them can be picked instead, it is then looked up on the
+/// rendezvous server.
+fn dialog_connect_to(
+    app: &gtk::Application,
+    state: AppStateRef,
+    title: &str,
+    default_port: &str,
+    command: fn(std::net::SocketAddr) -> NetworkCommand,
+    contacts: Vec<(VerifyingKey, String)>,
+) {
     let win_dialog = gtk::Window::builder()
         .modal(true)
         .default_width(300)
         .default_height(150)
         .resizable(false)
-        .title("Establish a new Connection")
+        .title(title)
         .build();
 
     if let Some(window) = app.active_window() {
@@ -36,8 +87,8 @@ pub(crate) fn dialog_connect(app: &gtk::Application, state: AppStateRef) {
         .build();
 
     let w_port_entry = gtk::Entry::builder()
-        .placeholder_text("51673")
-        .text("51673")
+        .placeholder_text(default_port)
+        .text(default_port)
         .build();
 
     let w_box_btn = gtk::Box::builder()
@@ -53,14 +104,31 @@ pub(crate) fn dialog_connect(app: &gtk::Application, state: AppStateRef) {
     w_box_btn.append(&w_btn_cancel);
     w_box_btn.append(&w_btn_accept);
 
-    w_grid.attach(&label("Host"), 0, 0, 1, 1);
-    w_grid.attach(&w_host_entry, 1, 0, 1, 1);
-    w_grid.attach(&label("Port"), 0, 1, 1, 1);
-    w_grid.attach(&w_port_entry, 1, 1, 1, 1);
+    let mut targets = vec!["Address below"];
+    targets.extend(contacts.iter().map(|(_key, username)| username.as_str()));
+    let w_target = gtk::DropDown::from_strings(&targets);
+    let w_host_entry_clone = w_host_entry.clone();
+    let w_port_entry_clone = w_port_entry.clone();
+    w_target.connect_selected_notify(move |w_target| {
+        let by_address = w_target.selected() == 0;
+        w_host_entry_clone.set_sensitive(by_address);
+        w_port_entry_clone.set_sensitive(by_address);
+    });
+
+    let w_lbl_target = label("Contact");
+    w_lbl_target.set_visible(!contacts.is_empty());
+    w_target.set_visible(!contacts.is_empty());
+
+    w_grid.attach(&w_lbl_target, 0, 0, 1, 1);
+    w_grid.attach(&w_target, 1, 0, 1, 1);
+    w_grid.attach(&label("Host"), 0, 1, 1, 1);
+    w_grid.attach(&w_host_entry, 1, 1, 1, 1);
+    w_grid.attach(&label("Port"), 0, 2, 1, 1);
+    w_grid.attach(&w_port_entry, 1, 2, 1, 1);
 
     let w_error = label("undefined error");
     w_error.set_visible(false);
-    w_grid.attach(&w_error, 0, 2, 2, 1);
+    w_grid.attach(&w_error, 0, 3, 2, 1);
 
     w_box.append(&w_grid);
     w_box.append(&w_box_btn);
@@ -84,21 +152,421 @@ pub(crate) fn dialog_connect(app: &gtk::Application, state: AppStateRef) {
             w_error_clone.set_visible(true);
         };
 
+        // the first entry is the address below
+        let contact = usize::try_from(w_target.selected())
+            .ok()
+            .and_then(|idx| idx.checked_sub(1))
+            .and_then(|idx| contacts.get(idx));
+        if let Some((key, _username)) = contact {
+            let state = state.borrow();
+            if let Err(e) = state
+                .command_channel
+                .send_blocking(NetworkCommand::ConnectToContact(*key))
+            {
+                handle_error(format!("Could not connect to contact: {e}"))
+            } else {
+                win_dialog_clone.close();
+            }
+            return;
+        }
+
         match format!("{raw_host}:{raw_port}").parse::<std::net::SocketAddr>() {
             Ok(remote) => {
+                let state = state.borrow();
+                if let Err(e) = state.command_channel.send_blocking(command(remote)) {
+                    handle_error(format!("Could not connect to remove: {e}"))
+                } else {
+                    win_dialog_clone.close();
+                }
+            }
+            Err(e) => handle_error(format!("Could not parse remote address: {e}")),
+        }
+    });
+
+    win_dialog.present();
+}
+
+pub(crate) fn dialog_disconnect(app: &gtk::Application, state: AppStateRef) {
+    let win_dialog = gtk::Window::builder()
+        .modal(true)
+        .default_width(300)
+        .default_height(150)
+        .resizable(false)
+        .title("Close a Connection")
+        .build();
+
+    if let Some(window) = app.active_window() {
+        win_dialog.set_transient_for(Some(&window));
+    }
+
+    let w_box = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .spacing(GUI_SPACING_MID)
+        .margin_top(GUI_SPACING_MID)
+        .margin_bottom(GUI_SPACING_MID)
+        .margin_start(GUI_SPACING_MID)
+        .margin_end(GUI_SPACING_MID)
+        .build();
+
+    let mut connections: Vec<(std::net::SocketAddr, String)> = state
+        .borrow()
+        .core()
+        .active_connections
+        .iter()
+        .map(|(remote, data)| (*remote, data.iden.username().to_string()))
+        .collect();
+    connections.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+
+    let w_list = gtk::ListBox::builder()
+        .selection_mode(gtk::SelectionMode::Single)
+        .build();
+
+    if connections.is_empty() {
+        w_list.set_selection_mode(gtk::SelectionMode::None);
+        w_list.append(&label("No active connections"));
+    }
+    for (remote, username) in &connections {
+        let w_lbl = label(format!("{username} ({remote})"));
+        w_lbl.set_halign(gtk::Align::Start);
+        w_lbl.set_margin_top(GUI_SPACING_MID);
+        w_lbl.set_margin_bottom(GUI_SPACING_MID);
+        w_lbl.set_margin_start(GUI_SPACING_MID);
+        w_lbl.set_margin_end(GUI_SPACING_MID);
+        w_list.append(&w_lbl);
+    }
+
+    let w_box_btn = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(6)
+        .halign(gtk::Align::End)
+        .build();
+
+    let w_btn_cancel = gtk::Button::builder().label("Cancel").build();
+    let w_btn_accept = gtk::Button::builder().label("Disconnect").build();
+    w_btn_accept.add_css_class("destructive-action");
+    w_btn_accept.set_sensitive(!connections.is_empty());
+
+    w_box_btn.append(&w_btn_cancel);
+    w_box_btn.append(&w_btn_accept);
+
+    let w_error = label("undefined error");
+    w_error.set_visible(false);
+
+    w_box.append(&gtk::Frame::builder().child(&w_list).build());
+    w_box.append(&w_error);
+    w_box.append(&w_box_btn);
+
+    win_dialog.set_child(Some(&w_box));
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_cancel.connect_clicked(move |_| {
+        win_dialog_clone.close();
+    });
+
+    let win_dialog_clone = win_dialog.clone();
+    let w_error_clone = w_error.clone();
+
+    w_btn_accept.connect_clicked(move |_| {
+        let handle_error = |reason: String| {
+            w_error_clone.set_text(&reason);
+            w_error_clone.set_visible(true);
+        };
+
+        let selected = w_list
+            .selected_row()
+            .and_then(|row| usize::try_from(row.index()).ok())
+            .and_then(|idx| connections.get(idx));
+
+        match selected {
+            Some((remote, _username)) => {
                 let state = state.borrow();
                 if let Err(e) = state
                     .command_channel
-                    .send_blocking(NetworkCommand::Connect(remote))
+                    .send_blocking(NetworkCommand::Disconnect(*remote))
                 {
-                    handle_error(format!("Could not connect to remove: {e}"))
+                    handle_error(format!("Could not disconnect from remote: {e}"))
                 } else {
                     win_dialog_clone.close();
                 }
             }
-            Err(e) => handle_error(format!("Could not parse remote address: {e}")),
+            None => handle_error("Select a connection to close".to_string()),
         }
     });
 
     win_dialog.present();
 }
+
+/// Creates and shows a dialog for choosing which peers may connect to the listener
+pub(crate) fn dialog_listener_access(app: &gtk::Application, state: AppStateRef) {
+    let access = state.borrow().core().settings.listener_access.clone();
+
+    let win_dialog = gtk::Window::builder()
+        .modal(true)
+        .default_width(500)
+        .default_height(400)
+        .title("Listener Access")
+        .build();
+
+    if let Some(window) = app.active_window() {
+        win_dialog.set_transient_for(Some(&window));
+    }
+
+    let w_box = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .spacing(GUI_SPACING_MID)
+        .margin_top(GUI_SPACING_MID)
+        .margin_bottom(GUI_SPACING_MID)
+        .margin_start(GUI_SPACING_MID)
+        .margin_end(GUI_SPACING_MID)
+        .build();
+
+    const POLICIES: [AcceptPolicy; 3] = [
+        AcceptPolicy::Open,
+        AcceptPolicy::KnownContacts,
+        AcceptPolicy::Allowlist,
+    ];
+    let policy_names: Vec<String> = POLICIES.iter().map(ToString::to_string).collect();
+    let w_policy =
+        gtk::DropDown::from_strings(&policy_names.iter().map(String::as_str).collect::<Vec<_>>());
+    let selected = POLICIES
+        .iter()
+        .position(|policy| *policy == access.policy)
+        .unwrap_or_default();
+    w_policy.set_selected(u32::try_from(selected).unwrap_or_default());
+
+    let mut allowlist: Vec<String> = access.allowlist.iter().map(format_key).collect();
+    allowlist.sort();
+    let w_allowlist = widget_lines_editor(&allowlist);
+    let denied: Vec<String> = access
+        .denied_networks
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let w_denied = widget_lines_editor(&denied);
+
+    let w_error = label("undefined error");
+    w_error.set_visible(false);
+    w_error.add_css_class("error");
+
+    let w_box_btn = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(6)
+        .halign(gtk::Align::End)
+        .build();
+
+    let w_btn_cancel = gtk::Button::builder().label("Cancel").build();
+    let w_btn_save = gtk::Button::builder().label("Save").build();
+    w_btn_save.add_css_class("suggested-action");
+
+    w_box_btn.append(&w_btn_cancel);
+    w_box_btn.append(&w_btn_save);
+
+    let w_lbl_policy = label("Accept connections from");
+    w_lbl_policy.set_halign(gtk::Align::Start);
+    let w_lbl_allowlist = label("Allowlist, one key per line");
+    w_lbl_allowlist.set_halign(gtk::Align::Start);
+    let w_lbl_denied = label("Denied networks, one per line, like 192.0.2.0/24");
+    w_lbl_denied.set_halign(gtk::Align::Start);
+
+    w_box.append(&w_lbl_policy);
+    w_box.append(&w_policy);
+    w_box.append(&w_lbl_allowlist);
+    w_box.append(&gtk::Frame::builder().child(&w_allowlist.0).build());
+    w_box.append(&w_lbl_denied);
+    w_box.append(&gtk::Frame::builder().child(&w_denied.0).build());
+    w_box.append(&w_error);
+    w_box.append(&w_box_btn);
+
+    win_dialog.set_child(Some(&w_box));
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_cancel.connect_clicked(move |_| {
+        win_dialog_clone.close();
+    });
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_save.connect_clicked(move |_| {
+        let handle_error = |reason: String| {
+            w_error.set_text(&reason);
+            w_error.set_visible(true);
+        };
+
+        let policy = POLICIES
+            .get(usize::try_from(w_policy.selected()).unwrap_or(usize::MAX))
+            .copied()
+            .unwrap_or_default();
+        let allowlist = match lines(&w_allowlist.1)
+            .iter()
+            .map(|line| parse_key(line))
+            .collect()
+        {
+            Ok(allowlist) => allowlist,
+            Err(e) => return handle_error(e.to_string()),
+        };
+        let denied_networks = match lines(&w_denied.1)
+            .iter()
+            .map(|line| parse_network(line))
+            .collect()
+        {
+            Ok(denied_networks) => denied_networks,
+            Err(e) => return handle_error(e.to_string()),
+        };
+
+        let state = state.borrow();
+        state.core_mut().settings.listener_access = ListenerAccess {
+            policy,
+            allowlist,
+            denied_networks,
+        };
+        if let Err(e) = state.save() {
+            return handle_error(format!("Could not save the settings: {e}"));
+        }
+        win_dialog_clone.close();
+    });
+
+    win_dialog.present();
+}
+
+/// Creates and shows a dialog for setting the rendezvous server, where the listener is
+/// registered and contacts are looked up
+pub(crate) fn dialog_rendezvous(app: &gtk::Application, state: AppStateRef) {
+    let server = state.borrow().core().settings.rendezvous;
+
+    let win_dialog = gtk::Window::builder()
+        .modal(true)
+        .default_width(300)
+        .default_height(150)
+        .resizable(false)
+        .title("Rendezvous Server")
+        .build();
+
+    if let Some(window) = app.active_window() {
+        win_dialog.set_transient_for(Some(&window));
+    }
+
+    let w_box = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .spacing(GUI_SPACING_MID)
+        .margin_top(GUI_SPACING_MID)
+        .margin_bottom(GUI_SPACING_MID)
+        .margin_start(GUI_SPACING_MID)
+        .margin_end(GUI_SPACING_MID)
+        .build();
+
+    let w_grid = gtk::Grid::builder()
+        .row_spacing(6)
+        .column_spacing(12)
+        .build();
+
+    let w_host_entry = gtk::Entry::builder()
+        .placeholder_text("none")
+        .text(
+            server
+                .map(|server| server.ip().to_string())
+                .unwrap_or_default(),
+        )
+        .hexpand(true)
+        .build();
+
+    let w_port_entry = gtk::Entry::builder()
+        .placeholder_text("7118")
+        .text(
+            server
+                .map(|server| server.port().to_string())
+                .unwrap_or_else(|| "7118".to_string()),
+        )
+        .build();
+
+    let w_box_btn = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(6)
+        .halign(gtk::Align::End)
+        .build();
+
+    let w_btn_cancel = gtk::Button::builder().label("Cancel").build();
+    let w_btn_save = gtk::Button::builder().label("Save").build();
+    w_btn_save.add_css_class("suggested-action");
+
+    w_box_btn.append(&w_btn_cancel);
+    w_box_btn.append(&w_btn_save);
+
+    let w_lbl_info = label("Leave the host empty to use no rendezvous server");
+    w_lbl_info.set_halign(gtk::Align::Start);
+
+    w_grid.attach(&label("Host"), 0, 0, 1, 1);
+    w_grid.attach(&w_host_entry, 1, 0, 1, 1);
+    w_grid.attach(&label("Port"), 0, 1, 1, 1);
+    w_grid.attach(&w_port_entry, 1, 1, 1, 1);
+
+    let w_error = label("undefined error");
+    w_error.set_visible(false);
+    w_error.add_css_class("error");
+
+    w_box.append(&w_lbl_info);
+    w_box.append(&w_grid);
+    w_box.append(&w_error);
+    w_box.append(&w_box_btn);
+
+    win_dialog.set_child(Some(&w_box));
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_cancel.connect_clicked(move |_| {
+        win_dialog_clone.close();
+    });
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_save.connect_clicked(move |_| {
+        let handle_error = |reason: String| {
+            w_error.set_text(&reason);
+            w_error.set_visible(true);
+        };
+
+        let raw_host = w_host_entry.text().trim().to_string();
+        let raw_port = w_port_entry.text().trim().to_string();
+        let server = if raw_host.is_empty() {
+            None
+        } else {
+            let raw_host = if raw_host.contains(':') {
+                format!("[{raw_host}]")
+            } else {
+                raw_host
+            };
+            match format!("{raw_host}:{raw_port}").parse::<std::net::SocketAddr>() {
+                Ok(server) => Some(server),
+                Err(e) => return handle_error(format!("Could not parse the address: {e}")),
+            }
+        };
+
+        let state = state.borrow();
+        state.core_mut().settings.rendezvous = server;
+        if let Err(e) = state.save() {
+            return handle_error(format!("Could not save the settings: {e}"));
+        }
+        win_dialog_clone.close();
+    });
+
+    win_dialog.present();
+}
+
+/// A scrollable text field holding `content` as lines, and its buffer
+fn widget_lines_editor(content: &[String]) -> (gtk::ScrolledWindow, gtk::TextBuffer) {
+    let w_text = gtk::TextView::builder().monospace(true).build();
+    let buffer = w_text.buffer();
+    buffer.set_text(&content.join("\n"));
+    let w_scroll = gtk::ScrolledWindow::builder()
+        .child(&w_text)
+        .min_content_height(100)
+        .vexpand(true)
+        .build();
+    (w_scroll, buffer)
+}
+
+/// The non-empty lines of `buffer`
+fn lines(buffer: &gtk::TextBuffer) -> Vec<String> {
+    let text = buffer.text(&buffer.start_iter(), &buffer.end_iter(), false);
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
@@ -1,7 +1,17 @@
+use ed25519_dalek::VerifyingKey;
 use gtk::prelude::*;
-use sremp_core::identity::{ContactIdentity, UserIdentity, format_key};
-
-use crate::{gui::label, state::AppStateRef, utils::GUI_SPACING_MID};
+use qrcode::QrCode;
+use sremp_core::crypto::safety_number::SafetyNumber;
+use sremp_core::identity::{ContactIdentity, Trust, UserIdentity, format_key};
+
+use crate::{
+    gui::{
+        label,
+        passphrase::{check_new_passphrase, passphrase_entry},
+    },
+    state::AppStateRef,
+    utils::GUI_SPACING_MID,
+};
 
 /// Creates and shows a dialog for creating a new user identity
 pub(crate) fn dialog_create_identity(app: &gtk::Application, state: AppStateRef) {
@@ -31,12 +41,20 @@ pub(crate) fn dialog_create_identity(app: &gtk::Application, state: AppStateRef)
         .margin_end(GUI_SPACING_MID)
         .build();
 
+    // the passphrase is chosen together with the first identity
+    let needs_passphrase = state.borrow().storage_is_locked();
+
     // Description text
-    let w_description = label(
+    let w_description = label(if needs_passphrase {
+        "Choose a username for your SREMP identity.\n\
+        A cryptographic keypair will be generated for you.\n\
+        Your username should have between 1 and 40 characters.\n\
+        Your identity and chats are encrypted with the passphrase."
+    } else {
         "Choose a username for your SREMP identity.\n\
         A cryptographic keypair will be generated for you.\n\
-        Your username should have between 1 and 40 characters.",
-    );
+        Your username should have between 1 and 40 characters."
+    });
     w_description.set_halign(gtk::Align::Start);
     w_description.set_margin_bottom(GUI_SPACING_MID);
 
@@ -64,15 +82,24 @@ pub(crate) fn dialog_create_identity(app: &gtk::Application, state: AppStateRef)
     w_box_btn.append(&w_btn_cancel);
     w_box_btn.append(&w_btn_create);
 
+    let w_passphrase_entry = passphrase_entry();
+    let w_confirm_entry = passphrase_entry();
+
     w_grid.attach(&label("Username"), 0, 0, 1, 1);
     w_grid.attach(&w_username_entry, 1, 0, 1, 1);
+    if needs_passphrase {
+        w_grid.attach(&label("Passphrase"), 0, 1, 1, 1);
+        w_grid.attach(&w_passphrase_entry, 1, 1, 1, 1);
+        w_grid.attach(&label("Repeat passphrase"), 0, 2, 1, 1);
+        w_grid.attach(&w_confirm_entry, 1, 2, 1, 1);
+    }
 
     // Error label (initially hidden)
     let w_error =
         label("Username must be 1-40 characters and contain only letters, numbers, - and _");
     w_error.set_visible(false);
     w_error.add_css_class("error");
-    w_grid.attach(&w_error, 0, 1, 2, 1);
+    w_grid.attach(&w_error, 0, 3, 2, 1);
 
     w_box.append(&w_description);
     w_box.append(&w_grid);
@@ -121,6 +148,14 @@ pub(crate) fn dialog_create_identity(app: &gtk::Application, state: AppStateRef)
             return;
         }
 
+        let passphrase = w_passphrase_entry.text();
+        if needs_passphrase {
+            if let Err(reason) = check_new_passphrase(&passphrase, &w_confirm_entry.text()) {
+                handle_error(reason);
+                return;
+            }
+        }
+
         // Try to create the identity
         match UserIdentity::build(&username) {
             Ok(user_identity) => {
@@ -128,6 +163,14 @@ pub(crate) fn dialog_create_identity(app: &gtk::Application, state: AppStateRef)
                 {
                     let state_ref = state_clone.borrow_mut();
                     state_ref.core_mut().user_identity = Some(user_identity.clone());
+                    let saved = if needs_passphrase {
+                        state_ref.set_passphrase(&passphrase)
+                    } else {
+                        state_ref.save()
+                    };
+                    if let Err(e) = saved {
+                        log::error!("Could not save the new user identity: {e}");
+                    }
                 }
 
                 log::info!(
@@ -184,6 +227,7 @@ fn show_identity_created_success(parent: &gtk::Window, user_identity: UserIdenti
         .title("Identity Created Successfully!")
         .child(&w_box)
         .build();
+    win_dialog.set_transient_for(Some(parent));
 
     let win_dialog_c = win_dialog.clone();
     w_btn_ok.connect_clicked(move |_| {
@@ -268,3 +312,186 @@ pub(crate) fn show_contact_identity(app: &gtk::Application, contact: &ContactIde
 
     win_dialog.present();
 }
+
+/// Creates and shows a dialog for verifying a contact by comparing the safety number, marking
+/// the contact as [`Trust::Trusted`] once the user confirms that the numbers match.
+pub(crate) fn dialog_verify_contact(
+    app: &gtk::Application,
+    state: AppStateRef,
+    contact_key: VerifyingKey,
+) {
+    let (user_key, contact) = {
+        let state_b = state.borrow();
+        let core = state_b.core();
+        let Some(user) = &core.user_identity else {
+            log::warn!("Cannot verify a contact without a user identity");
+            return;
+        };
+        let Some(contact) = core.known_identities.get(&contact_key) else {
+            log::warn!("Cannot verify an unknown contact");
+            return;
+        };
+        (user.identity.public_key, contact.clone())
+    };
+    let safety_number = SafetyNumber::new(&user_key, &contact_key);
+
+    let win_dialog = gtk::Window::builder()
+        .modal(true)
+        .default_width(400)
+        .default_height(500)
+        .resizable(false)
+        .title("Verify Contact")
+        .build();
+
+    if let Some(window) = app.active_window() {
+        win_dialog.set_transient_for(Some(&window));
+    }
+
+    let w_box = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .spacing(GUI_SPACING_MID)
+        .margin_top(GUI_SPACING_MID)
+        .margin_bottom(GUI_SPACING_MID)
+        .margin_start(GUI_SPACING_MID)
+        .margin_end(GUI_SPACING_MID)
+        .build();
+
+    let w_description = label(format!(
+        "Compare the safety number with {} in person or over a channel you trust. \
+         If the numbers match, nobody is listening in on your chat.",
+        contact.identity.username()
+    ));
+    w_description.set_wrap(true);
+    w_description.set_max_width_chars(50);
+
+    let w_safety_number = label(safety_number);
+    w_safety_number.add_css_class("monospace");
+    w_safety_number.set_selectable(true);
+
+    let w_trust = label(format!("Trust: {}", contact.trust));
+    w_trust.add_css_class("dim-label");
+
+    let w_scanned_entry = gtk::Entry::builder()
+        .placeholder_text("Code scanned from your contact")
+        .hexpand(true)
+        .build();
+    let w_btn_check = gtk::Button::builder().label("Check").build();
+    let w_box_scanned = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(6)
+        .build();
+    w_box_scanned.append(&w_scanned_entry);
+    w_box_scanned.append(&w_btn_check);
+
+    let w_error = label("undefined error");
+    w_error.set_visible(false);
+    w_error.add_css_class("error");
+
+    let w_box_btn = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(6)
+        .halign(gtk::Align::End)
+        .build();
+
+    let w_btn_cancel = gtk::Button::builder().label("Cancel").build();
+    let w_btn_verify = gtk::Button::builder().label("Mark as Verified").build();
+    w_btn_verify.add_css_class("suggested-action");
+
+    w_box_btn.append(&w_btn_cancel);
+    w_box_btn.append(&w_btn_verify);
+
+    w_box.append(&w_description);
+    w_box.append(&w_safety_number);
+    w_box.append(&widget_qr_code(&safety_number.qr_payload()));
+    w_box.append(&w_trust);
+    w_box.append(&w_box_scanned);
+    w_box.append(&w_error);
+    w_box.append(&w_box_btn);
+
+    win_dialog.set_child(Some(&w_box));
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_cancel.connect_clicked(move |_| {
+        win_dialog_clone.close();
+    });
+
+    let w_error_c = w_error.clone();
+    w_btn_check.connect_clicked(move |_| {
+        let (text, is_error) = match safety_number.matches_qr_payload(&w_scanned_entry.text()) {
+            Ok(true) => ("The codes match".to_string(), false),
+            Ok(false) => (
+                "The codes do not match, do not verify this contact".to_string(),
+                true,
+            ),
+            Err(e) => (e.to_string(), true),
+        };
+        w_error_c.set_text(&text);
+        if is_error {
+            w_error_c.add_css_class("error");
+        } else {
+            w_error_c.remove_css_class("error");
+        }
+        w_error_c.set_visible(true);
+    });
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_verify.connect_clicked(move |_| {
+        let handle_error = |reason: String| {
+            w_error.add_css_class("error");
+            w_error.set_text(&reason);
+            w_error.set_visible(true);
+        };
+
+        let state_b = state.borrow();
+        let result = state_b.core_mut().set_trust(&contact_key, Trust::Trusted);
+        if let Err(e) = result.and_then(|()| state_b.save()) {
+            handle_error(format!("Could not verify the contact: {e}"));
+            return;
+        }
+        log::info!("Marked {} as verified", format_key(&contact_key));
+        win_dialog_clone.close();
+    });
+
+    win_dialog.present();
+}
+
+/// Draws `payload` as a QR code
+fn widget_qr_code(payload: &str) -> impl IsA<gtk::Widget> {
+    const QR_SIZE: i32 = 200;
+
+    let w_drawing = gtk::DrawingArea::builder()
+        .content_width(QR_SIZE)
+        .content_height(QR_SIZE)
+        .halign(gtk::Align::Center)
+        .build();
+
+    let code = match QrCode::new(payload) {
+        Ok(code) => code,
+        Err(e) => {
+            log::error!("Could not create the QR code: {e}");
+            return w_drawing;
+        }
+    };
+    let modules = code.to_colors();
+    let width = code.width();
+
+    w_drawing.set_draw_func(move |_, cr, w, h| {
+        // leave room for the quiet zone around the code
+        let quiet_zone = 4;
+        let scale = f64::from(w.min(h)) / (width + 2 * quiet_zone) as f64;
+
+        cr.set_source_rgb(1.0, 1.0, 1.0);
+        cr.paint().ok();
+        cr.set_source_rgb(0.0, 0.0, 0.0);
+        for (i, color) in modules.iter().enumerate() {
+            if *color == qrcode::Color::Dark {
+                let x = (i % width + quiet_zone) as f64 * scale;
+                let y = (i / width + quiet_zone) as f64 * scale;
+                cr.rectangle(x, y, scale, scale);
+            }
+        }
+        cr.fill().ok();
+    });
+
+    w_drawing
+}
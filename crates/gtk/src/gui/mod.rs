@@ -9,6 +9,8 @@ pub(crate) mod chat;
 pub(crate) mod chats;
 pub(crate) mod connect;
 pub(crate) mod identity;
+pub(crate) mod passphrase;
+pub(crate) mod requests;
 pub(crate) mod topbar;
 
 use chat::*;
@@ -0,0 +1,212 @@
+use gtk::prelude::*;
+use sremp_core::error::{CoreError, LoadError};
+
+use crate::{gui::label, state::AppStateRef, utils::GUI_SPACING_MID};
+
+/// Creates and shows the dialog that unlocks the stored state at startup.
+///
+/// `on_unlocked` is called once the state was loaded. Closing the dialog before that quits the
+/// application, since it is the only window at that point.
+pub(crate) fn dialog_unlock(
+    app: &gtk::Application,
+    state: AppStateRef,
+    on_unlocked: impl Fn(&gtk::Application, AppStateRef) + 'static,
+) {
+    let win_dialog = gtk::Window::builder()
+        .application(app)
+        .default_width(400)
+        .default_height(150)
+        .resizable(false)
+        .title("Unlock SREMP")
+        .build();
+
+    let w_box = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .spacing(GUI_SPACING_MID)
+        .margin_top(GUI_SPACING_MID)
+        .margin_bottom(GUI_SPACING_MID)
+        .margin_start(GUI_SPACING_MID)
+        .margin_end(GUI_SPACING_MID)
+        .build();
+
+    let w_description = label("Enter your passphrase to unlock your identity and chats.");
+    w_description.set_halign(gtk::Align::Start);
+
+    let w_passphrase_entry = passphrase_entry();
+
+    let w_error = label("undefined error");
+    w_error.set_visible(false);
+    w_error.add_css_class("error");
+
+    let w_box_btn = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(6)
+        .halign(gtk::Align::End)
+        .build();
+
+    let w_btn_quit = gtk::Button::builder().label("Quit").build();
+    let w_btn_unlock = gtk::Button::builder().label("Unlock").build();
+    w_btn_unlock.add_css_class("suggested-action");
+
+    w_box_btn.append(&w_btn_quit);
+    w_box_btn.append(&w_btn_unlock);
+
+    w_box.append(&w_description);
+    w_box.append(&w_passphrase_entry);
+    w_box.append(&w_error);
+    w_box.append(&w_box_btn);
+
+    win_dialog.set_child(Some(&w_box));
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_quit.connect_clicked(move |_| {
+        win_dialog_clone.close();
+    });
+
+    let win_dialog_clone = win_dialog.clone();
+    let w_passphrase_entry_c = w_passphrase_entry.clone();
+    let app_c = app.clone();
+    w_btn_unlock.connect_clicked(move |_| {
+        let passphrase = w_passphrase_entry_c.text();
+
+        let handle_error = |reason: String| {
+            w_error.set_text(&reason);
+            w_error.set_visible(true);
+        };
+
+        let result = state.borrow().unlock(&passphrase);
+        match result {
+            Ok(()) => {
+                // open the main window first, the application quits without any window
+                on_unlocked(&app_c, state.clone());
+                win_dialog_clone.close();
+            }
+            Err(CoreError::Load(LoadError::Decryption)) => {
+                handle_error("Wrong passphrase".to_string());
+                w_passphrase_entry_c.set_text("");
+            }
+            Err(e) => handle_error(format!("Could not unlock: {e}")),
+        }
+    });
+
+    w_passphrase_entry.connect_activate(move |_| {
+        w_btn_unlock.emit_clicked();
+    });
+
+    win_dialog.present();
+}
+
+/// Creates and shows a dialog for changing the passphrase the state is stored with
+pub(crate) fn dialog_change_passphrase(app: &gtk::Application, state: AppStateRef) {
+    let win_dialog = gtk::Window::builder()
+        .modal(true)
+        .default_width(400)
+        .default_height(200)
+        .resizable(false)
+        .title("Change Passphrase")
+        .build();
+
+    if let Some(window) = app.active_window() {
+        win_dialog.set_transient_for(Some(&window));
+    }
+
+    let w_box = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .spacing(GUI_SPACING_MID)
+        .margin_top(GUI_SPACING_MID)
+        .margin_bottom(GUI_SPACING_MID)
+        .margin_start(GUI_SPACING_MID)
+        .margin_end(GUI_SPACING_MID)
+        .build();
+
+    let w_grid = gtk::Grid::builder()
+        .row_spacing(6)
+        .column_spacing(12)
+        .build();
+
+    let w_old_entry = passphrase_entry();
+    let w_new_entry = passphrase_entry();
+    let w_confirm_entry = passphrase_entry();
+
+    w_grid.attach(&label("Current passphrase"), 0, 0, 1, 1);
+    w_grid.attach(&w_old_entry, 1, 0, 1, 1);
+    w_grid.attach(&label("New passphrase"), 0, 1, 1, 1);
+    w_grid.attach(&w_new_entry, 1, 1, 1, 1);
+    w_grid.attach(&label("Repeat new passphrase"), 0, 2, 1, 1);
+    w_grid.attach(&w_confirm_entry, 1, 2, 1, 1);
+
+    let w_error = label("undefined error");
+    w_error.set_visible(false);
+    w_error.add_css_class("error");
+    w_grid.attach(&w_error, 0, 3, 2, 1);
+
+    let w_box_btn = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(6)
+        .halign(gtk::Align::End)
+        .build();
+
+    let w_btn_cancel = gtk::Button::builder().label("Cancel").build();
+    let w_btn_change = gtk::Button::builder().label("Change Passphrase").build();
+    w_btn_change.add_css_class("suggested-action");
+
+    w_box_btn.append(&w_btn_cancel);
+    w_box_btn.append(&w_btn_change);
+
+    w_box.append(&w_grid);
+    w_box.append(&w_box_btn);
+
+    win_dialog.set_child(Some(&w_box));
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_cancel.connect_clicked(move |_| {
+        win_dialog_clone.close();
+    });
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_change.connect_clicked(move |_| {
+        let handle_error = |reason: String| {
+            w_error.set_text(&reason);
+            w_error.set_visible(true);
+        };
+
+        let new = w_new_entry.text();
+        if let Err(reason) = check_new_passphrase(&new, &w_confirm_entry.text()) {
+            handle_error(reason);
+            return;
+        }
+
+        let result = state.borrow().change_passphrase(&w_old_entry.text(), &new);
+        match result {
+            Ok(()) => {
+                log::info!("Changed the storage passphrase");
+                win_dialog_clone.close();
+            }
+            Err(CoreError::WrongPassphrase) => {
+                handle_error("The current passphrase is wrong".to_string())
+            }
+            Err(e) => handle_error(format!("Could not change the passphrase: {e}")),
+        }
+    });
+
+    win_dialog.present();
+}
+
+/// Creates an entry for a passphrase
+pub(crate) fn passphrase_entry() -> gtk::PasswordEntry {
+    gtk::PasswordEntry::builder()
+        .show_peek_icon(true)
+        .hexpand(true)
+        .build()
+}
+
+/// Checks a newly chosen passphrase, returning the reason if it can not be used.
+pub(crate) fn check_new_passphrase(passphrase: &str, confirmation: &str) -> Result<(), String> {
+    if passphrase.is_empty() {
+        return Err("Passphrase cannot be empty".to_string());
+    }
+    if passphrase != confirmation {
+        return Err("The passphrases do not match".to_string());
+    }
+    Ok(())
+}
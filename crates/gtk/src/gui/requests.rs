@@ -0,0 +1,177 @@
+use ed25519_dalek::VerifyingKey;
+use gtk::prelude::*;
+use sremp_core::{
+    chat::{Chat, messages::Message},
+    error::CoreResult,
+    identity::format_key,
+    net::NetworkCommand,
+    state::State,
+};
+
+use crate::{gui::label, state::AppStateRef, utils::GUI_SPACING_MID};
+
+/// What the user decided to do with a contact request
+type Decision = fn(&mut State, &VerifyingKey) -> CoreResult<()>;
+
+/// Creates and shows a dialog for reviewing the contact requests of unknown peers
+pub(crate) fn dialog_contact_requests(app: &gtk::Application, state: AppStateRef) {
+    let win_dialog = gtk::Window::builder()
+        .modal(true)
+        .default_width(400)
+        .default_height(400)
+        .title("Contact Requests")
+        .build();
+
+    if let Some(window) = app.active_window() {
+        win_dialog.set_transient_for(Some(&window));
+    }
+
+    let w_box = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .spacing(GUI_SPACING_MID)
+        .margin_top(GUI_SPACING_MID)
+        .margin_bottom(GUI_SPACING_MID)
+        .margin_start(GUI_SPACING_MID)
+        .margin_end(GUI_SPACING_MID)
+        .build();
+
+    let mut requests: Vec<Chat> = state.borrow().core().requests.values().cloned().collect();
+    requests.sort_by_key(|chat| std::cmp::Reverse(chat.latest_timestamp()));
+
+    let w_list = gtk::ListBox::builder()
+        .selection_mode(gtk::SelectionMode::None)
+        .vexpand(true)
+        .build();
+
+    let w_error = label("undefined error");
+    w_error.set_visible(false);
+    w_error.add_css_class("error");
+
+    if requests.is_empty() {
+        w_list.append(&label("No contact requests"));
+    }
+    for request in &requests {
+        w_list.append(&widget_request(state.clone(), &w_list, &w_error, request));
+    }
+
+    let w_btn_close = gtk::Button::builder()
+        .label("Close")
+        .halign(gtk::Align::End)
+        .build();
+
+    w_box.append(
+        &gtk::ScrolledWindow::builder()
+            .child(&w_list)
+            .vexpand(true)
+            .build(),
+    );
+    w_box.append(&w_error);
+    w_box.append(&w_btn_close);
+
+    win_dialog.set_child(Some(&w_box));
+
+    let win_dialog_clone = win_dialog.clone();
+    w_btn_close.connect_clicked(move |_| {
+        win_dialog_clone.close();
+    });
+
+    win_dialog.present();
+}
+
+/// One request with its latest message and the buttons for deciding on it
+fn widget_request(
+    state: AppStateRef,
+    w_list: &gtk::ListBox,
+    w_error: &gtk::Label,
+    request: &Chat,
+) -> impl IsA<gtk::Widget> {
+    let contact_key = request.contact().identity.public_key;
+
+    let w_box = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .spacing(6)
+        .margin_top(GUI_SPACING_MID)
+        .margin_bottom(GUI_SPACING_MID)
+        .margin_start(GUI_SPACING_MID)
+        .margin_end(GUI_SPACING_MID)
+        .build();
+
+    let w_username = label(format!(
+        "{} ({})",
+        request.contact().identity.username(),
+        format_key(&contact_key)
+    ));
+    w_username.set_halign(gtk::Align::Start);
+
+    let preview = match request.messages().next_back() {
+        Some(Message::Text(msg)) => msg.text.clone(),
+        None => String::new(),
+    };
+    let w_preview = label(format!(
+        "{} message(s): {preview}",
+        request.messages().len()
+    ));
+    w_preview.set_halign(gtk::Align::Start);
+    w_preview.set_ellipsize(gtk::pango::EllipsizeMode::End);
+    w_preview.add_css_class("dim-label");
+
+    let w_box_btn = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(6)
+        .halign(gtk::Align::End)
+        .build();
+
+    let w_btn_block = gtk::Button::builder().label("Block").build();
+    w_btn_block.add_css_class("destructive-action");
+    let w_btn_reject = gtk::Button::builder().label("Reject").build();
+    let w_btn_accept = gtk::Button::builder().label("Accept").build();
+    w_btn_accept.add_css_class("suggested-action");
+
+    w_box_btn.append(&w_btn_block);
+    w_box_btn.append(&w_btn_reject);
+    w_box_btn.append(&w_btn_accept);
+
+    w_box.append(&w_username);
+    w_box.append(&w_preview);
+    w_box.append(&w_box_btn);
+
+    let w_row = gtk::ListBoxRow::builder().child(&w_box).build();
+
+    let buttons: [(&gtk::Button, Decision); 3] = [
+        (&w_btn_accept, State::accept_request),
+        (&w_btn_reject, State::reject_request),
+        (&w_btn_block, State::block_contact),
+    ];
+    for (button, decide) in buttons {
+        let state = state.clone();
+        let w_list = w_list.clone();
+        let w_row = w_row.clone();
+        let w_error = w_error.clone();
+        let blocks = button == &w_btn_block;
+        button.connect_clicked(move |_| {
+            let state_b = state.borrow();
+            let result = decide(&mut state_b.core_mut(), &contact_key);
+            if let Err(e) = result.and_then(|()| state_b.save()) {
+                w_error.set_text(&format!("Could not handle the request: {e}"));
+                w_error.set_visible(true);
+                return;
+            }
+            // a blocked peer must not keep its open connection
+            let remote = state_b
+                .core()
+                .active_connections
+                .find_socket_addr_for_contact(&contact_key);
+            if blocks && let Some(remote) = remote {
+                if let Err(e) = state_b
+                    .command_channel
+                    .send_blocking(NetworkCommand::Disconnect(remote))
+                {
+                    log::error!("Could not disconnect from the blocked contact: {e}");
+                }
+            }
+            w_list.remove(&w_row);
+        });
+    }
+
+    w_row
+}
@@ -14,6 +14,10 @@ pub(crate) fn widget_topbar(_app: &gtk::Application, state: AppStateRef) -> impl
         Some("Connect"),
         Some(actions::ids::A_ID_CONNECTION_CONNECT!(app)),
     );
+    menu_connection.append(
+        Some("Connect to Relay"),
+        Some(actions::ids::A_ID_CONNECTION_RELAY!(app)),
+    );
     menu_connection.append(
         Some("Listen"),
         Some(actions::ids::A_ID_CONNECTION_LISTEN!(app)),
@@ -22,7 +26,23 @@ pub(crate) fn widget_topbar(_app: &gtk::Application, state: AppStateRef) -> impl
         Some("Disconnect"),
         Some(actions::ids::A_ID_CONNECTION_DISCONNECT!(app)),
     );
+    menu_connection.append(
+        Some("Keep Chat Connected"),
+        Some(actions::ids::A_ID_CONNECTION_KEEP_CONNECTED!(app)),
+    );
+    menu_connection.append(
+        Some("Listener Access"),
+        Some(actions::ids::A_ID_CONNECTION_ACCESS!(app)),
+    );
+    menu_connection.append(
+        Some("Rendezvous Server"),
+        Some(actions::ids::A_ID_CONNECTION_RENDEZVOUS!(app)),
+    );
 
+    menu_settings.append(
+        Some("Send read receipts"),
+        Some(actions::ids::A_ID_SETTINGS_READ_RECEIPTS!(app)),
+    );
     menu_settings.append(
         Some("Delete everything"),
         Some(actions::ids::A_ID_SETTINGS_DELETE_EVERYTHING!(app)),
@@ -55,7 +75,23 @@ pub(crate) fn widget_topbar(_app: &gtk::Application, state: AppStateRef) -> impl
     );
     menu_identity.append(
         Some("Show my Identity"),
-        Some(actions::ids::A_ID_IDENTITY_SHOW_USER!(app)), // TODO: open a show window on clicked
+        Some(actions::ids::A_ID_IDENTITY_SHOW_USER!(app)),
+    );
+    menu_identity.append(
+        Some("Show Contact"),
+        Some(actions::ids::A_ID_IDENTITY_SHOW_CONTACT!(app)),
+    );
+    menu_identity.append(
+        Some("Verify Contact"),
+        Some(actions::ids::A_ID_IDENTITY_VERIFY_CONTACT!(app)),
+    );
+    menu_identity.append(
+        Some("Contact Requests"),
+        Some(actions::ids::A_ID_IDENTITY_CONTACT_REQUESTS!(app)),
+    );
+    menu_identity.append(
+        Some("Change Passphrase"),
+        Some(actions::ids::A_ID_IDENTITY_CHANGE_PASSPHRASE!(app)),
     );
 
     menu.append_submenu(Some("Connection"), &menu_connection);
@@ -3,8 +3,12 @@
 #![deny(clippy::await_holding_refcell_ref)]
 #![deny(clippy::await_holding_lock)]
 
+use ed25519_dalek::VerifyingKey;
 use log::trace;
-use sremp_core::net::NetworkEvent;
+use sremp_core::{
+    chat::messages::{DeliveryState, MessageId},
+    net::{NetworkEvent, ReconnectState},
+};
 
 use crate::state::AppStateRef;
 
@@ -28,14 +32,46 @@ async fn event_processor(state: AppStateRef) {
                     NetworkEvent::ListenerStopped => {
                         update_listener_label(&state_bind);
                     }
-                    NetworkEvent::ConnectionEstablished(_addr, _key) => {
+                    NetworkEvent::ConnectionEstablished(_addr, key) => {
                         // Add new chat, update chat list, etc.
+                        update_chat_status(&state_bind, &key, "Connected");
                     }
                     NetworkEvent::IncomingMessage(_addr, _key, _msg) => {
                         // Update chat window, show notification, etc.
                     }
-                    NetworkEvent::ConnectionLost(_addr, _key) => {
+                    NetworkEvent::MessageQueued(_key, msg) => {
+                        update_delivery(&state_bind, &msg.id(), msg.meta().delivery);
+                    }
+                    NetworkEvent::MessageSent(_addr, _key, msg) => {
+                        update_delivery(&state_bind, &msg.id(), msg.meta().delivery);
+                    }
+                    NetworkEvent::MessageStateChanged(_key, id, delivery) => {
+                        update_delivery(&state_bind, &id, delivery);
+                    }
+                    NetworkEvent::ConnectionLost(_addr, key) => {
                         // Update connection status, maybe show error
+                        update_chat_status(&state_bind, &key, "Offline");
+                    }
+                    NetworkEvent::ReconnectStateChanged(key, reconnect_state) => {
+                        let status = match reconnect_state {
+                            ReconnectState::Connecting(_) => "Connecting…".to_string(),
+                            ReconnectState::Backoff(until) => format!(
+                                "Offline, retrying at {}",
+                                until.with_timezone(&chrono::Local).format("%H:%M:%S")
+                            ),
+                            ReconnectState::Connected(_) => "Connected".to_string(),
+                        };
+                        update_chat_status(&state_bind, &key, &status);
+                    }
+                    NetworkEvent::RendezvousRegistered(_server, _endpoint, _expires_at) => {
+                        update_listener_label(&state_bind);
+                    }
+                    NetworkEvent::PeerLookedUp(key, peer) => {
+                        let status = match peer {
+                            Some(peer) => format!("Found at {}", peer.endpoint),
+                            None => "Not found on the rendezvous server".to_string(),
+                        };
+                        update_chat_status(&state_bind, &key, &status);
                     }
                     _ => {}
                 }
@@ -55,3 +91,27 @@ fn update_listener_label(state: &std::cell::Ref<'_, crate::state::AppState>) {
         .expect("menu listen status label does not exist")
         .set_text(&new_text);
 }
+
+fn update_delivery(
+    state: &std::cell::Ref<'_, crate::state::AppState>,
+    id: &MessageId,
+    delivery: DeliveryState,
+) {
+    trace!("updating delivery ticks");
+    // only the bubbles of the open chat are tracked
+    if let Some(lbl) = state.tracked_widgets.lbl_delivery(id) {
+        crate::gui::chat::set_delivery(lbl, delivery);
+    }
+}
+
+fn update_chat_status(
+    state: &std::cell::Ref<'_, crate::state::AppState>,
+    key: &VerifyingKey,
+    status: &str,
+) {
+    trace!("updating chat status label");
+    // chats that were added after the chat list was built have no card yet
+    if let Some(lbl) = state.tracked_widgets.lbl_chat_status(key) {
+        lbl.set_text(status);
+    }
+}
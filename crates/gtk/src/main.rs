@@ -1,10 +1,17 @@
+// the core errors contain the async channel types, see sremp-core
+#![allow(clippy::result_large_err)]
+
+use async_channel::{Receiver, Sender};
 use gtk::prelude::*;
 use gtk::{Application, glib};
+use sremp_core::net::{NetworkCommand, NetworkEvent};
 use sremp_core::state::State;
+use sremp_core::storage::Storage;
 
 use crate::actions::register_actions;
+use crate::gui::passphrase::dialog_unlock;
 use crate::gui::start_gui;
-use crate::state::AppState;
+use crate::state::{AppState, AppStateRef};
 
 /// maximum of 10 messages queues, otherwise crash
 const CHANNEL_CAPACITY: usize = 10;
@@ -28,19 +35,38 @@ fn main() -> glib::ExitCode {
         let (command_tx, command_rx) = async_channel::bounded(CHANNEL_CAPACITY);
         let (event_tx, event_rx) = async_channel::bounded(CHANNEL_CAPACITY);
 
-        let state = AppState::new_or_load(command_tx, event_rx, rt)
-            .expect("could not load or create application state")
-            .into_ref();
-
-        let cc = state.borrow().core.clone();
-        State::start_backend_worker(cc, command_rx, event_tx, &mut state.borrow_mut().rt)
-            .expect("could not start backend worker");
+        let storage =
+            Storage::default_location().expect("could not determine where to store the state");
+        let has_stored_state = storage.exists();
 
-        register_actions(app, state.clone());
-        start_gui(app, state.clone());
+        let state = AppState::new(storage, command_tx, event_rx, rt).into_ref();
 
-        jobs::start_jobs(state);
+        if has_stored_state {
+            dialog_unlock(app, state, move |app, state| {
+                launch(app, state, command_rx.clone(), event_tx.clone());
+            });
+        } else {
+            log::info!("No stored state found, starting fresh");
+            launch(app, state, command_rx, event_tx);
+        }
     });
 
     app.run()
 }
+
+/// Starts the backend and the main window, once the state is ready.
+fn launch(
+    app: &Application,
+    state: AppStateRef,
+    command_rx: Receiver<NetworkCommand>,
+    event_tx: Sender<NetworkEvent>,
+) {
+    let cc = state.borrow().core.clone();
+    State::start_backend_worker(cc, command_rx, event_tx, &mut state.borrow_mut().rt)
+        .expect("could not start backend worker");
+
+    register_actions(app, state.clone());
+    start_gui(app, state.clone());
+
+    jobs::start_jobs(state);
+}
@@ -8,6 +8,7 @@ use sremp_core::{
     error::CoreResult,
     net::{NetworkCommand, NetworkEvent},
     state::{State, StateSync},
+    storage::Storage,
 };
 
 pub(crate) mod tracked_widgets;
@@ -33,12 +34,17 @@ pub(crate) struct AppStateRef {
 impl AppState {
     #[must_use]
     pub(crate) fn new(
+        storage: Storage,
         command_channel: Sender<NetworkCommand>,
         event_channel: Receiver<NetworkEvent>,
         rt: tokio::runtime::Runtime,
     ) -> Self {
+        let core = State {
+            storage: Some(storage),
+            ..Default::default()
+        };
         Self {
-            core: State::default().to_sync(),
+            core: core.to_sync(),
             command_channel,
             event_channel,
             rt,
@@ -47,35 +53,51 @@ impl AppState {
         }
     }
 
-    #[inline]
-    pub(crate) fn new_or_load(
-        command_channel: Sender<NetworkCommand>,
-        event_channel: Receiver<NetworkEvent>,
-        rt: tokio::runtime::Runtime,
-    ) -> CoreResult<Self> {
-        // TODO: add load condition
-        if false {
-            Self::load(command_channel, event_channel, rt)
-        } else {
-            Ok(Self::new(command_channel, event_channel, rt))
-        }
+    /// Loads the stored core state, replacing the current one.
+    pub(crate) fn unlock(&self, passphrase: &str) -> CoreResult<()> {
+        let mut storage = self.storage();
+        let core = self.rt.block_on(storage.load(passphrase))?;
+        *self.core_mut() = core;
+        Ok(())
+    }
+
+    /// Sets the passphrase the core state is stored with and saves it.
+    pub(crate) fn set_passphrase(&self, passphrase: &str) -> CoreResult<()> {
+        let mut storage = self.storage();
+        self.rt.block_on(storage.set_passphrase(passphrase))?;
+        self.core_mut().storage = Some(storage);
+        self.save()
+    }
+
+    pub(crate) fn change_passphrase(&self, old: &str, new: &str) -> CoreResult<()> {
+        self.rt
+            .block_on(State::change_passphrase(&self.core, old, new))
+    }
+
+    /// Checks if no passphrase was set for storing the core state yet.
+    pub(crate) fn storage_is_locked(&self) -> bool {
+        self.core().storage.as_ref().is_none_or(Storage::is_locked)
+    }
+
+    fn storage(&self) -> Storage {
+        self.core()
+            .storage
+            .clone()
+            .expect("the core state has no storage")
     }
 
-    pub(crate) fn load(
-        _command_channel: Sender<NetworkCommand>,
-        _event_channel: Receiver<NetworkEvent>,
-        _rt: tokio::runtime::Runtime,
-    ) -> CoreResult<Self> {
-        // TODO: impl load from disk
-        Err(sremp_core::error::CoreError::Load(
-            sremp_core::error::LoadError::Placeholder,
-        ))
+    /// Saves the core state to the disk.
+    pub(crate) fn save(&self) -> CoreResult<()> {
+        self.rt.block_on(State::save(&self.core))
     }
 
     pub(crate) fn set_selected_chat(&mut self, key: Option<VerifyingKey>) -> CoreResult<()> {
         if let Some(key) = key {
             if self.core().chats.contains_key(&key) {
                 self.selected_chat = Some(key);
+                // viewing a chat reads it
+                self.command_channel
+                    .send_blocking(NetworkCommand::MarkRead(key))?;
             } else {
                 panic!("given key not found in chats")
             }
@@ -109,12 +131,16 @@ impl AppState {
     pub(crate) fn fmt_listen_status(&self) -> String {
         let listener = &self.core().listener;
         if let Some(listener) = listener {
-            format!(
-                "Listening on {}",
-                listener
-                    .local_addr()
-                    .expect("could not read local address of listener")
-            )
+            let addr = listener
+                .local_addr()
+                .expect("could not read local address of listener");
+            match &self.core().registration {
+                Some(registration) if registration.expires_at.is_some() => format!(
+                    "Listening on {addr}, registered with {}",
+                    registration.server
+                ),
+                _ => format!("Listening on {addr}"),
+            }
         } else {
             "No listener active".to_string()
         }
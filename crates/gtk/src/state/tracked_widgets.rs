@@ -1,6 +1,15 @@
+use std::collections::HashMap;
+
+use ed25519_dalek::VerifyingKey;
+use sremp_core::chat::messages::MessageId;
+
 #[derive(Debug, Default)]
 pub(crate) struct TrackedWidgets {
     lbl_listener_status: Option<gtk::Label>,
+    /// Connection status of the contact on each card in the chat list
+    lbls_chat_status: HashMap<VerifyingKey, gtk::Label>,
+    /// Delivery ticks on the bubbles of our own messages
+    lbls_delivery: HashMap<MessageId, gtk::Label>,
 }
 
 impl TrackedWidgets {
@@ -11,4 +20,20 @@ impl TrackedWidgets {
     pub(crate) fn set_lbl_listener_status(&mut self, lbl_listener_status: Option<gtk::Label>) {
         self.lbl_listener_status = lbl_listener_status;
     }
+
+    pub(crate) fn lbl_chat_status(&self, key: &VerifyingKey) -> Option<&gtk::Label> {
+        self.lbls_chat_status.get(key)
+    }
+
+    pub(crate) fn set_lbl_chat_status(&mut self, key: VerifyingKey, lbl_chat_status: gtk::Label) {
+        self.lbls_chat_status.insert(key, lbl_chat_status);
+    }
+
+    pub(crate) fn lbl_delivery(&self, id: &MessageId) -> Option<&gtk::Label> {
+        self.lbls_delivery.get(id)
+    }
+
+    pub(crate) fn set_lbl_delivery(&mut self, id: MessageId, lbl_delivery: gtk::Label) {
+        self.lbls_delivery.insert(id, lbl_delivery);
+    }
 }
@@ -0,0 +1,23 @@
+//! Relay server for SREMP
+//!
+//! The relay keeps end-to-end encrypted messages for recipients that are not online, and hands
+//! them out once they connect, see [`sremp_core::net::relay`] for the protocol. It never sees
+//! the content of the messages, only who sends to whom and when.
+//!
+//! To run a relay locally, for example for trying out offline delivery:
+//!
+//! ```text
+//! cargo run -p sremp-relay -- --listen 127.0.0.1:7117
+//! ```
+//!
+//! Messages are kept in a directory, so that they survive restarts, see [`store`]. Each client
+//! can only store so much, and messages are dropped once they are delivered or have expired.
+
+// the core errors contain the async channel types, see sremp-core
+#![allow(clippy::result_large_err)]
+
+pub mod server;
+pub mod store;
+
+pub use server::{Relay, RelayConfig};
+pub use store::{DiskStorage, MemoryStorage, Storage};
@@ -0,0 +1,146 @@
+// the core errors contain the async channel types, see sremp-core
+#![allow(clippy::result_large_err)]
+
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode, time::Duration};
+
+use chrono::Utc;
+use clap::Parser;
+use ed25519_dalek::SigningKey;
+use sremp_core::{error::CoreResult, identity::UserIdentity, net::relay::RelayCapabilities};
+use sremp_relay::{
+    DiskStorage, MemoryStorage, Relay, RelayConfig,
+    server::{
+        DEFAULT_GC_INTERVAL, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_STORAGE_DURATION,
+        DEFAULT_STORAGE_LIMITS,
+    },
+    store::StorageLimits,
+};
+use tokio::net::TcpListener;
+
+/// Relay server for SREMP, keeps encrypted messages for recipients that are offline
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Address to listen on for clients
+    #[arg(short, long, default_value = "0.0.0.0:7117")]
+    listen: SocketAddr,
+    /// File with the secret key of the relay, created if it does not exist
+    #[arg(short, long, default_value = "sremp-relay.key")]
+    key_file: PathBuf,
+    /// Username the relay presents to its clients
+    #[arg(short, long, default_value = "sremp-relay")]
+    name: String,
+    /// Largest message clients may store, in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: u32,
+    /// How long messages are kept, in seconds
+    #[arg(long, default_value_t = DEFAULT_MAX_STORAGE_DURATION.as_secs())]
+    max_storage_duration: u64,
+    /// Directory the messages are kept in
+    #[arg(short, long, default_value = "sremp-relay-messages")]
+    storage_dir: PathBuf,
+    /// Keep the messages in memory only, they are lost when the relay stops
+    #[arg(long, conflicts_with = "storage_dir")]
+    in_memory: bool,
+    /// Size of all messages waiting for one recipient, in bytes
+    #[arg(long, default_value_t = DEFAULT_STORAGE_LIMITS.recipient_bytes)]
+    max_recipient_bytes: u64,
+    /// Number of messages waiting for one recipient
+    #[arg(long, default_value_t = DEFAULT_STORAGE_LIMITS.recipient_messages)]
+    max_recipient_messages: usize,
+    /// Size of all undelivered messages from one sender, in bytes
+    #[arg(long, default_value_t = DEFAULT_STORAGE_LIMITS.sender_bytes)]
+    max_sender_bytes: u64,
+    /// Number of undelivered messages from one sender
+    #[arg(long, default_value_t = DEFAULT_STORAGE_LIMITS.sender_messages)]
+    max_sender_messages: usize,
+    /// How often expired messages are dropped, in seconds
+    #[arg(long, default_value_t = DEFAULT_GC_INTERVAL.as_secs())]
+    gc_interval: u64,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+    let args = Args::parse();
+
+    if let Err(e) = run(args).await {
+        log::error!("Relay has failed: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+async fn run(args: Args) -> CoreResult<()> {
+    let identity = load_identity(&args)?;
+    log::info!(
+        "Relay key is {}",
+        sremp_core::identity::format_key(&identity.identity.public_key)
+    );
+    let config = RelayConfig {
+        capabilities: RelayCapabilities {
+            max_message_size: args.max_message_size,
+            max_storage_duration: Duration::from_secs(args.max_storage_duration),
+        },
+        limits: StorageLimits {
+            recipient_bytes: args.max_recipient_bytes,
+            recipient_messages: args.max_recipient_messages,
+            sender_bytes: args.max_sender_bytes,
+            sender_messages: args.max_sender_messages,
+        },
+        gc_interval: Duration::from_secs(args.gc_interval),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind(args.listen).await?;
+    if args.in_memory {
+        log::warn!("Messages are kept in memory only, they are lost when the relay stops");
+        Relay::new(identity, config, MemoryStorage::default())
+            .await?
+            .serve(listener)
+            .await
+    } else {
+        log::info!("Keeping messages in {}", args.storage_dir.display());
+        Relay::new(identity, config, DiskStorage::new(args.storage_dir))
+            .await?
+            .serve(listener)
+            .await
+    }
+}
+
+/// Loads the key of the relay, so that clients can recognize it across restarts.
+fn load_identity(args: &Args) -> CoreResult<UserIdentity> {
+    if args.key_file.exists() {
+        let bytes = std::fs::read(&args.key_file)?;
+        let key: [u8; 32] = bytes.try_into().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} is not a relay key", args.key_file.display()),
+            )
+        })?;
+        return UserIdentity::load(&args.name, SigningKey::from_bytes(&key), Utc::now());
+    }
+
+    log::info!("Creating a new relay key in {}", args.key_file.display());
+    let identity = UserIdentity::build(&args.name)?;
+    write_secret(&args.key_file, &identity.private_key.to_bytes())?;
+    Ok(identity)
+}
+
+#[cfg(unix)]
+fn write_secret(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, data)
+}
@@ -0,0 +1,425 @@
+//! Serves the relay protocol to clients, see [`sremp_core::net::relay`]
+
+use std::{collections::HashMap, io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use ed25519_dalek::VerifyingKey;
+use log::{debug, error, info, trace, warn};
+use sremp_core::{
+    error::{CoreError, CoreResult},
+    identity::{Identity, UserIdentity},
+    net::{
+        connection::{Connection, ConnectionReader, ConnectionWriter, FrameBody, Timeouts},
+        relay::{
+            Challenge, DeliveryConfirmation, MessageBatch, RelayCapabilities, RelayPacket,
+            StoreMessage, StoreResponse,
+        },
+    },
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+use crate::store::{MAX_BATCH_SIZE, MessageStore, NotStored, Storage, StorageLimits, StoreResult};
+
+/// Size of the largest message the relay stores by default, in bytes
+pub const DEFAULT_MAX_MESSAGE_SIZE: u32 = 1024 * 1024;
+/// How long the relay keeps messages by default
+pub const DEFAULT_MAX_STORAGE_DURATION: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+/// How much the relay keeps for a single client by default
+pub const DEFAULT_STORAGE_LIMITS: StorageLimits = StorageLimits {
+    recipient_bytes: 256 * 1024 * 1024,
+    recipient_messages: 10_000,
+    sender_bytes: 64 * 1024 * 1024,
+    sender_messages: 2_000,
+};
+/// How often expired messages are dropped by default
+pub const DEFAULT_GC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How the relay treats its clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayConfig {
+    /// The limits the relay applies, clients can only ask for lower ones
+    pub capabilities: RelayCapabilities,
+    /// How much each client may keep on the relay
+    pub limits: StorageLimits,
+    /// How often messages that have expired are dropped
+    pub gc_interval: Duration,
+    pub timeouts: Timeouts,
+}
+
+/// A relay server, shared by the tasks of all its connections
+#[derive(Debug)]
+pub struct Relay<S: Storage> {
+    identity: UserIdentity,
+    config: RelayConfig,
+    /// Synchronizes itself, so that connections do not wait for each other's storage
+    store: MessageStore<S>,
+    state: Mutex<RelayState>,
+}
+
+#[derive(Debug)]
+struct RelayState {
+    /// Registered clients that are connected, new messages and confirmations are pushed to them
+    clients: HashMap<VerifyingKey, ConnectionWriter>,
+    /// Confirmations for senders that were not connected when their message was delivered,
+    /// with the time they are dropped if the sender does not come back
+    confirmations: HashMap<VerifyingKey, Vec<(DateTime<Utc>, DeliveryConfirmation)>>,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            capabilities: RelayCapabilities {
+                max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                max_storage_duration: DEFAULT_MAX_STORAGE_DURATION,
+            },
+            limits: DEFAULT_STORAGE_LIMITS,
+            gc_interval: DEFAULT_GC_INTERVAL,
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+impl<S: Storage> Relay<S> {
+    /// Creates a relay that presents `identity` to its clients, and keeps the messages in
+    /// `storage`.
+    ///
+    /// The maximum message size is lowered so that any message fits into a [`MessageBatch`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the messages that are already in the storage cannot be loaded.
+    pub async fn new(
+        mut identity: UserIdentity,
+        mut config: RelayConfig,
+        storage: S,
+    ) -> StoreResult<Arc<Self>> {
+        identity.identity.flags.is_relay_server = true;
+        let max_size = u32::try_from(MAX_BATCH_SIZE).unwrap_or(u32::MAX);
+        config.capabilities.max_message_size = config.capabilities.max_message_size.min(max_size);
+        let store = MessageStore::open(storage, config.limits).await?;
+        Ok(Arc::new(Self {
+            identity,
+            config,
+            store,
+            state: Mutex::new(RelayState {
+                clients: HashMap::new(),
+                confirmations: HashMap::new(),
+            }),
+        }))
+    }
+
+    /// The identity the relay presents to its clients.
+    pub fn identity(&self) -> &Identity {
+        &self.identity.identity
+    }
+
+    pub fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+
+    /// Accepts clients on the listener, each is served by its own task.
+    ///
+    /// Expired messages and delivery confirmations are dropped every
+    /// [`RelayConfig::gc_interval`] meanwhile.
+    ///
+    /// # Errors
+    ///
+    /// Only returns if accepting connections fails.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> CoreResult<()> {
+        info!("Relay is listening on {}", listener.local_addr()?);
+        let gc = tokio::spawn(self.clone().collect_garbage());
+        let result = self.accept(listener).await;
+        gc.abort();
+        result
+    }
+
+    async fn accept(self: &Arc<Self>, listener: TcpListener) -> CoreResult<()> {
+        loop {
+            let (stream, remote) = listener.accept().await?;
+            debug!("Accepted a connection from {remote}");
+            let relay = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = relay.handle_client(stream, remote).await {
+                    warn!("Connection with {remote} has failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn collect_garbage(self: Arc<Self>) {
+        // an interval must not be zero
+        let period = self.config.gc_interval.max(Duration::from_secs(1));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let now = Utc::now();
+            let deleted = self.store.collect_garbage(now).await;
+            if deleted > 0 {
+                info!("Dropped {deleted} expired or delivered messages");
+            }
+
+            let mut state = self.state.lock().await;
+            let mut dropped = 0;
+            state.confirmations.retain(|_, confirmations| {
+                let before = confirmations.len();
+                confirmations.retain(|(expires_at, _)| *expires_at > now);
+                dropped += before - confirmations.len();
+                !confirmations.is_empty()
+            });
+            if dropped > 0 {
+                info!("Dropped {dropped} expired delivery confirmations");
+            }
+        }
+    }
+
+    async fn handle_client(&self, stream: TcpStream, remote: SocketAddr) -> CoreResult<()> {
+        let (conn, mut reader) =
+            Connection::connect_from(stream, remote, &self.identity, &self.config.timeouts).await?;
+        let writer = conn.writer();
+        let client = conn.peer_identity().await.public_key;
+
+        let result = self
+            .serve_client(client, remote, &mut reader, &writer)
+            .await;
+
+        {
+            // the client might have connected again meanwhile
+            let mut state = self.state.lock().await;
+            if state
+                .clients
+                .get(&client)
+                .is_some_and(|registered| registered.remote() == remote)
+            {
+                state.clients.remove(&client);
+            }
+        }
+        match result {
+            Ok(()) => conn.disconnect().await,
+            Err(e) => {
+                if let Err(send_error) = writer.send(&FrameBody::Error(e.to_string())).await {
+                    debug!("Could not tell {remote} about the error: {send_error}");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn serve_client(
+        &self,
+        client: VerifyingKey,
+        remote: SocketAddr,
+        reader: &mut ConnectionReader,
+        writer: &ConnectionWriter,
+    ) -> CoreResult<()> {
+        let (challenge, capabilities) = self.register(client, remote, reader, writer).await?;
+        info!("Client {remote} has registered");
+
+        loop {
+            let packet = match reader.recv_keepalive(writer, &self.config.timeouts).await {
+                Ok(packet) => packet,
+                Err(CoreError::IO(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    info!("Client {remote} has closed the connection");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+
+            match packet.body {
+                FrameBody::Relay(RelayPacket::Store(msg)) => {
+                    let response = self.store(client, msg, &capabilities).await;
+                    writer.send_detached(FrameBody::Relay(RelayPacket::StoreResponse(response)));
+                }
+                FrameBody::Relay(RelayPacket::Retrieve(request)) => {
+                    if request.identity != client {
+                        return Err(CoreError::InvalidSignature("message retrieval"));
+                    }
+                    request.verify(&self.identity.identity.public_key, &challenge)?;
+                    let batch = self
+                        .store
+                        .retrieve(&client, request.since, Utc::now())
+                        .await?;
+                    debug!("Handing {} messages to {remote}", batch.messages.len());
+                    writer.send_detached(FrameBody::Relay(RelayPacket::Batch(batch)));
+                }
+                FrameBody::Relay(RelayPacket::DeliveryConfirmation(confirmation)) => {
+                    if confirmation.delivered_to != client
+                        || confirmation
+                            .verify(&self.identity.identity.public_key)
+                            .is_err()
+                    {
+                        warn!("Client {remote} sent a bad delivery confirmation, ignoring it");
+                        continue;
+                    }
+                    self.confirm(confirmation).await;
+                }
+                FrameBody::Ping(value) => writer.send_detached(FrameBody::Pong(value)),
+                FrameBody::Pong(value) => trace!("Client {remote} answered ping {value}"),
+                FrameBody::Ack(id) => trace!("Client {remote} acknowledged packet {id}"),
+                FrameBody::Goodbye => {
+                    info!("Client {remote} is closing the connection");
+                    return Ok(());
+                }
+                FrameBody::Error(reason) => {
+                    warn!(
+                        "Client {remote} is closing the connection because of an error: {reason}"
+                    );
+                    return Ok(());
+                }
+                other => {
+                    return Err(CoreError::UnexpectedPacket {
+                        remote,
+                        kind: other.kind(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Has the client sign a [`Challenge`], and remembers it as connected once it did.
+    ///
+    /// Returns the challenge, which later requests are signed for, and the capabilities that
+    /// apply to the client.
+    async fn register(
+        &self,
+        client: VerifyingKey,
+        remote: SocketAddr,
+        reader: &mut ConnectionReader,
+        writer: &ConnectionWriter,
+    ) -> CoreResult<(Challenge, RelayCapabilities)> {
+        let challenge = Challenge::generate();
+        writer
+            .send(&FrameBody::Relay(RelayPacket::Challenge(challenge)))
+            .await?;
+
+        let packet = tokio::time::timeout(self.config.timeouts.identity, reader.recv())
+            .await
+            .map_err(|_| CoreError::Timeout {
+                remote,
+                stage: "relay registration",
+            })??;
+        let register = match packet.body {
+            FrameBody::Relay(RelayPacket::Register(register)) => register,
+            other => {
+                return Err(CoreError::UnexpectedPacket {
+                    remote,
+                    kind: other.kind(),
+                });
+            }
+        };
+        // the registration must come from the key the connection was made with
+        if register.identity != client {
+            return Err(CoreError::InvalidSignature("relay registration"));
+        }
+        register.verify(&self.identity.identity.public_key, &challenge)?;
+
+        let capabilities = self.config.capabilities.limit(&register.capabilities);
+        writer
+            .send(&FrameBody::Relay(RelayPacket::Registered(capabilities)))
+            .await?;
+
+        let mut state = self.state.lock().await;
+        state.clients.insert(client, writer.clone());
+        for (_, confirmation) in state.confirmations.remove(&client).unwrap_or_default() {
+            writer.send_detached(FrameBody::Relay(RelayPacket::DeliveryConfirmation(
+                confirmation,
+            )));
+        }
+        Ok((challenge, capabilities))
+    }
+
+    /// Stores a message from `sender`, and passes it on right away if the recipient is
+    /// connected.
+    async fn store(
+        &self,
+        sender: VerifyingKey,
+        msg: StoreMessage,
+        capabilities: &RelayCapabilities,
+    ) -> StoreResponse {
+        let now = Utc::now();
+        let message_id = msg.message_id;
+        let rejected = |reason: String| StoreResponse {
+            success: false,
+            stored_at: now,
+            message_id,
+            reason: Some(reason),
+        };
+
+        if msg.encrypted_blob.len() > capabilities.max_message_size as usize {
+            return rejected(format!(
+                "the message is larger than {} bytes",
+                capabilities.max_message_size
+            ));
+        }
+        if let Err(e) = msg.verify(&sender) {
+            return rejected(e.to_string());
+        }
+
+        let recipient = msg.recipient;
+        let expires_at = expiry(now, capabilities.max_storage_duration);
+        let (stored_at, new) = match self.store.store(sender, msg, now, expires_at).await {
+            Ok(stored) => stored,
+            Err(e @ (NotStored::RecipientQuota | NotStored::SenderQuota)) => {
+                return rejected(e.to_string());
+            }
+            Err(NotStored::Storage(e)) => {
+                error!("Could not store message {message_id}: {e}");
+                return rejected("the relay could not store the message".to_string());
+            }
+        };
+        let state = self.state.lock().await;
+        if let (Some(stored), Some(writer)) = (new, state.clients.get(&recipient)) {
+            writer.send_detached(FrameBody::Relay(RelayPacket::Batch(MessageBatch {
+                messages: vec![stored],
+                has_more: false,
+            })));
+        }
+        StoreResponse {
+            success: true,
+            stored_at,
+            message_id,
+            reason: None,
+        }
+    }
+
+    /// Drops a delivered message, and routes the confirmation back to its sender.
+    async fn confirm(&self, confirmation: DeliveryConfirmation) {
+        let removed = self
+            .store
+            .remove(
+                &confirmation.delivered_to,
+                &confirmation.sender,
+                confirmation.message_id,
+            )
+            .await;
+        if removed.is_empty() {
+            debug!(
+                "Message {} was confirmed, but is not stored",
+                confirmation.message_id
+            );
+            return;
+        }
+        let sender = confirmation.sender;
+        let mut state = self.state.lock().await;
+        match state.clients.get(&sender) {
+            Some(writer) => writer.send_detached(FrameBody::Relay(
+                RelayPacket::DeliveryConfirmation(confirmation),
+            )),
+            // kept as long as a message would be, the sender might never come back
+            None => state.confirmations.entry(sender).or_default().push((
+                expiry(Utc::now(), self.config.capabilities.max_storage_duration),
+                confirmation,
+            )),
+        }
+    }
+}
+
+/// When a message stored at `now` is dropped, even if it was not delivered.
+fn expiry(now: DateTime<Utc>, max_storage_duration: Duration) -> DateTime<Utc> {
+    TimeDelta::from_std(max_storage_duration)
+        .ok()
+        .and_then(|duration| now.checked_add_signed(duration))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
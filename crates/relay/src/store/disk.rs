@@ -0,0 +1,141 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use sremp_core::identity::format_key;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{Storage, StoreResult, StoredEntry};
+
+/// Extension of the files that hold a message
+const MESSAGE_EXTENSION: &str = "msg";
+/// Upper bound for the encoded [`StoredEntry`] at the start of a file, so that loading does not
+/// need to read the messages
+const MAX_ENTRY_SIZE: u64 = 1024;
+
+/// Keeps the messages in a directory, so that they survive restarts of the relay
+///
+/// Each message has its own file in a directory per recipient. The file starts with the
+/// [`StoredEntry`] encoded as MessagePack, the encrypted message follows as it is. Files are
+/// written to a temporary file first, so that a crash never leaves half a message behind.
+/// Files that cannot be loaded are removed.
+#[derive(Debug)]
+pub struct DiskStorage {
+    dir: PathBuf,
+}
+
+impl DiskStorage {
+    /// Uses `dir` for the messages, it is created if it does not exist.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, entry: &StoredEntry) -> PathBuf {
+        self.dir
+            .join(format_key(&entry.recipient))
+            .join(format!(
+                "{}-{}",
+                entry.timestamp.timestamp_micros(),
+                entry.message_id
+            ))
+            .with_extension(MESSAGE_EXTENSION)
+    }
+
+    /// Reads the entry at the start of a message file.
+    async fn read_entry(path: &Path) -> StoreResult<StoredEntry> {
+        let mut data = Vec::new();
+        tokio::fs::File::open(path)
+            .await?
+            .take(MAX_ENTRY_SIZE)
+            .read_to_end(&mut data)
+            .await?;
+        Ok(rmp_serde::from_read(&mut data.as_slice())?)
+    }
+}
+
+impl Storage for DiskStorage {
+    async fn load(&mut self) -> StoreResult<Vec<StoredEntry>> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let mut entries = Vec::new();
+        let mut recipients = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(recipient) = recipients.next_entry().await? {
+            if !recipient.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut files = tokio::fs::read_dir(recipient.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let path = file.path();
+                if path.extension().is_none_or(|ext| ext != MESSAGE_EXTENSION) {
+                    // left over from a crash while writing
+                    debug!("Removing unfinished message file {}", path.display());
+                    tokio::fs::remove_file(&path).await?;
+                    continue;
+                }
+                match Self::read_entry(&path).await {
+                    // an entry in the wrong place could never be deleted
+                    Ok(entry) if self.path(&entry) == path => entries.push(entry),
+                    result => {
+                        // the message cannot be handed out, so it would only take up space
+                        let reason = result.map_or_else(
+                            |e| e.to_string(),
+                            |_| "it is not where its entry belongs".to_string(),
+                        );
+                        warn!("Removing broken message file {}: {reason}", path.display());
+                        tokio::fs::remove_file(&path).await?;
+                    }
+                }
+            }
+        }
+        debug!(
+            "Loaded {} stored messages from {}",
+            entries.len(),
+            self.dir.display()
+        );
+        Ok(entries)
+    }
+
+    async fn write(&self, entry: &StoredEntry, blob: &[u8]) -> StoreResult<()> {
+        let path = self.path(entry);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut data = rmp_serde::to_vec(entry)?;
+        data.extend_from_slice(blob);
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+
+    async fn read(&self, entry: &StoredEntry) -> StoreResult<Vec<u8>> {
+        let data = tokio::fs::read(self.path(entry)).await?;
+        let mut rest = data.as_slice();
+        let _: StoredEntry = rmp_serde::from_read(&mut rest)?;
+        Ok(rest.to_vec())
+    }
+
+    async fn delete(&self, entry: &StoredEntry) -> StoreResult<()> {
+        let path = self.path(entry);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        // the directory of a recipient is only removed once it is empty
+        if let Some(dir) = path.parent() {
+            let _ = tokio::fs::remove_dir(dir).await;
+        }
+        Ok(())
+    }
+}
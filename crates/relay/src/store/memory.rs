@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+
+use tokio::sync::Mutex;
+
+use super::{EntryKey, Storage, StoreResult, StoredEntry};
+
+/// Keeps the messages in memory, they are lost when the relay stops
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    /// By recipient and the time the message was stored, which is unique per recipient
+    blobs: Mutex<HashMap<EntryKey, Vec<u8>>>,
+}
+
+impl Storage for MemoryStorage {
+    async fn load(&mut self) -> StoreResult<Vec<StoredEntry>> {
+        Ok(Vec::new())
+    }
+
+    async fn write(&self, entry: &StoredEntry, blob: &[u8]) -> StoreResult<()> {
+        self.blobs.lock().await.insert(entry.key(), blob.to_vec());
+        Ok(())
+    }
+
+    async fn read(&self, entry: &StoredEntry) -> StoreResult<Vec<u8>> {
+        self.blobs
+            .lock()
+            .await
+            .get(&entry.key())
+            .cloned()
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound).into())
+    }
+
+    async fn delete(&self, entry: &StoredEntry) -> StoreResult<()> {
+        self.blobs.lock().await.remove(&entry.key());
+        Ok(())
+    }
+}
@@ -0,0 +1,751 @@
+//! Messages the relay holds for their recipients
+//!
+//! The [`MessageStore`] keeps track of what is stored for whom, enforces the [`StorageLimits`]
+//! and expires messages after the storage duration the sender was granted. Where the encrypted
+//! messages themselves are kept is up to a [`Storage`], either [`MemoryStorage`], which loses
+//! everything when the relay stops, or [`DiskStorage`].
+//!
+//! The store can be shared by all connections of the relay. It only locks its index while it
+//! decides what to do, the storage is written and read without holding the lock.
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use ed25519_dalek::VerifyingKey;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sremp_core::{
+    chat::messages::MessageId,
+    error::CoreError,
+    net::{
+        connection::MAX_MESSAGE_SIZE,
+        relay::{MessageBatch, StoreMessage, StoredMessage},
+    },
+};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+mod disk;
+mod memory;
+
+pub use disk::DiskStorage;
+pub use memory::MemoryStorage;
+
+/// Size of the encrypted messages in one [`MessageBatch`], so that the batch still fits into a
+/// single packet. A batch always has at least one message.
+pub const MAX_BATCH_SIZE: usize = MAX_MESSAGE_SIZE / 2;
+/// Number of messages in one [`MessageBatch`]
+pub const MAX_BATCH_MESSAGES: usize = 256;
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// A failure of the [`Storage`]
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("input/output error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("could not encode a stored message: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error("could not decode a stored message: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+}
+
+impl From<StoreError> for CoreError {
+    fn from(value: StoreError) -> Self {
+        match value {
+            StoreError::IO(e) => CoreError::IO(e),
+            StoreError::Encode(e) => CoreError::MessagePackEncode(e),
+            StoreError::Decode(e) => CoreError::MessagePackDecode(e),
+        }
+    }
+}
+
+/// Why [`MessageStore::store`] did not keep a message
+///
+/// The quotas are the client's problem and are told to it, a failing storage is the relay's.
+#[derive(Debug, Error)]
+pub enum NotStored {
+    #[error("the recipient has too many messages waiting on this relay")]
+    RecipientQuota,
+    #[error("the sender has too many messages waiting on this relay")]
+    SenderQuota,
+    #[error(transparent)]
+    Storage(#[from] StoreError),
+}
+
+/// How much the relay keeps for a single client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageLimits {
+    /// Size of all messages waiting for one recipient, in bytes
+    pub recipient_bytes: u64,
+    /// Number of messages waiting for one recipient
+    pub recipient_messages: usize,
+    /// Size of all messages from one sender that were not delivered yet, in bytes
+    pub sender_bytes: u64,
+    /// Number of messages from one sender that were not delivered yet
+    pub sender_messages: usize,
+}
+
+/// Identifies a [`StoredEntry`] by its recipient and the time it was stored, which is unique per
+/// recipient
+type EntryKey = (VerifyingKey, DateTime<Utc>);
+
+/// Everything about a stored message but the message itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredEntry {
+    pub recipient: VerifyingKey,
+    pub message_id: MessageId,
+    pub sender: VerifyingKey,
+    /// When the relay stored the message, unique per recipient
+    pub timestamp: DateTime<Utc>,
+    /// When the relay drops the message, even if it was not delivered
+    pub expires_at: DateTime<Utc>,
+    /// Size of the encrypted message, in bytes
+    pub size: usize,
+}
+
+/// Where a [`MessageStore`] keeps the encrypted messages
+///
+/// The store decides what is kept and for how long, a storage only has to hold the data. It is
+/// used by several connections at once, but never for the same entry at the same time.
+pub trait Storage: Send + Sync + 'static {
+    /// All entries that were kept before, in any order.
+    fn load(&mut self) -> impl Future<Output = StoreResult<Vec<StoredEntry>>> + Send;
+
+    /// Keeps the encrypted message of a new entry.
+    fn write(
+        &self,
+        entry: &StoredEntry,
+        blob: &[u8],
+    ) -> impl Future<Output = StoreResult<()>> + Send;
+
+    /// Reads the encrypted message of an entry.
+    fn read(&self, entry: &StoredEntry) -> impl Future<Output = StoreResult<Vec<u8>>> + Send;
+
+    /// Drops the encrypted message of an entry.
+    fn delete(&self, entry: &StoredEntry) -> impl Future<Output = StoreResult<()>> + Send;
+}
+
+/// Stored messages by recipient, with their contents in a [`Storage`]
+#[derive(Debug)]
+pub struct MessageStore<S: Storage> {
+    storage: S,
+    limits: StorageLimits,
+    index: Mutex<Index>,
+}
+
+/// What is stored for whom, without the messages themselves
+#[derive(Debug, Default)]
+struct Index {
+    /// Ordered by the time they were stored, which is unique per recipient
+    queues: HashMap<VerifyingKey, Vec<StoredEntry>>,
+    /// What each sender has stored, for the quotas
+    senders: HashMap<VerifyingKey, Usage>,
+    /// Entries that are gone, but could not be deleted from the storage yet
+    garbage: Vec<StoredEntry>,
+    /// Entries whose message is still being written
+    writing: HashSet<EntryKey>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    bytes: u64,
+    messages: usize,
+}
+
+impl<S: Storage> MessageStore<S> {
+    /// Opens a store with the messages that are already in `storage`.
+    pub async fn open(mut storage: S, limits: StorageLimits) -> StoreResult<Self> {
+        let mut entries = storage.load().await?;
+        entries.sort_by_key(|entry| entry.timestamp);
+        let mut index = Index::default();
+        for entry in entries {
+            index.usage_mut(&entry.sender).add(&entry);
+            index.queues.entry(entry.recipient).or_default().push(entry);
+        }
+        debug!(
+            "Opened the message store with {} waiting recipients",
+            index.queues.len()
+        );
+        Ok(Self {
+            storage,
+            limits,
+            index: Mutex::new(index),
+        })
+    }
+
+    pub fn limits(&self) -> &StorageLimits {
+        &self.limits
+    }
+
+    /// Keeps a message from `sender` for its recipient, until it is delivered or `expires_at`.
+    ///
+    /// Returns when the message was stored, and the message if it is new. A message that was
+    /// stored already is not stored again.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`NotStored::RecipientQuota`] or [`NotStored::SenderQuota`] if the message
+    /// would exceed the [`StorageLimits`], or with [`NotStored::Storage`] if the storage fails.
+    pub async fn store(
+        &self,
+        sender: VerifyingKey,
+        msg: StoreMessage,
+        now: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(DateTime<Utc>, Option<StoredMessage>), NotStored> {
+        let entry = {
+            let mut index = self.index.lock().await;
+            let queue = index
+                .queues
+                .get(&msg.recipient)
+                .map_or(&[][..], Vec::as_slice);
+            if let Some(stored) = queue
+                .iter()
+                .find(|stored| stored.message_id == msg.message_id && stored.sender == sender)
+            {
+                return Ok((stored.timestamp, None));
+            }
+
+            let size = msg.encrypted_blob.len();
+            let waiting = queue.iter().fold(Usage::default(), |mut usage, stored| {
+                usage.add(stored);
+                usage
+            });
+            if !waiting.allows(
+                size,
+                self.limits.recipient_bytes,
+                self.limits.recipient_messages,
+            ) {
+                return Err(NotStored::RecipientQuota);
+            }
+            let sent = index.senders.get(&sender).copied().unwrap_or_default();
+            if !sent.allows(size, self.limits.sender_bytes, self.limits.sender_messages) {
+                return Err(NotStored::SenderQuota);
+            }
+
+            // retrieving "since" a message must not skip others stored at the same time
+            let timestamp = match queue.last() {
+                Some(last) if last.timestamp >= now => last.timestamp + TimeDelta::microseconds(1),
+                _ => now,
+            };
+            let entry = StoredEntry {
+                recipient: msg.recipient,
+                message_id: msg.message_id,
+                sender,
+                timestamp,
+                expires_at,
+                size,
+            };
+            // the entry holds its place and quota while the message is written
+            index.usage_mut(&sender).add(&entry);
+            index
+                .queues
+                .entry(entry.recipient)
+                .or_default()
+                .push(entry.clone());
+            index.writing.insert(entry.key());
+            entry
+        };
+
+        let written = self.storage.write(&entry, &msg.encrypted_blob).await;
+        let mut index = self.index.lock().await;
+        index.writing.remove(&entry.key());
+        if let Err(e) = written {
+            index.take(&entry.recipient, |stored| {
+                stored.timestamp == entry.timestamp
+            });
+            return Err(e.into());
+        }
+        let timestamp = entry.timestamp;
+        Ok((timestamp, Some(entry.into_message(msg.encrypted_blob))))
+    }
+
+    /// The oldest messages for `recipient` that were stored after `since` and have not expired.
+    ///
+    /// The batch ends before a message that is still being written, so that retrieving "since"
+    /// the last message of the batch does not skip it.
+    pub async fn retrieve(
+        &self,
+        recipient: &VerifyingKey,
+        since: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> StoreResult<MessageBatch> {
+        let (entries, has_more) = {
+            let index = self.index.lock().await;
+            let mut entries = Vec::new();
+            let mut size = 0;
+            let mut has_more = false;
+            let pending = index
+                .queues
+                .get(recipient)
+                .into_iter()
+                .flatten()
+                .filter(|entry| since.is_none_or(|since| entry.timestamp > since))
+                .filter(|entry| entry.expires_at > now);
+            for entry in pending {
+                if index.writing.contains(&entry.key()) {
+                    break;
+                }
+                size += entry.size;
+                if !entries.is_empty()
+                    && (size > MAX_BATCH_SIZE || entries.len() >= MAX_BATCH_MESSAGES)
+                {
+                    has_more = true;
+                    break;
+                }
+                entries.push(entry.clone());
+            }
+            (entries, has_more)
+        };
+
+        let mut messages = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match self.storage.read(&entry).await {
+                Ok(blob) => messages.push(entry.into_message(blob)),
+                // the message was delivered or has expired meanwhile
+                Err(_) if !self.contains(&entry).await => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(MessageBatch { messages, has_more })
+    }
+
+    /// Drops the message `id` from `sender` once its recipient has confirmed it, returning the
+    /// entries that were removed.
+    ///
+    /// Message ids are chosen by the senders, so only the message of this sender is removed.
+    /// If the storage cannot delete a message right away, it is retried on the next
+    /// [`MessageStore::collect_garbage`].
+    pub async fn remove(
+        &self,
+        recipient: &VerifyingKey,
+        sender: &VerifyingKey,
+        id: MessageId,
+    ) -> Vec<StoredEntry> {
+        let entries = {
+            let mut index = self.index.lock().await;
+            let writing = &index.writing;
+            // a message that is still being written cannot have been delivered
+            let written: Vec<DateTime<Utc>> = index
+                .queues
+                .get(recipient)
+                .into_iter()
+                .flatten()
+                .filter(|entry| {
+                    entry.message_id == id
+                        && entry.sender == *sender
+                        && !writing.contains(&entry.key())
+                })
+                .map(|entry| entry.timestamp)
+                .collect();
+            index.take(recipient, |entry| written.contains(&entry.timestamp))
+        };
+        let mut failed = Vec::new();
+        for entry in &entries {
+            if let Err(e) = self.storage.delete(entry).await {
+                warn!("Could not delete delivered message {id}, retrying later: {e}");
+                failed.push(entry.clone());
+            }
+        }
+        self.index.lock().await.garbage.extend(failed);
+        entries
+    }
+
+    /// Drops all messages that have expired, and deletes what could not be deleted before.
+    ///
+    /// Returns how many messages were deleted from the storage.
+    pub async fn collect_garbage(&self, now: DateTime<Utc>) -> usize {
+        let garbage = {
+            let mut index = self.index.lock().await;
+            let expired: HashSet<EntryKey> = index
+                .queues
+                .values()
+                .flatten()
+                .filter(|entry| entry.expires_at <= now)
+                .map(StoredEntry::key)
+                .filter(|key| !index.writing.contains(key))
+                .collect();
+            let recipients: Vec<VerifyingKey> = index.queues.keys().copied().collect();
+            for recipient in recipients {
+                let expired = index.take(&recipient, |entry| expired.contains(&entry.key()));
+                index.garbage.extend(expired);
+            }
+            std::mem::take(&mut index.garbage)
+        };
+
+        let mut deleted = 0;
+        let mut failed = Vec::new();
+        for entry in garbage {
+            match self.storage.delete(&entry).await {
+                Ok(()) => deleted += 1,
+                Err(e) => {
+                    warn!("Could not delete message {}: {e}", entry.message_id);
+                    failed.push(entry);
+                }
+            }
+        }
+        self.index.lock().await.garbage.extend(failed);
+        deleted
+    }
+
+    /// Checks if the entry is still stored.
+    async fn contains(&self, entry: &StoredEntry) -> bool {
+        self.index
+            .lock()
+            .await
+            .queues
+            .get(&entry.recipient)
+            .is_some_and(|queue| queue.iter().any(|stored| stored.key() == entry.key()))
+    }
+}
+
+impl Index {
+    /// Takes the entries for `recipient` that match out of the index.
+    fn take(
+        &mut self,
+        recipient: &VerifyingKey,
+        matches: impl Fn(&StoredEntry) -> bool,
+    ) -> Vec<StoredEntry> {
+        let Some(queue) = self.queues.get_mut(recipient) else {
+            return Vec::new();
+        };
+        let (taken, kept): (Vec<_>, Vec<_>) = std::mem::take(queue).into_iter().partition(matches);
+        if kept.is_empty() {
+            self.queues.remove(recipient);
+        } else {
+            *queue = kept;
+        }
+        for entry in &taken {
+            if let Some(usage) = self.senders.get_mut(&entry.sender) {
+                usage.remove(entry);
+                if usage.messages == 0 {
+                    self.senders.remove(&entry.sender);
+                }
+            }
+        }
+        taken
+    }
+
+    fn usage_mut(&mut self, sender: &VerifyingKey) -> &mut Usage {
+        self.senders.entry(*sender).or_default()
+    }
+}
+
+impl StoredEntry {
+    fn key(&self) -> EntryKey {
+        (self.recipient, self.timestamp)
+    }
+
+    fn into_message(self, encrypted_blob: Vec<u8>) -> StoredMessage {
+        StoredMessage {
+            message_id: self.message_id,
+            sender: self.sender,
+            timestamp: self.timestamp,
+            encrypted_blob,
+        }
+    }
+}
+
+impl Usage {
+    fn add(&mut self, entry: &StoredEntry) {
+        self.bytes += entry.size as u64;
+        self.messages += 1;
+    }
+
+    fn remove(&mut self, entry: &StoredEntry) {
+        self.bytes = self.bytes.saturating_sub(entry.size as u64);
+        self.messages = self.messages.saturating_sub(1);
+    }
+
+    /// Whether another message of `size` bytes stays within the limits.
+    fn allows(&self, size: usize, max_bytes: u64, max_messages: usize) -> bool {
+        self.bytes + size as u64 <= max_bytes && self.messages < max_messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use sremp_core::identity::UserIdentity;
+
+    use super::*;
+
+    const LIMITS: StorageLimits = StorageLimits {
+        recipient_bytes: 100,
+        recipient_messages: 3,
+        sender_bytes: 60,
+        sender_messages: 10,
+    };
+
+    fn users() -> (UserIdentity, UserIdentity, UserIdentity) {
+        (
+            UserIdentity::build("alice").unwrap(),
+            UserIdentity::build("bob").unwrap(),
+            UserIdentity::build("carol").unwrap(),
+        )
+    }
+
+    async fn store_blob<S: Storage>(
+        store: &MessageStore<S>,
+        sender: &UserIdentity,
+        recipient: &UserIdentity,
+        blob: Vec<u8>,
+        now: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(DateTime<Utc>, Option<StoredMessage>), NotStored> {
+        let msg = StoreMessage::sign(
+            sender,
+            recipient.identity.public_key,
+            MessageId::generate(),
+            blob,
+        );
+        store
+            .store(sender.identity.public_key, msg, now, expires_at)
+            .await
+    }
+
+    #[tokio::test]
+    async fn quotas_are_enforced() {
+        let (alice, bob, carol) = users();
+        let store = MessageStore::open(MemoryStorage::default(), LIMITS)
+            .await
+            .unwrap();
+        let now = Utc::now();
+        let later = now + TimeDelta::days(1);
+
+        store_blob(&store, &alice, &bob, vec![1; 30], now, later)
+            .await
+            .unwrap();
+        store_blob(&store, &alice, &carol, vec![2; 30], now, later)
+            .await
+            .unwrap();
+        // alice has stored 60 bytes
+        let e = store_blob(&store, &alice, &carol, vec![3], now, later)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, NotStored::SenderQuota), "{e}");
+
+        store_blob(&store, &carol, &bob, vec![4; 10], now, later)
+            .await
+            .unwrap();
+        store_blob(&store, &carol, &bob, vec![4; 10], now, later)
+            .await
+            .unwrap();
+        // bob has 3 messages waiting
+        let e = store_blob(&store, &carol, &bob, vec![4; 10], now, later)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, NotStored::RecipientQuota), "{e}");
+
+        // a delivered message frees the quota of its sender
+        let batch = store
+            .retrieve(&bob.identity.public_key, None, now)
+            .await
+            .unwrap();
+        assert_eq!(batch.messages.len(), 3);
+        let first = &batch.messages[0];
+        assert_eq!(first.encrypted_blob, vec![1; 30]);
+        let removed = store
+            .remove(
+                &bob.identity.public_key,
+                &alice.identity.public_key,
+                first.message_id,
+            )
+            .await;
+        assert_eq!(removed.len(), 1);
+        store_blob(&store, &alice, &carol, vec![5; 30], now, later)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn stored_message_is_not_stored_again() {
+        let (alice, bob, _) = users();
+        let store = MessageStore::open(MemoryStorage::default(), LIMITS)
+            .await
+            .unwrap();
+        let now = Utc::now();
+        let later = now + TimeDelta::days(1);
+        let msg = StoreMessage::sign(
+            &alice,
+            bob.identity.public_key,
+            MessageId::generate(),
+            vec![1; 10],
+        );
+
+        let (stored_at, new) = store
+            .store(alice.identity.public_key, msg.clone(), now, later)
+            .await
+            .unwrap();
+        assert!(new.is_some());
+        let (again_at, new) = store
+            .store(alice.identity.public_key, msg, now, later)
+            .await
+            .unwrap();
+        assert!(new.is_none());
+        assert_eq!(stored_at, again_at);
+    }
+
+    #[tokio::test]
+    async fn confirmation_only_removes_the_message_of_the_sender() {
+        let (alice, bob, carol) = users();
+        let store = MessageStore::open(MemoryStorage::default(), LIMITS)
+            .await
+            .unwrap();
+        let now = Utc::now();
+        let later = now + TimeDelta::days(1);
+        // carol reuses the id of the message from alice
+        let id = MessageId::generate();
+        for sender in [&alice, &carol] {
+            let msg = StoreMessage::sign(sender, bob.identity.public_key, id, vec![1; 10]);
+            store
+                .store(sender.identity.public_key, msg, now, later)
+                .await
+                .unwrap();
+        }
+
+        let removed = store
+            .remove(&bob.identity.public_key, &carol.identity.public_key, id)
+            .await;
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].sender, carol.identity.public_key);
+        let batch = store
+            .retrieve(&bob.identity.public_key, None, now)
+            .await
+            .unwrap();
+        assert_eq!(batch.messages.len(), 1);
+        assert_eq!(batch.messages[0].sender, alice.identity.public_key);
+
+        let removed = store
+            .remove(&bob.identity.public_key, &carol.identity.public_key, id)
+            .await;
+        assert!(removed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_dropped() {
+        let (alice, bob, _) = users();
+        let store = MessageStore::open(MemoryStorage::default(), LIMITS)
+            .await
+            .unwrap();
+        let now = Utc::now();
+        let soon = now + TimeDelta::seconds(1);
+        let later = now + TimeDelta::days(1);
+        store_blob(&store, &alice, &bob, vec![1; 10], now, soon)
+            .await
+            .unwrap();
+        store_blob(&store, &alice, &bob, vec![2; 10], now, later)
+            .await
+            .unwrap();
+
+        let after_expiry = soon + TimeDelta::seconds(1);
+        let batch = store
+            .retrieve(&bob.identity.public_key, None, after_expiry)
+            .await
+            .unwrap();
+        assert_eq!(batch.messages.len(), 1);
+        assert_eq!(batch.messages[0].encrypted_blob, vec![2; 10]);
+
+        assert_eq!(store.collect_garbage(now).await, 0);
+        assert_eq!(store.collect_garbage(after_expiry).await, 1);
+        // the quota of the expired message is free again
+        store_blob(&store, &alice, &bob, vec![3; 50], now, later)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_survive_reopening_the_disk_storage() {
+        let (alice, bob, _) = users();
+        let dir = std::env::temp_dir().join(format!("sremp-relay-store-{}", MessageId::generate()));
+        let now = Utc::now();
+        let later = now + TimeDelta::days(1);
+
+        let store = MessageStore::open(DiskStorage::new(&dir), LIMITS)
+            .await
+            .unwrap();
+        store_blob(&store, &alice, &bob, vec![1; 20], now, later)
+            .await
+            .unwrap();
+        let (_, delivered) = store_blob(&store, &alice, &bob, vec![2; 20], now, later)
+            .await
+            .unwrap();
+        let removed = store
+            .remove(
+                &bob.identity.public_key,
+                &alice.identity.public_key,
+                delivered.unwrap().message_id,
+            )
+            .await;
+        assert_eq!(removed.len(), 1);
+        store_blob(&store, &alice, &bob, vec![3; 20], now, later)
+            .await
+            .unwrap();
+        drop(store);
+
+        let store = MessageStore::open(DiskStorage::new(&dir), LIMITS)
+            .await
+            .unwrap();
+        let batch = store
+            .retrieve(&bob.identity.public_key, None, now)
+            .await
+            .unwrap();
+        let blobs: Vec<Vec<u8>> = batch
+            .messages
+            .into_iter()
+            .map(|msg| msg.encrypted_blob)
+            .collect();
+        assert_eq!(blobs, vec![vec![1; 20], vec![3; 20]]);
+        // the quota is restored with the messages
+        let e = store_blob(&store, &alice, &bob, vec![4; 30], now, later)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, NotStored::SenderQuota), "{e}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn broken_message_files_are_removed() {
+        let (alice, bob, _) = users();
+        let dir = std::env::temp_dir().join(format!("sremp-relay-store-{}", MessageId::generate()));
+        let now = Utc::now();
+        let later = now + TimeDelta::days(1);
+
+        let store = MessageStore::open(DiskStorage::new(&dir), LIMITS)
+            .await
+            .unwrap();
+        store_blob(&store, &alice, &bob, vec![1; 20], now, later)
+            .await
+            .unwrap();
+        drop(store);
+        let recipient_dir = dir.join(sremp_core::identity::format_key(&bob.identity.public_key));
+        let written: Vec<PathBuf> = std::fs::read_dir(&recipient_dir)
+            .unwrap()
+            .map(|file| file.unwrap().path())
+            .collect();
+        let garbage = recipient_dir.join("garbage.msg");
+        std::fs::write(&garbage, b"not a message").unwrap();
+        let misplaced = recipient_dir.join("0-misplaced.msg");
+        std::fs::copy(&written[0], &misplaced).unwrap();
+
+        let store = MessageStore::open(DiskStorage::new(&dir), LIMITS)
+            .await
+            .unwrap();
+        assert!(!garbage.exists());
+        assert!(!misplaced.exists());
+        assert!(written[0].exists());
+        let batch = store
+            .retrieve(&bob.identity.public_key, None, now)
+            .await
+            .unwrap();
+        assert_eq!(batch.messages.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,22 @@
+//! Rendezvous server for SREMP
+//!
+//! The rendezvous server keeps a registry of where peers can be reached, so that their
+//! contacts can find them without exchanging addresses first, see
+//! [`sremp_core::net::rendezvous`] for the protocol. Registrations are signed by the peer they
+//! are for, and end after the time to live the peer asked for.
+//!
+//! To run a rendezvous server locally, for example for trying out lookups:
+//!
+//! ```text
+//! cargo run -p sremp-rendezvous -- --listen 127.0.0.1:7118
+//! ```
+//!
+//! The registry is only kept in memory, peers register again after a restart anyway.
+
+// the core errors contain the async channel types, see sremp-core
+#![allow(clippy::result_large_err)]
+
+pub mod registry;
+pub mod server;
+
+pub use server::{Rendezvous, RendezvousConfig};
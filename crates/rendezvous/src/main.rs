@@ -0,0 +1,108 @@
+// the core errors contain the async channel types, see sremp-core
+#![allow(clippy::result_large_err)]
+
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode, time::Duration};
+
+use chrono::Utc;
+use clap::Parser;
+use ed25519_dalek::SigningKey;
+use sremp_core::{error::CoreResult, identity::UserIdentity};
+use sremp_rendezvous::{
+    Rendezvous, RendezvousConfig,
+    server::{DEFAULT_GC_INTERVAL, DEFAULT_MAX_LIST, DEFAULT_MAX_PEERS, DEFAULT_MAX_TTL},
+};
+use tokio::net::TcpListener;
+
+/// Rendezvous server for SREMP, tells peers where others can be reached
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Address to listen on for clients
+    #[arg(short, long, default_value = "0.0.0.0:7118")]
+    listen: SocketAddr,
+    /// File with the secret key of the server, created if it does not exist
+    #[arg(short, long, default_value = "sremp-rendezvous.key")]
+    key_file: PathBuf,
+    /// Username the server presents to its clients
+    #[arg(short, long, default_value = "sremp-rendezvous")]
+    name: String,
+    /// Longest time to live of a registration, in seconds
+    #[arg(long, default_value_t = DEFAULT_MAX_TTL.as_secs())]
+    max_ttl: u64,
+    /// Number of peers that can be registered at once
+    #[arg(long, default_value_t = DEFAULT_MAX_PEERS)]
+    max_peers: usize,
+    /// Number of peers in the answer to a lookup for all peers
+    #[arg(long, default_value_t = DEFAULT_MAX_LIST)]
+    max_list: usize,
+    /// How often expired registrations are dropped, in seconds
+    #[arg(long, default_value_t = DEFAULT_GC_INTERVAL.as_secs())]
+    gc_interval: u64,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+    let args = Args::parse();
+
+    if let Err(e) = run(args).await {
+        log::error!("Rendezvous server has failed: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+async fn run(args: Args) -> CoreResult<()> {
+    let identity = load_identity(&args)?;
+    log::info!(
+        "Rendezvous server key is {}",
+        sremp_core::identity::format_key(&identity.identity.public_key)
+    );
+    let config = RendezvousConfig {
+        max_ttl: Duration::from_secs(args.max_ttl),
+        max_peers: args.max_peers,
+        max_list: args.max_list,
+        gc_interval: Duration::from_secs(args.gc_interval),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind(args.listen).await?;
+    Rendezvous::new(identity, config).serve(listener).await
+}
+
+/// Loads the key of the server, so that clients can recognize it across restarts.
+fn load_identity(args: &Args) -> CoreResult<UserIdentity> {
+    if args.key_file.exists() {
+        let bytes = std::fs::read(&args.key_file)?;
+        let key: [u8; 32] = bytes.try_into().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} is not a rendezvous server key", args.key_file.display()),
+            )
+        })?;
+        return UserIdentity::load(&args.name, SigningKey::from_bytes(&key), Utc::now());
+    }
+
+    log::info!("Creating a new server key in {}", args.key_file.display());
+    let identity = UserIdentity::build(&args.name)?;
+    write_secret(&args.key_file, &identity.private_key.to_bytes())?;
+    Ok(identity)
+}
+
+#[cfg(unix)]
+fn write_secret(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, data)
+}
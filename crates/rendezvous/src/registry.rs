@@ -0,0 +1,241 @@
+//! Where the registered peers can be reached
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use ed25519_dalek::VerifyingKey;
+use sremp_core::{
+    identity::Identity,
+    net::rendezvous::{Endpoint, PeerInfo},
+};
+
+/// Registered peers by their key, kept in memory
+///
+/// Peers that registered again replace their old registration. A registration ends once it has
+/// expired, whether the peer is still connected or not.
+#[derive(Debug, Default)]
+pub struct Registry {
+    peers: HashMap<VerifyingKey, Registration>,
+    /// Number of open connections by key, a peer is online while it has any
+    connections: HashMap<VerifyingKey, usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Registration {
+    identity: Identity,
+    endpoint: Endpoint,
+    expires_at: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+impl Registry {
+    /// Number of peers that are registered, including those that have expired but were not
+    /// dropped yet
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn is_registered(&self, key: &VerifyingKey) -> bool {
+        self.peers.contains_key(key)
+    }
+
+    /// Registers the endpoint of a peer until `expires_at`, replacing its old registration.
+    pub fn register(
+        &mut self,
+        identity: Identity,
+        endpoint: Endpoint,
+        now: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) {
+        self.peers.insert(
+            identity.public_key,
+            Registration {
+                identity,
+                endpoint,
+                expires_at,
+                last_seen: now,
+            },
+        );
+    }
+
+    /// Ends the registration of a peer, returning whether it had one.
+    pub fn unregister(&mut self, key: &VerifyingKey) -> bool {
+        self.peers.remove(key).is_some()
+    }
+
+    /// The registration of a single peer, unless it has expired.
+    pub fn lookup(&self, key: &VerifyingKey, now: DateTime<Utc>) -> Option<PeerInfo> {
+        self.peers
+            .get(key)
+            .filter(|registration| registration.expires_at > now)
+            .map(|registration| self.info(registration))
+    }
+
+    /// All registrations that have not expired, at most `limit` of them.
+    pub fn list(&self, now: DateTime<Utc>, limit: usize) -> Vec<PeerInfo> {
+        self.peers
+            .values()
+            .filter(|registration| registration.expires_at > now)
+            .take(limit)
+            .map(|registration| self.info(registration))
+            .collect()
+    }
+
+    /// Remembers that the peer with `key` has opened a connection.
+    pub fn connected(&mut self, key: VerifyingKey, now: DateTime<Utc>) {
+        *self.connections.entry(key).or_default() += 1;
+        self.seen(&key, now);
+    }
+
+    /// Remembers that the peer with `key` has closed a connection.
+    pub fn disconnected(&mut self, key: VerifyingKey, now: DateTime<Utc>) {
+        if let Some(count) = self.connections.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                self.connections.remove(&key);
+            }
+        }
+        self.seen(&key, now);
+    }
+
+    /// Drops the registrations that have expired, returning how many there were.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> usize {
+        let before = self.peers.len();
+        self.peers
+            .retain(|_, registration| registration.expires_at > now);
+        before - self.peers.len()
+    }
+
+    fn seen(&mut self, key: &VerifyingKey, now: DateTime<Utc>) {
+        if let Some(registration) = self.peers.get_mut(key) {
+            registration.last_seen = now;
+        }
+    }
+
+    fn info(&self, registration: &Registration) -> PeerInfo {
+        let online = self
+            .connections
+            .contains_key(&registration.identity.public_key);
+        PeerInfo {
+            identity: registration.identity.clone(),
+            endpoint: registration.endpoint.clone(),
+            last_seen: registration.last_seen,
+            online,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use sremp_core::identity::UserIdentity;
+
+    use super::*;
+
+    fn identity(name: &str) -> Identity {
+        UserIdentity::build(name).unwrap().identity
+    }
+
+    #[test]
+    fn registered_peer_can_be_looked_up() {
+        let mut registry = Registry::default();
+        let alice = identity("alice");
+        let now = Utc::now();
+        registry.register(
+            alice.clone(),
+            Endpoint::new("192.0.2.1", 5555),
+            now,
+            now + TimeDelta::minutes(1),
+        );
+
+        let info = registry.lookup(&alice.public_key, now).unwrap();
+        assert_eq!(info.identity, alice);
+        assert_eq!(info.endpoint, Endpoint::new("192.0.2.1", 5555));
+        assert!(!info.online);
+
+        registry.connected(alice.public_key, now);
+        assert!(registry.lookup(&alice.public_key, now).unwrap().online);
+        registry.disconnected(alice.public_key, now);
+        assert!(!registry.lookup(&alice.public_key, now).unwrap().online);
+
+        assert!(registry.unregister(&alice.public_key));
+        assert!(!registry.unregister(&alice.public_key));
+        assert_eq!(registry.lookup(&alice.public_key, now), None);
+    }
+
+    #[test]
+    fn renewing_replaces_the_registration() {
+        let mut registry = Registry::default();
+        let alice = identity("alice");
+        let now = Utc::now();
+        registry.register(
+            alice.clone(),
+            Endpoint::new("192.0.2.1", 5555),
+            now,
+            now + TimeDelta::minutes(1),
+        );
+        let renewed = now + TimeDelta::seconds(50);
+        registry.register(
+            alice.clone(),
+            Endpoint::new("192.0.2.2", 6666),
+            renewed,
+            renewed + TimeDelta::minutes(1),
+        );
+
+        assert_eq!(registry.len(), 1);
+        // the first registration would have expired by now
+        let info = registry
+            .lookup(&alice.public_key, now + TimeDelta::minutes(1))
+            .unwrap();
+        assert_eq!(info.endpoint, Endpoint::new("192.0.2.2", 6666));
+        assert_eq!(info.last_seen, renewed);
+    }
+
+    #[test]
+    fn expired_registrations_are_dropped() {
+        let mut registry = Registry::default();
+        let (alice, bob) = (identity("alice"), identity("bob"));
+        let now = Utc::now();
+        let endpoint = Endpoint::new("192.0.2.1", 5555);
+        registry.register(
+            alice.clone(),
+            endpoint.clone(),
+            now,
+            now + TimeDelta::minutes(1),
+        );
+        registry.register(bob.clone(), endpoint, now, now + TimeDelta::minutes(2));
+
+        let later = now + TimeDelta::minutes(1);
+        // expired registrations are hidden before they are dropped
+        assert_eq!(registry.lookup(&alice.public_key, later), None);
+        assert_eq!(registry.list(later, 10).len(), 1);
+        assert!(registry.is_registered(&alice.public_key));
+
+        assert_eq!(registry.expire(now), 0);
+        assert_eq!(registry.expire(later), 1);
+        assert!(!registry.is_registered(&alice.public_key));
+        assert!(registry.is_registered(&bob.public_key));
+    }
+
+    #[test]
+    fn list_is_limited() {
+        let mut registry = Registry::default();
+        let now = Utc::now();
+        for name in ["alice", "bob", "carol"] {
+            registry.register(
+                identity(name),
+                Endpoint::new("192.0.2.1", 5555),
+                now,
+                now + TimeDelta::minutes(1),
+            );
+        }
+
+        assert_eq!(registry.list(now, 10).len(), 3);
+        assert_eq!(registry.list(now, 2).len(), 2);
+        assert!(registry.list(now, 0).is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! Serves the rendezvous protocol to clients, see [`sremp_core::net::rendezvous`]
+
+use std::{io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use ed25519_dalek::VerifyingKey;
+use log::{debug, info, trace, warn};
+use sremp_core::{
+    error::{CoreError, CoreResult},
+    identity::{Identity, UserIdentity},
+    net::{
+        connection::{Connection, ConnectionReader, ConnectionWriter, FrameBody, Timeouts},
+        rendezvous::{
+            LookupRequest, LookupResponse, RegisterRequest, RegisterResponse, RendezvousPacket,
+        },
+    },
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+use crate::registry::Registry;
+
+/// Longest time to live a registration can have by default
+pub const DEFAULT_MAX_TTL: Duration = Duration::from_secs(60 * 60);
+/// Number of peers that can be registered at once by default
+pub const DEFAULT_MAX_PEERS: usize = 100_000;
+/// Number of peers in the answer to a list-all lookup by default
+pub const DEFAULT_MAX_LIST: usize = 1000;
+/// How often expired registrations are dropped by default
+pub const DEFAULT_GC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How the rendezvous server treats its clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RendezvousConfig {
+    /// Registrations that ask for a longer time to live get this one
+    pub max_ttl: Duration,
+    /// New peers can not register once this many are
+    pub max_peers: usize,
+    /// Lookups for all peers get at most this many, so that the answer fits into a packet
+    pub max_list: usize,
+    /// How often registrations that have expired are dropped
+    pub gc_interval: Duration,
+    pub timeouts: Timeouts,
+}
+
+/// A rendezvous server, shared by the tasks of all its connections
+#[derive(Debug)]
+pub struct Rendezvous {
+    identity: UserIdentity,
+    config: RendezvousConfig,
+    registry: Mutex<Registry>,
+}
+
+impl Default for RendezvousConfig {
+    fn default() -> Self {
+        Self {
+            max_ttl: DEFAULT_MAX_TTL,
+            max_peers: DEFAULT_MAX_PEERS,
+            max_list: DEFAULT_MAX_LIST,
+            gc_interval: DEFAULT_GC_INTERVAL,
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+impl Rendezvous {
+    /// Creates a rendezvous server that presents `identity` to its clients.
+    pub fn new(mut identity: UserIdentity, config: RendezvousConfig) -> Arc<Self> {
+        identity.identity.flags.is_machine_account = true;
+        Arc::new(Self {
+            identity,
+            config,
+            registry: Mutex::default(),
+        })
+    }
+
+    /// The identity the rendezvous server presents to its clients.
+    pub fn identity(&self) -> &Identity {
+        &self.identity.identity
+    }
+
+    pub fn config(&self) -> &RendezvousConfig {
+        &self.config
+    }
+
+    /// Accepts clients on the listener, each is served by its own task.
+    ///
+    /// Expired registrations are dropped every [`RendezvousConfig::gc_interval`] meanwhile.
+    ///
+    /// # Errors
+    ///
+    /// Only returns if accepting connections fails.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> CoreResult<()> {
+        info!(
+            "Rendezvous server is listening on {}",
+            listener.local_addr()?
+        );
+        let gc = tokio::spawn(self.clone().expire_registrations());
+        let result = self.accept(listener).await;
+        gc.abort();
+        result
+    }
+
+    async fn accept(self: &Arc<Self>, listener: TcpListener) -> CoreResult<()> {
+        loop {
+            let (stream, remote) = listener.accept().await?;
+            debug!("Accepted a connection from {remote}");
+            let rendezvous = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = rendezvous.handle_client(stream, remote).await {
+                    warn!("Connection with {remote} has failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn expire_registrations(self: Arc<Self>) {
+        // an interval must not be zero
+        let period = self.config.gc_interval.max(Duration::from_secs(1));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let expired = self.registry.lock().await.expire(Utc::now());
+            if expired > 0 {
+                info!("Dropped {expired} expired registrations");
+            }
+        }
+    }
+
+    async fn handle_client(&self, stream: TcpStream, remote: SocketAddr) -> CoreResult<()> {
+        let (conn, mut reader) =
+            Connection::connect_from(stream, remote, &self.identity, &self.config.timeouts).await?;
+        let writer = conn.writer();
+        let client = conn.peer_identity().await.public_key;
+
+        self.registry.lock().await.connected(client, Utc::now());
+        let result = self
+            .serve_client(client, remote, &mut reader, &writer)
+            .await;
+        self.registry.lock().await.disconnected(client, Utc::now());
+
+        match result {
+            Ok(()) => conn.disconnect().await,
+            Err(e) => {
+                if let Err(send_error) = writer.send(&FrameBody::Error(e.to_string())).await {
+                    debug!("Could not tell {remote} about the error: {send_error}");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn serve_client(
+        &self,
+        client: VerifyingKey,
+        remote: SocketAddr,
+        reader: &mut ConnectionReader,
+        writer: &ConnectionWriter,
+    ) -> CoreResult<()> {
+        loop {
+            let packet = match reader.recv_keepalive(writer, &self.config.timeouts).await {
+                Ok(packet) => packet,
+                Err(CoreError::IO(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    info!("Client {remote} has closed the connection");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+
+            match packet.body {
+                FrameBody::Rendezvous(RendezvousPacket::Register(request)) => {
+                    let response = self.register(client, remote, request).await;
+                    writer.send_detached(FrameBody::Rendezvous(RendezvousPacket::Registered(
+                        response,
+                    )));
+                }
+                FrameBody::Rendezvous(RendezvousPacket::Lookup(request)) => {
+                    let response = self.lookup(&request).await;
+                    debug!("Found {} peers for {remote}", response.peers.len());
+                    writer.send_detached(FrameBody::Rendezvous(RendezvousPacket::Found(response)));
+                }
+                FrameBody::Ping(value) => writer.send_detached(FrameBody::Pong(value)),
+                FrameBody::Pong(value) => trace!("Client {remote} answered ping {value}"),
+                FrameBody::Ack(id) => trace!("Client {remote} acknowledged packet {id}"),
+                FrameBody::Goodbye => {
+                    info!("Client {remote} is closing the connection");
+                    return Ok(());
+                }
+                FrameBody::Error(reason) => {
+                    warn!(
+                        "Client {remote} is closing the connection because of an error: {reason}"
+                    );
+                    return Ok(());
+                }
+                other => {
+                    return Err(CoreError::UnexpectedPacket {
+                        remote,
+                        kind: other.kind(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Registers the endpoint of the client, after checking that the client signed it.
+    async fn register(
+        &self,
+        client: VerifyingKey,
+        remote: SocketAddr,
+        mut request: RegisterRequest,
+    ) -> RegisterResponse {
+        let now = Utc::now();
+        let rejected = |reason: String| RegisterResponse {
+            success: false,
+            expires_at: now,
+            renewal_interval: 0,
+            error_message: Some(reason),
+        };
+
+        // a peer can only register itself
+        if request.identity.public_key != client {
+            return rejected("the identity is not the one of the connection".to_string());
+        }
+        if let Err(e) = request.verify(&self.identity.identity.public_key) {
+            return rejected(e.to_string());
+        }
+        if let Err(e) = Identity::validate_username(&request.identity.username)
+            .and_then(|()| request.endpoint.validate())
+        {
+            return rejected(e.to_string());
+        }
+
+        let mut registry = self.registry.lock().await;
+        if request.ttl_seconds == 0 {
+            if registry.unregister(&client) {
+                info!("Client {remote} has unregistered");
+            }
+            return RegisterResponse {
+                success: true,
+                expires_at: now,
+                renewal_interval: 0,
+                error_message: None,
+            };
+        }
+        if !registry.is_registered(&client) && registry.len() >= self.config.max_peers {
+            return rejected("the rendezvous server is full".to_string());
+        }
+
+        let ttl = Duration::from_secs(request.ttl_seconds.into()).min(self.config.max_ttl);
+        let expires_at = expiry(now, ttl);
+        // a listener on all interfaces can be reached where the registration came from
+        if request
+            .endpoint
+            .host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_unspecified())
+        {
+            request.endpoint.host = remote.ip().to_string();
+        }
+        info!(
+            "Client {remote} has registered {} until {expires_at}",
+            request.endpoint
+        );
+        registry.register(request.identity, request.endpoint, now, expires_at);
+        RegisterResponse {
+            success: true,
+            expires_at,
+            // renew halfway, so that a late renewal does not let the registration lapse
+            renewal_interval: u32::try_from(ttl.as_secs() / 2).unwrap_or(u32::MAX).max(1),
+            error_message: None,
+        }
+    }
+
+    async fn lookup(&self, request: &LookupRequest) -> LookupResponse {
+        let now = Utc::now();
+        let registry = self.registry.lock().await;
+        match (request.target_identity, request.list_all) {
+            (Some(key), _) => LookupResponse {
+                peers: registry.lookup(&key, now).into_iter().collect(),
+                error_message: None,
+            },
+            (None, true) => LookupResponse {
+                peers: registry.list(now, self.config.max_list),
+                error_message: None,
+            },
+            (None, false) => LookupResponse {
+                peers: Vec::new(),
+                error_message: Some("neither a peer nor all peers were asked for".to_string()),
+            },
+        }
+    }
+}
+
+/// When a registration made at `now` with the time to live `ttl` ends.
+fn expiry(now: DateTime<Utc>, ttl: Duration) -> DateTime<Utc> {
+    TimeDelta::from_std(ttl)
+        .ok()
+        .and_then(|ttl| now.checked_add_signed(ttl))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}